@@ -1,3 +1,13 @@
+use std::{env, sync::Arc};
+
+use shared::{
+    debug::CBCADebugCommand,
+    payload::CBCAInstanceFilter,
+    request::{CBCAAuctionStatus, CBCAInstanceList}
+};
+
+use crate::client::CBCAClient;
+
 pub async fn help(c_args: &mut Vec<&str>) -> () {
     if c_args.len() > 1 {
         eprintln!("Bad usage of help {:?}\n", c_args);
@@ -8,16 +18,19 @@ pub async fn help(c_args: &mut Vec<&str>) -> () {
         c_args.push("1");
     }
 
-    const COMMANDS_LIST: [[&str; 8]; 1] = [
+    const COMMANDS_LIST: [[&str; 11]; 1] = [
         [
             "help <page>\t\t- Display the nth page of the command list.",
             "connect <token>\t\t- Login to your BCA identity.",
+            "list <open|closed>\t- List the auctions, open and closed ones by default.",
+            "search <words>\t\t- Find the auctions whose name or description holds the words.",
             "join <instance_id>\t- Join an auction instance.",
             "*message <content>\t- Send message into auction instance.",
             "*offer <amount> <message>\t- Send an offer to auction owner.",
             "leave\t\t\t- Leave the current auction.",
             "quit\t\t\t- Leave the client",
-            "*logout\t\t\t- Remove the BCA identity from your client."
+            "*logout\t\t\t- Remove the BCA identity from your client.",
+            "debug <command>\t\t- Admin `stats` or `verify <instance_id>`, needs CBCA_ADMIN_TOKEN."
         ]
     ];
 
@@ -48,3 +61,75 @@ pub async fn help(c_args: &mut Vec<&str>) -> () {
 
     println!("Page {} over {}\n", c_args[0], COMMANDS_LIST.len());
 }
+
+pub async fn debug(
+    client: &Arc<tokio::sync::Mutex<CBCAClient>>,
+    c_args: &mut Vec<&str>
+) -> () {
+    let command: CBCADebugCommand = match c_args.as_slice() {
+        ["stats"] => CBCADebugCommand::Stats,
+        ["verify", instance_id] => CBCADebugCommand::Verify { instance_id: instance_id.to_string() },
+        _ => {
+            eprintln!("Bad usage of debug {:?}, expected 'stats' or 'verify <instance_id>'.\n", c_args);
+            return;
+        }
+    };
+
+    let token: String = match env::var("CBCA_ADMIN_TOKEN") {
+        Ok(v) => v,
+        Err(_) => {
+            eprintln!("CBCA_ADMIN_TOKEN isn't set.\n");
+            return;
+        }
+    };
+
+    match client.lock().await.debug(token, command).await {
+        Ok(v) => println!("{}\n", serde_json::to_string_pretty(&v).unwrap_or_default()),
+        Err(e) => eprintln!("debug failed, {}.\n", e)
+    }
+}
+
+// `list [open|closed]` or `search <words>`, private auctions show up when they invite the name
+// of the client certificate.
+pub async fn find(
+    client: &Arc<tokio::sync::Mutex<CBCAClient>>,
+    search: bool,
+    c_args: &mut Vec<&str>
+) -> () {
+    let mut filter: CBCAInstanceFilter = CBCAInstanceFilter::default();
+    let text: Option<String> = if search {
+        if c_args.is_empty() {
+            eprintln!("Bad usage of search, expected 'search <words>'.\n");
+            return;
+        }
+        Some(c_args.join(" "))
+    } else {
+        filter.status = match c_args.as_slice() {
+            [] => None,
+            ["open"] => Some(CBCAAuctionStatus::Open),
+            ["closed"] => Some(CBCAAuctionStatus::Closed),
+            _ => {
+                eprintln!("Bad usage of list {:?}, expected 'open' or 'closed'.\n", c_args);
+                return;
+            }
+        };
+        None
+    };
+
+    let list: CBCAInstanceList = match client.lock().await.find_instances(text, filter).await {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("listing failed, {}.\n", e);
+            return;
+        }
+    };
+
+    for v in &list.instances {
+        println!(
+            "{} | {}{} | {:?} | {} bids | {}",
+            v.instance_id, v.name, if v.private { " (private)" } else { "" }, v.status, v.bids, v.currency
+        );
+    }
+
+    println!("{} of {} auctions\n", list.instances.len(), list.total);
+}
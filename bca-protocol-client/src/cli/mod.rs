@@ -107,6 +107,8 @@ impl CBCACli {
         match command {
             "help" => { commands::help(&mut c_args).await; },
             "connect" => {}
+            "list" | "search" => { commands::find(&self.client, command == "search", &mut c_args).await; },
+            "debug" => { commands::debug(&self.client, &mut c_args).await; },
             _ => {
                 println!("unknow command.");
             }
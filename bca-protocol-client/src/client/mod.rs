@@ -1,6 +1,15 @@
 use std::{sync::Arc, time::Duration};
 use shared::{
-    communication::CBCATcpError, fchain::CBCAConfig, payload::{IPayload, MPayload, OPayload}
+    communication::{CBCAErrorPayload, CBCATcpError},
+    debug::CBCADebugCommand,
+    event::CBCAEvent,
+    fchain::{CBCAChainKind, CBCAConfig}, 
+    handshake::{self, CBCAFeature, CBCAHello}, 
+    limits::CBCALimits,
+    payload::{CBCAChainRange, CBCAInstanceFilter, DPayload, IPayload, MPayload, OPayload, QPayload, SPayload},
+    request::{CBCAChainPage, CBCAInstanceList, CBCAReceipt},
+    tls::{CBCATlsClientConfig, CBCATlsConnector},
+    transport::CBCAStream
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt}, 
@@ -11,58 +20,262 @@ use shared::communication::{CBCATcpPayloadType, CBCATcpPayload};
 pub struct CBCAClient { 
     ip_message: String,
     ip_instance: String,
-    ip_offer: String
+    ip_offer: String,
+    ip_subscribe: String,
+    ip_query: String,
+    ip_admin: String,
+    hello: CBCAHello,
+    tls: Option<CBCATlsConnector>,
+    request_timeout: Duration,
+    retries: u32,
+    limits: CBCALimits
 }
 
+// Transport failures, as opposed to an answer of the server (a Rejected error
+// carries its CBCAErrorPayload, the OS one doesn't).
+fn is_retryable(error: &std::io::Error) -> bool {
+    match error.kind() {
+        std::io::ErrorKind::ConnectionRefused => error.get_ref().is_none(),
+        std::io::ErrorKind::TimedOut
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof => true,
+        _ => false
+    }
+}
+
+#[derive(Clone, Copy)]
 pub enum CBCAFlag {
-    IPM, IPI, IPO
+    IPM, IPI, IPO, IPS, IPQ, IPD
+}
+
+impl CBCAFlag {
+    // The admin routine only talks in Debug frames.
+    fn frame_type(&self) -> CBCATcpPayloadType {
+        match self {
+            CBCAFlag::IPD => CBCATcpPayloadType::Debug,
+            _ => CBCATcpPayloadType::Reqwest
+        }
+    }
+
+    fn reply_type(&self) -> CBCATcpPayloadType {
+        match self {
+            CBCAFlag::IPD => CBCATcpPayloadType::Debug,
+            _ => CBCATcpPayloadType::Data
+        }
+    }
 }
 
 impl CBCAClient {
     pub fn spawn(
         ip_message: String,
         ip_instance: String,
-        ip_offer: String
+        ip_offer: String,
+        ip_subscribe: String,
+        ip_query: String,
+        ip_admin: String
     ) -> Self {
         Self {
             ip_message,
             ip_instance,
-            ip_offer
+            ip_offer,
+            ip_subscribe,
+            ip_query,
+            ip_admin,
+            hello: CBCAHello::spawn(
+                vec![CBCAFeature::Compression, CBCAFeature::Subscriptions, CBCAFeature::Heartbeats], 
+                format!("bca-protocol-client/{}", env!("CARGO_PKG_VERSION"))
+            ),
+            tls: None,
+            request_timeout: Duration::from_secs(10),
+            retries: 2,
+            limits: CBCALimits::default()
         }
     }
+
+    // Messages and offers are sent again up to `retries` times when they time out,
+    // their idempotency key keeps the server from appending them twice.
+    pub fn set_retry_policy(
+        &mut self,
+        request_timeout: Duration,
+        retries: u32
+    ) {
+        self.request_timeout = request_timeout;
+        self.retries = retries;
+    }
+
+    pub fn set_limits(
+        &mut self,
+        limits: CBCALimits
+    ) {
+        self.limits = limits;
+    }
+
+    pub fn enable_tls(
+        &mut self,
+        config: &CBCATlsClientConfig
+    ) -> Result<(), std::io::Error> {
+        self.tls = Some(config.build_connector()?);
+        Ok(())
+    }
     
-    async fn fetch(
-        &self, 
-        flag: CBCAFlag, 
+    // Connects to the routine behind `flag` and sends `payload` once the handshake is done.
+    async fn open(
+        &self,
+        flag: CBCAFlag,
         payload: String
-    ) -> Result<String, std::io::Error> {
-        let stream: tokio::net::TcpStream =
-            tokio::net::TcpStream::connect(
+    ) -> Result<Arc<tokio::sync::Mutex<CBCAStream>>, std::io::Error> {
+        let mut stream: CBCAStream =
+            CBCAStream::connect(
                 match flag {
                     CBCAFlag::IPM => &self.ip_message,
                     CBCAFlag::IPI => &self.ip_instance,
                     CBCAFlag::IPO => &self.ip_offer,
-                }
+                    CBCAFlag::IPS => &self.ip_subscribe,
+                    CBCAFlag::IPQ => &self.ip_query,
+                    CBCAFlag::IPD => &self.ip_admin,
+                },
+                self.tls.as_ref()
             ).await?;
 
+        stream.set_limits(self.limits)?;
         let shared_stream = Arc::new(tokio::sync::Mutex::new(stream));
-        let shared_stream_scope = Arc::clone(&shared_stream);
 
-        let reqwest = CBCATcpPayload::spawn(CBCATcpPayloadType::Reqwest, payload);
-        reqwest.send(shared_stream).await?;
+        handshake::client_handshake(Arc::clone(&shared_stream), &self.hello).await?;
 
-        let result = CBCATcpPayload::read(shared_stream_scope, CBCATcpPayloadType::Data).await;
+        let reqwest = CBCATcpPayload::spawn(flag.frame_type(), payload);
+        reqwest.send(Arc::clone(&shared_stream)).await?;
 
-        match result {
-            Ok(v) => {Ok(v)},
-            Err(v) => {
-                match v {
-                    CBCATcpError::InvalidHeader(v) => {
-                        Err(std::io::Error::new(std::io::ErrorKind::InvalidData, v))
-                    }
-                }
+        Ok(shared_stream)
+    }
+
+    async fn fetch(
+        &self, 
+        flag: CBCAFlag, 
+        payload: String
+    ) -> Result<String, std::io::Error> {
+        let shared_stream_scope = self.open(flag, payload).await?;
+
+        let result: CBCATcpPayload = CBCATcpPayload::read_frame(shared_stream_scope, flag.reply_type()).await?;
+
+        if result.get_type().is_error() {
+            let error: CBCAErrorPayload = serde_json::from_str(result.get_content())?;
+            return Err(CBCATcpError::Rejected(error).into());
+        }
+
+        Ok(result.get_content().to_string())
+    }
+
+    // Only what may not have reached the server is retried, a refusal is final.
+    async fn fetch_with_retry(
+        &self,
+        flag: CBCAFlag,
+        payload: String
+    ) -> Result<String, std::io::Error> {
+        let mut attempt: u32 = 0;
+
+        loop {
+            let error: std::io::Error = match tokio::time::timeout(
+                self.request_timeout, 
+                self.fetch(flag, payload.clone())
+            ).await {
+                Ok(Ok(v)) => return Ok(v),
+                Ok(Err(e)) if is_retryable(&e) => e,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out.")
+            };
+
+            if attempt >= self.retries {
+                return Err(error);
             }
+
+            attempt += 1;
+            println!("[RETRY {}/{}] {}.", attempt, self.retries, error);
+            sleep(Duration::from_millis(200 * attempt as u64)).await;
+        }
+    }
+
+    // Events of the instance arrive on the receiver until it is dropped or the server goes away.
+    pub async fn subscribe(
+        &self,
+        identifier: String
+    ) -> Result<tokio::sync::mpsc::Receiver<CBCAEvent>, std::io::Error> {
+        let payload: SPayload = SPayload { instance_id: identifier };
+        let stream = self.open(CBCAFlag::IPS, serde_json::to_string(&payload)?).await?;
+
+        let accepted: String = CBCATcpPayload::read(Arc::clone(&stream), CBCATcpPayloadType::Data).await?;
+        if accepted != "true" {
+            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, accepted));
         }
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(64);
+
+        tokio::spawn(async move {
+            while let Ok(v) = CBCATcpPayload::read(Arc::clone(&stream), CBCATcpPayloadType::Data).await {
+                let event: CBCAEvent = match serde_json::from_str(&v) {
+                    Ok(e) => e,
+                    Err(_) => break
+                };
+
+                if sender.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
+
+    // Reading is safe to repeat, so it goes through the retry policy too.
+    // Keep `head_hash` of the page to check the next one follows the same chain.
+    pub async fn fetch_chain(
+        &self,
+        identifier: String,
+        chain: CBCAChainKind,
+        range: CBCAChainRange
+    ) -> Result<CBCAChainPage, std::io::Error> {
+        let payload: QPayload = QPayload::Chain { 
+            instance_id: identifier, 
+            chain, 
+            range 
+        };
+
+        let serialized: String = serde_json::to_string(&payload)?;
+        let res: String = self.fetch_with_retry(CBCAFlag::IPQ, serialized).await?;
+
+        Ok(serde_json::from_str(&res)?)
+    }
+
+    // Instances the index lists for `filter`, or only those matching `text` when given.
+    pub async fn find_instances(
+        &self,
+        text: Option<String>,
+        filter: CBCAInstanceFilter
+    ) -> Result<CBCAInstanceList, std::io::Error> {
+        let payload: QPayload = match text {
+            Some(text) => QPayload::Search { text, filter },
+            None => QPayload::List { filter }
+        };
+
+        let serialized: String = serde_json::to_string(&payload)?;
+        let res: String = self.fetch_with_retry(CBCAFlag::IPQ, serialized).await?;
+
+        Ok(serde_json::from_str(&res)?)
+    }
+
+    // Admin introspection, `token` must match the CBCA_ADMIN_TOKEN of the server.
+    pub async fn debug(
+        &self,
+        token: String,
+        command: CBCADebugCommand
+    ) -> Result<serde_json::Value, std::io::Error> {
+        let payload: DPayload = DPayload { token, command };
+
+        let serialized: String = serde_json::to_string(&payload)?;
+        let res: String = self.fetch(CBCAFlag::IPD, serialized).await?;
+
+        Ok(serde_json::from_str(&res)?)
     }
 
     pub async fn send_message(
@@ -70,17 +283,18 @@ impl CBCAClient {
         author: String, 
         content: String, 
         identifier: String
-    ) -> Result<String, std::io::Error> {
+    ) -> Result<CBCAReceipt, std::io::Error> {
         let payload: MPayload = MPayload { 
             content, 
             author, 
-            instance_id: identifier
+            instance_id: identifier,
+            idempotency_key: Some(uuid::Uuid::new_v4().to_string())
         };
 
         let serialized: String = serde_json::to_string(&payload)?;
-        let res: String = self.fetch(CBCAFlag::IPM, serialized).await?;
+        let res: String = self.fetch_with_retry(CBCAFlag::IPM, serialized).await?;
         
-        Ok(res)
+        Ok(serde_json::from_str(&res)?)
     }
 
     pub async fn send_offer(
@@ -89,17 +303,18 @@ impl CBCAClient {
         message: Option<String>, 
         identifier: String,
         author: String
-    ) -> Result<bool, std::io::Error> {
+    ) -> Result<CBCAReceipt, std::io::Error> {
         let payload: OPayload = OPayload { 
             amount,
             author, 
             message,
-            instance_id: identifier
+            instance_id: identifier,
+            idempotency_key: Some(uuid::Uuid::new_v4().to_string())
         };
 
         let serialized: String = serde_json::to_string(&payload)?;
-        let res: String = self.fetch(CBCAFlag::IPO, serialized).await?;
-        let res_parsed: bool = serde_json::from_str(res.as_str())?;
+        let res: String = self.fetch_with_retry(CBCAFlag::IPO, serialized).await?;
+        let res_parsed: CBCAReceipt = serde_json::from_str(res.as_str())?;
 
         Ok(res_parsed)
     }
@@ -108,8 +323,9 @@ impl CBCAClient {
         &self,
         config: CBCAConfig
     ) -> Result<String, std::io::Error> {
+        // The server issues the id and answers with it.
         let payload: IPayload = IPayload { 
-            instance_id: uuid::Uuid::new_v4().to_string(), 
+            instance_id: String::new(), 
             config:  config
         };
 
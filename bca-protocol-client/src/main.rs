@@ -1,9 +1,9 @@
 mod client;
 mod cli;
 
-use std::{fmt::format, sync::Arc};
+use std::{env, fmt::format, path::PathBuf, sync::Arc};
 
-use shared::fchain::CBCAConfig;
+use shared::{fchain::CBCAConfig, limits::CBCALimits, tls::CBCATlsClientConfig, transport::CBCA_UNIX_PREFIX};
 use crate::{cli::{CBCACli, CBCAIdentity}, client::CBCAClient};
 // async fn cli() -> Result<(), std::io::Error> {
 //     let cli = CBCACli::spawn(Some("Bilal".to_string()));
@@ -13,12 +13,36 @@ use crate::{cli::{CBCACli, CBCAIdentity}, client::CBCAClient};
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let client: CBCAClient = CBCAClient::spawn(
-        "127.0.0.1:8686".to_string(),
-        "127.0.0.1:8687".to_string(),
-        "127.0.0.1:8688".to_string()
+    // CBCA_UNIX_DIR reaches a server running on the same host through its sockets.
+    let unix_dir: Option<PathBuf> = env::var("CBCA_UNIX_DIR").ok().map(PathBuf::from);
+    let routine_addr = |port: &str, name: &str| -> String {
+        match &unix_dir {
+            Some(v) => format!("{}{}", CBCA_UNIX_PREFIX, v.join(format!("{}.sock", name)).display()),
+            None => format!("127.0.0.1:{}", port)
+        }
+    };
+
+    let mut client: CBCAClient = CBCAClient::spawn(
+        routine_addr("8686", "message"),
+        routine_addr("8687", "instance"),
+        routine_addr("8688", "offer"),
+        routine_addr("8689", "subscribe"),
+        routine_addr("8690", "query"),
+        routine_addr("8691", "admin")
     );
 
+    client.set_limits(CBCALimits::from_env());
+
+    // CBCA_TLS_CA turns TLS on, CBCA_TLS_CERT and CBCA_TLS_KEY add a client certificate.
+    if let Ok(ca) = env::var("CBCA_TLS_CA") {
+        client.enable_tls(&CBCATlsClientConfig::spawn(
+            PathBuf::from(ca),
+            env::var("CBCA_TLS_SERVER_NAME").unwrap_or("localhost".to_string()),
+            env::var("CBCA_TLS_CERT").ok().map(PathBuf::from),
+            env::var("CBCA_TLS_KEY").ok().map(PathBuf::from)
+        ))?;
+    }
+
     let shared_client: Arc<tokio::sync::Mutex<CBCAClient>> = 
         Arc::new(tokio::sync::Mutex::new(client));
 
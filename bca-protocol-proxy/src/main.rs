@@ -0,0 +1,55 @@
+mod proxy;
+mod record;
+mod replay;
+
+use std::{env, path::PathBuf};
+
+use proxy::CBCAProxy;
+use record::CBCARecorder;
+use replay::CBCAReplay;
+use shared::{limits::CBCALimits, transport::CBCAEndpoint};
+
+const CBCA_PROXY_USAGE: &str = "usage:
+    cbca-protocol-proxy forward <listen> <upstream> [record_file]
+    cbca-protocol-proxy replay <record_file> <upstream>
+
+addresses are \"host:port\" or \"unix:/path\".";
+
+fn usage() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, "bad arguments.")
+}
+
+#[tokio::main]
+async fn main() -> Result<(), std::io::Error> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.iter().map(|v| v.as_str()).collect::<Vec<&str>>().as_slice() {
+        ["forward", listen, upstream, rest @ ..] if rest.len() <= 1 => {
+            let recorder: Option<CBCARecorder> = match rest.first() {
+                Some(v) => Some(CBCARecorder::spawn(PathBuf::from(v))?),
+                None => None
+            };
+
+            let proxy: CBCAProxy = CBCAProxy::spawn(
+                CBCAEndpoint::parse(listen),
+                upstream.to_string(),
+                recorder
+            );
+
+            proxy.run().await
+        },
+        ["replay", record_file, upstream] => {
+            let replay: CBCAReplay = CBCAReplay::spawn(
+                PathBuf::from(record_file),
+                upstream.to_string(),
+                CBCALimits::from_env()
+            );
+
+            replay.run().await
+        },
+        _ => {
+            eprintln!("{}", CBCA_PROXY_USAGE);
+            Err(usage())
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    sync::{Arc, atomic::{AtomicU64, Ordering}},
+    time::Instant
+};
+
+use shared::{
+    communication::{CBCATcpError, CBCATcpPayload},
+    compression::CBCAFrameEncoding,
+    limits::CBCA_MAX_FRAME_SIZE,
+    transport::{CBCAEndpoint, CBCAIncoming, CBCAListener, CBCAStream}
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+use crate::record::{CBCADirection, CBCARecord, CBCARecorder};
+
+// Splits a byte stream back into frames, bytes are pushed as they come off the socket.
+pub struct CBCAFrameDecoder {
+    buffer: Vec<u8>,
+    // Largest body a compressed frame may inflate to.
+    max_size: usize
+}
+
+impl CBCAFrameDecoder {
+    pub fn spawn(max_size: usize) -> Self {
+        Self { buffer: Vec::new(), max_size }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    // Next complete frame with its encoding and size on the wire, None until enough bytes came.
+    pub fn next_frame(&mut self) -> Result<Option<(CBCATcpPayload, CBCAFrameEncoding, usize)>, CBCATcpError> {
+        match CBCATcpPayload::decode_frame(&self.buffer, self.max_size)? {
+            Some((frame, encoding, consumed)) => {
+                self.buffer.drain(..consumed);
+                Ok(Some((frame, encoding, consumed)))
+            },
+            None => Ok(None)
+        }
+    }
+}
+
+// Pretty prints a frame, JSON contents are indented.
+pub fn print_frame(
+    tag: &str,
+    frame: &CBCATcpPayload,
+    encoding: CBCAFrameEncoding,
+    size: usize
+) {
+    let encoding: &str = match encoding {
+        CBCAFrameEncoding::Plain => "",
+        CBCAFrameEncoding::Deflate => " deflate"
+    };
+
+    println!(
+        "{} {:02} {:?} {}B{}",
+        tag,
+        frame.get_type().get_code(),
+        frame.get_type(),
+        size,
+        encoding
+    );
+
+    match serde_json::from_str::<serde_json::Value>(frame.get_content()) {
+        Ok(v) => println!("{}", serde_json::to_string_pretty(&v).unwrap_or_default()),
+        Err(_) if frame.get_content().is_empty() => {},
+        Err(_) => println!("{}", frame.get_content())
+    }
+}
+
+// Sits between a client and a server routine, forwards the bytes untouched and prints every frame.
+// Frames are decoded from the plain bytes, so the proxy only sees inside connections without TLS.
+pub struct CBCAProxy {
+    listen: CBCAEndpoint,
+    upstream: String,
+    recorder: Option<CBCARecorder>,
+    connections: Arc<AtomicU64>
+}
+
+impl CBCAProxy {
+    pub fn spawn(
+        listen: CBCAEndpoint,
+        upstream: String,
+        recorder: Option<CBCARecorder>
+    ) -> Self {
+        Self {
+            listen,
+            upstream,
+            recorder,
+            connections: Arc::new(AtomicU64::new(0))
+        }
+    }
+
+    pub async fn run(&self) -> Result<(), std::io::Error> {
+        let listener: CBCAListener = CBCAListener::bind(&self.listen, 0o660).await?;
+        println!("[PROXY] {} -> {}", listener.get_endpoint(), self.upstream);
+
+        loop {
+            let incoming: CBCAIncoming = listener.accept().await?;
+            let conn: u64 = self.connections.fetch_add(1, Ordering::Relaxed) + 1;
+            let upstream: String = self.upstream.clone();
+            let recorder: Option<CBCARecorder> = self.recorder.clone();
+
+            tokio::spawn(async move {
+                match Self::handle_connection(conn, incoming, upstream, recorder).await {
+                    Ok(_) => println!("[PROXY #{}] closed.", conn),
+                    Err(e) => println!("[PROXY #{}] closed: {}", conn, e)
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(
+        conn: u64,
+        incoming: CBCAIncoming,
+        upstream: String,
+        recorder: Option<CBCARecorder>
+    ) -> Result<(), std::io::Error> {
+        let client: CBCAStream = CBCAStream::accept(incoming, None).await?;
+        let server: CBCAStream = CBCAStream::connect(&upstream, None).await?;
+        println!("[PROXY #{}] opened.", conn);
+
+        let opened: Instant = Instant::now();
+        let (client_read, client_write): (ReadHalf<CBCAStream>, WriteHalf<CBCAStream>) = tokio::io::split(client);
+        let (server_read, server_write): (ReadHalf<CBCAStream>, WriteHalf<CBCAStream>) = tokio::io::split(server);
+
+        // A side hanging up only closes its direction, answers still on the way reach it.
+        // The connection ends once both directions did, or as soon as one fails.
+        tokio::try_join!(
+            Self::pump(conn, CBCADirection::ClientToServer, opened, client_read, server_write, recorder.clone()),
+            Self::pump(conn, CBCADirection::ServerToClient, opened, server_read, client_write, recorder)
+        )?;
+
+        Ok(())
+    }
+
+    async fn pump<R, W>(
+        conn: u64,
+        dir: CBCADirection,
+        opened: Instant,
+        mut from: R,
+        mut to: W,
+        recorder: Option<CBCARecorder>
+    ) -> Result<(), std::io::Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin
+    {
+        // The proxy does not know the peers' limits, no peer takes a body larger than a frame holds.
+        let mut decoder: CBCAFrameDecoder = CBCAFrameDecoder::spawn(CBCA_MAX_FRAME_SIZE);
+        // Bytes keep flowing once the stream stops making sense, only the printing stops.
+        let mut decoding: bool = true;
+        let mut buf: [u8; 4096] = [0u8; 4096];
+
+        loop {
+            let n: usize = from.read(&mut buf).await?;
+            if n == 0 {
+                // Passes the half close on, the other peer may still answer.
+                to.shutdown().await?;
+                return Ok(());
+            }
+
+            to.write_all(&buf[..n]).await?;
+            to.flush().await?;
+
+            if !decoding {
+                continue;
+            }
+
+            decoder.push(&buf[..n]);
+
+            loop {
+                match decoder.next_frame() {
+                    Ok(Some((frame, encoding, size))) => {
+                        let at_ms: u64 = opened.elapsed().as_millis() as u64;
+                        let tag: String = format!("[PROXY #{} +{}ms {}]", conn, at_ms, dir.get_arrow());
+                        print_frame(&tag, &frame, encoding, size);
+
+                        if let Some(v) = &recorder {
+                            let record: CBCARecord = CBCARecord::spawn(
+                                conn,
+                                dir,
+                                at_ms,
+                                &frame,
+                                encoding == CBCAFrameEncoding::Deflate,
+                                size
+                            );
+
+                            if let Err(e) = v.write(&record) {
+                                println!("[PROXY #{}] record failed: {}", conn, e);
+                            }
+                        }
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        println!("[PROXY #{} {}] undecodable stream, forwarding only: {:?}", conn, dir.get_arrow(), e);
+                        decoding = false;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::communication::CBCATcpPayloadType;
+
+    use super::*;
+
+    // A frame as a client sends it, padded to 8 bytes.
+    fn wire(frame: &CBCATcpPayload, encoding: CBCAFrameEncoding) -> Vec<u8> {
+        let mut data: Vec<u8> = frame.build_frame(encoding).unwrap();
+        data.resize(data.len().div_ceil(8) * 8, 0);
+        data
+    }
+
+    #[test]
+    fn decoder_splits_frames_pushed_in_pieces() {
+        let plain: CBCATcpPayload = CBCATcpPayload::spawn(CBCATcpPayloadType::Data, "{\"a\":1}".to_string());
+        let deflated: CBCATcpPayload = CBCATcpPayload::spawn(CBCATcpPayloadType::Reqwest, "bid ".repeat(200));
+        let bytes: Vec<u8> = [wire(&plain, CBCAFrameEncoding::Plain), wire(&deflated, CBCAFrameEncoding::Deflate)].concat();
+
+        let mut decoder: CBCAFrameDecoder = CBCAFrameDecoder::spawn(CBCA_MAX_FRAME_SIZE);
+        let mut frames: Vec<(CBCATcpPayload, CBCAFrameEncoding, usize)> = Vec::new();
+        for chunk in bytes.chunks(5) {
+            decoder.push(chunk);
+            while let Some(v) = decoder.next_frame().unwrap() {
+                frames.push(v);
+            }
+        }
+
+        assert_eq!(frames.len(), 2);
+        let sent: [(&CBCATcpPayload, CBCAFrameEncoding); 2] = [(&plain, CBCAFrameEncoding::Plain), (&deflated, CBCAFrameEncoding::Deflate)];
+        for ((frame, encoding, _), (sent, sent_encoding)) in frames.iter().zip(sent) {
+            assert!(frame.get_type().eq(sent.get_type()));
+            assert_eq!(frame.get_content(), sent.get_content());
+            assert_eq!(*encoding, sent_encoding);
+        }
+        assert_eq!(frames[0].2 + frames[1].2, bytes.len());
+        assert!(frames[1].2 < 200 * 4);
+    }
+
+    #[test]
+    fn decoder_refuses_a_body_inflating_past_its_limit() {
+        let frame: CBCATcpPayload = CBCATcpPayload::spawn(CBCATcpPayloadType::Data, "0".repeat(4096));
+        let mut decoder: CBCAFrameDecoder = CBCAFrameDecoder::spawn(1024);
+        decoder.push(&wire(&frame, CBCAFrameEncoding::Deflate));
+
+        assert!(decoder.next_frame().is_err());
+    }
+}
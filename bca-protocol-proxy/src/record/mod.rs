@@ -0,0 +1,144 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex}
+};
+
+use serde::{Deserialize, Serialize};
+use shared::communication::{CBCATcpPayload, CBCATcpPayloadType};
+
+// Which peer wrote the frame.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CBCADirection {
+    #[serde(rename = "c2s")]
+    ClientToServer,
+    #[serde(rename = "s2c")]
+    ServerToClient
+}
+
+impl CBCADirection {
+    pub fn get_arrow(&self) -> &'static str {
+        match self {
+            CBCADirection::ClientToServer => "c→s",
+            CBCADirection::ServerToClient => "s→c"
+        }
+    }
+}
+
+// One decoded frame of a recorded session, a line of the record file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCARecord {
+    pub conn: u64,
+    pub dir: CBCADirection,
+    // Milliseconds since the connection was opened.
+    pub at_ms: u64,
+    // Two digits type code, "03" for Reqwest.
+    pub frame_type: String,
+    pub deflate: bool,
+    // Size on the wire, compressed if `deflate`.
+    pub size: usize,
+    pub content: String
+}
+
+impl CBCARecord {
+    pub fn spawn(
+        conn: u64,
+        dir: CBCADirection,
+        at_ms: u64,
+        frame: &CBCATcpPayload,
+        deflate: bool,
+        size: usize
+    ) -> Self {
+        Self {
+            conn,
+            dir,
+            at_ms,
+            frame_type: format!("{:02}", frame.get_type().get_code()),
+            deflate,
+            size,
+            content: frame.get_content().to_string()
+        }
+    }
+
+    pub fn get_type(&self) -> CBCATcpPayloadType {
+        CBCATcpPayloadType::from_str(&self.frame_type)
+    }
+
+    // Reads back a file written by a CBCARecorder.
+    pub fn load(path: &PathBuf) -> Result<Vec<CBCARecord>, std::io::Error> {
+        let reader: BufReader<File> = BufReader::new(File::open(path)?);
+        let mut records: Vec<CBCARecord> = Vec::new();
+
+        for line in reader.lines() {
+            let line: String = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            records.push(serde_json::from_str(&line)?);
+        }
+
+        Ok(records)
+    }
+}
+
+// Appends the frames of every proxied connection to a JSON lines file.
+#[derive(Clone)]
+pub struct CBCARecorder {
+    file: Arc<Mutex<File>>
+}
+
+impl CBCARecorder {
+    pub fn spawn(path: PathBuf) -> Result<Self, std::io::Error> {
+        let file: File = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(Self { file: Arc::new(Mutex::new(file)) })
+    }
+
+    pub fn write(&self, record: &CBCARecord) -> Result<(), std::io::Error> {
+        let line: String = serde_json::to_string(record)?;
+        let mut lock = self.file.lock().map_err(|_| {
+            std::io::Error::other("record file poisoned.")
+        })?;
+
+        writeln!(lock, "{}", line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::communication::CBCATcpPayload;
+
+    use super::*;
+
+    #[test]
+    fn records_read_back_as_written() {
+        let path: PathBuf = std::env::temp_dir().join(format!("cbca-record-{}.jsonl", std::process::id()));
+        let recorder: CBCARecorder = CBCARecorder::spawn(path.clone()).unwrap();
+
+        let asked: CBCATcpPayload = CBCATcpPayload::spawn(CBCATcpPayloadType::Reqwest, "{\"instance_id\":\"i\"}".to_string());
+        let answered: CBCATcpPayload = CBCATcpPayload::spawn(CBCATcpPayloadType::Data, "line\nbreak".to_string());
+        let written: Vec<CBCARecord> = vec![
+            CBCARecord::spawn(1, CBCADirection::ClientToServer, 0, &asked, false, 32),
+            CBCARecord::spawn(1, CBCADirection::ServerToClient, 12, &answered, true, 24)
+        ];
+        for record in &written {
+            recorder.write(record).unwrap();
+        }
+
+        let loaded: Vec<CBCARecord> = CBCARecord::load(&path).unwrap();
+        assert_eq!(loaded.len(), written.len());
+        for (got, record) in loaded.iter().zip(&written) {
+            assert_eq!(serde_json::to_value(got).unwrap(), serde_json::to_value(record).unwrap());
+        }
+        assert!(loaded[0].get_type().eq(&CBCATcpPayloadType::Reqwest));
+        assert_eq!(loaded[1].content, "line\nbreak");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,192 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde_json::Value;
+use shared::{
+    communication::{CBCATcpPayload, CBCATcpPayloadType},
+    compression::CBCAFrameEncoding,
+    limits::CBCALimits,
+    transport::CBCAStream
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    proxy::{print_frame, CBCAFrameDecoder},
+    record::{CBCADirection, CBCARecord}
+};
+
+// Keys whose values change from one run to the other, they aren't compared.
+const CBCA_VOLATILE_KEYS: [&str; 5] = ["hash", "previous_hash", "head_hash", "timestamp", "closes_at"];
+
+fn mask_volatile(value: &mut Value) {
+    match value {
+        Value::Object(v) => {
+            for (key, field) in v.iter_mut() {
+                if CBCA_VOLATILE_KEYS.contains(&key.as_str()) {
+                    *field = Value::Null;
+                } else {
+                    mask_volatile(field);
+                }
+            }
+        },
+        Value::Array(v) => v.iter_mut().for_each(mask_volatile),
+        _ => {}
+    }
+}
+
+// Contents are compared as JSON when both sides parse, as text otherwise.
+fn same_content(expected: &str, got: &str) -> bool {
+    match (serde_json::from_str::<Value>(expected), serde_json::from_str::<Value>(got)) {
+        (Ok(mut a), Ok(mut b)) => {
+            mask_volatile(&mut a);
+            mask_volatile(&mut b);
+            a == b
+        },
+        _ => expected == got
+    }
+}
+
+#[derive(Default)]
+struct CBCAReplayReport {
+    sent: usize,
+    matched: usize,
+    mismatched: usize
+}
+
+// Plays the client side of a recorded session against a server, and checks its answers
+// are the recorded ones.
+pub struct CBCAReplay {
+    record_file: PathBuf,
+    upstream: String,
+    limits: CBCALimits
+}
+
+impl CBCAReplay {
+    pub fn spawn(
+        record_file: PathBuf,
+        upstream: String,
+        limits: CBCALimits
+    ) -> Self {
+        Self { record_file, upstream, limits }
+    }
+
+    pub async fn run(&self) -> Result<(), std::io::Error> {
+        let records: Vec<CBCARecord> = CBCARecord::load(&self.record_file)?;
+
+        // Connections are replayed one after the other, in the order they were opened.
+        let mut connections: BTreeMap<u64, Vec<CBCARecord>> = BTreeMap::new();
+        for record in records {
+            connections.entry(record.conn).or_default().push(record);
+        }
+
+        let mut report: CBCAReplayReport = CBCAReplayReport::default();
+
+        for (conn, records) in connections {
+            println!("[REPLAY #{}] {} frames.", conn, records.len());
+
+            if let Err(e) = self.replay_connection(conn, &records, &mut report).await {
+                println!("[REPLAY #{}] failed: {}", conn, e);
+                report.mismatched += 1;
+            }
+        }
+
+        println!(
+            "[REPLAY] {} frames sent, {} answers matched, {} mismatched.",
+            report.sent,
+            report.matched,
+            report.mismatched
+        );
+
+        if report.mismatched > 0 {
+            return Err(std::io::Error::other(
+                format!("{} answers differ from the recording.", report.mismatched)
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn replay_connection(
+        &self,
+        conn: u64,
+        records: &[CBCARecord],
+        report: &mut CBCAReplayReport
+    ) -> Result<(), std::io::Error> {
+        let mut stream: CBCAStream = CBCAStream::connect(&self.upstream, None).await?;
+        let mut decoder: CBCAFrameDecoder = CBCAFrameDecoder::spawn(self.limits.max_payload_size);
+
+        for record in records {
+            // Heartbeats depend on timing, not on what was asked.
+            if record.get_type().is_heartbeat() {
+                continue;
+            }
+
+            match record.dir {
+                CBCADirection::ClientToServer => {
+                    let encoding: CBCAFrameEncoding = match record.deflate {
+                        true => CBCAFrameEncoding::Deflate,
+                        false => CBCAFrameEncoding::Plain
+                    };
+
+                    // Same bytes as the recorded client, the last chunk padded to 8.
+                    let mut data: Vec<u8> = CBCATcpPayload::spawn(record.get_type(), record.content.clone())
+                        .build_frame(encoding)?;
+                    data.resize(data.len().div_ceil(8) * 8, 0);
+
+                    stream.write_all(&data).await?;
+                    stream.flush().await?;
+                    report.sent += 1;
+                },
+                CBCADirection::ServerToClient => {
+                    let (frame, encoding, size) = self.next_frame(&mut stream, &mut decoder).await?;
+                    let expected: CBCATcpPayloadType = record.get_type();
+
+                    if frame.get_type().eq(&expected) && same_content(&record.content, frame.get_content()) {
+                        report.matched += 1;
+                        continue;
+                    }
+
+                    report.mismatched += 1;
+                    println!("[REPLAY #{}] mismatch, expected {:?}:", conn, expected);
+                    println!("{}", record.content);
+                    print_frame(&format!("[REPLAY #{}] got", conn), &frame, encoding, size);
+                }
+            }
+        }
+
+        stream.shutdown().await
+    }
+
+    // Next frame from the server that isn't a heartbeat.
+    async fn next_frame(
+        &self,
+        stream: &mut CBCAStream,
+        decoder: &mut CBCAFrameDecoder
+    ) -> Result<(CBCATcpPayload, CBCAFrameEncoding, usize), std::io::Error> {
+        let mut buf: [u8; 4096] = [0u8; 4096];
+
+        loop {
+            while let Some((frame, encoding, size)) = decoder.next_frame()? {
+                if !frame.get_type().is_heartbeat() {
+                    return Ok((frame, encoding, size));
+                }
+            }
+
+            let n: usize = match tokio::time::timeout(self.limits.idle_timeout, stream.read(&mut buf)).await {
+                Ok(v) => v?,
+                Err(_) => return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "server didn't answer."
+                ))
+            };
+
+            if n == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "server closed the connection."
+                ));
+            }
+
+            decoder.push(&buf[..n]);
+        }
+    }
+}
@@ -0,0 +1,586 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, MutexGuard}, time::{Duration, Instant}};
+
+use tokio::sync::{mpsc::{self, error::SendError}, oneshot};
+use shared::{
+    block::CBCABlock,
+    communication::{CBCAErrorCode, CBCAErrorPayload},
+    event::CBCAEvent,
+    fchain::{CBCAChain, CBCAChainKind},
+    request::{CBCAChainPage, CBCAReceipt}
+};
+
+use crate::{
+    events::CBCAEventBus,
+    idempotency::CBCAIdempotencyCache,
+    instance::CBCAInstance,
+    stats::CBCAStats,
+    storage::CBCAStorage
+};
+
+// An actor nobody wrote to or read from for this long lets its instance go.
+const CBCA_ACTOR_IDLE: Duration = Duration::from_secs(300);
+// Commands waiting for an actor, senders wait past it.
+const CBCA_ACTOR_BACKLOG: usize = 256;
+// Appends queued behind a first one are written with it, in one sync.
+const CBCA_BATCH_BLOCKS: usize = 64;
+
+pub type CBCAReply<T> = oneshot::Sender<Result<T, std::io::Error>>;
+pub type CBCAAnswer<T> = oneshot::Receiver<Result<T, std::io::Error>>;
+
+pub enum CBCAActorCommand {
+    Append {
+        kind: CBCAChainKind,
+        block: Box<CBCABlock>,
+        key: Option<String>,
+        reply: CBCAReply<CBCAReceipt>
+    },
+    ReadRange {
+        kind: CBCAChainKind,
+        from: usize,
+        limit: usize,
+        reply: CBCAReply<CBCAChainPage>
+    },
+    ReadAfter {
+        kind: CBCAChainKind,
+        hash: String,
+        limit: usize,
+        reply: CBCAReply<Option<CBCAChainPage>>
+    },
+    Snapshot {
+        reply: CBCAReply<CBCAInstance>
+    }
+}
+
+struct CBCAActorMessage {
+    sent: Instant,
+    command: CBCAActorCommand
+}
+
+type CBCAPendingAppend = (Box<CBCABlock>, Option<String>, CBCAReply<CBCAReceipt>);
+
+// A block to write, with its key and the callers waiting for it, the first one asked for it
+// and the others sent the same key while it was queued.
+type CBCAKeyedAppend = (CBCABlock, Option<String>, Vec<CBCAReply<CBCAReceipt>>);
+
+// Copies an error for every caller of a batch, io errors can't be cloned.
+fn copy_error(error: &std::io::Error) -> std::io::Error {
+    match error.get_ref().and_then(|v| v.downcast_ref::<CBCAErrorPayload>()) {
+        Some(v) => v.clone().into(),
+        None => std::io::Error::new(error.kind(), error.to_string())
+    }
+}
+
+// Owns one instance in memory, runs its commands one after the other and writes its blocks
+// to the storage before keeping them and announcing them, in chain order.
+struct CBCAInstanceActor {
+    instance: CBCAInstance,
+    storage: Arc<dyn CBCAStorage>,
+    events: CBCAEventBus,
+    idempotency: CBCAIdempotencyCache
+}
+
+impl CBCAInstanceActor {
+    fn get_chain_mut(&mut self, kind: CBCAChainKind) -> &mut CBCAChain {
+        match kind {
+            CBCAChainKind::Offers => &mut self.instance.offers_chain,
+            CBCAChainKind::Messages => &mut self.instance.messages_chain
+        }
+    }
+
+    // Every caller gets its own block back, or the block its key already appended. Returns false
+    // once the chain in memory no longer matches the storage.
+    async fn append(
+        &mut self,
+        kind: CBCAChainKind,
+        batch: Vec<CBCAPendingAppend>
+    ) -> bool {
+        let mut pending: Vec<CBCAKeyedAppend> = Vec::with_capacity(batch.len());
+
+        for (block, key, reply) in batch {
+            if let Some(v) = key.as_deref() {
+                if let Some(block) = self.idempotency.lookup(&self.instance.identifier, kind, v) {
+                    let _ = reply.send(Ok(CBCAReceipt { block, duplicate: true }));
+                    continue;
+                }
+
+                if let Some((_, _, replies)) = pending.iter_mut().find(|(_, k, _)| k.as_deref() == Some(v)) {
+                    replies.push(reply);
+                    continue;
+                }
+            }
+
+            // Offers made once the auction closed are refused, the settlement would ignore them.
+            if kind == CBCAChainKind::Offers && !self.instance.is_in_time(block.get_timestamp()) {
+                let closed: CBCAErrorPayload = CBCAErrorPayload::spawn(
+                    CBCAErrorCode::Closed,
+                    format!("auction {} is closed.", self.instance.identifier)
+                );
+                let _ = reply.send(Err(closed.into()));
+                continue;
+            }
+
+            pending.push((*block, key, vec![reply]));
+        }
+
+        if pending.is_empty() {
+            return true;
+        }
+
+        let blocks: Vec<CBCABlock> = pending.iter().map(|(v, _, _)| v.clone()).collect();
+
+        let stored: Vec<CBCABlock> = match self.storage.append_batch(&self.instance.identifier, kind, blocks).await {
+            Ok(v) => v,
+            Err(e) => {
+                for reply in pending.into_iter().flat_map(|(_, _, v)| v) {
+                    let _ = reply.send(Err(copy_error(&e)));
+                }
+                return true;
+            }
+        };
+
+        let mut healthy: bool = true;
+        for (block, (_, key, replies)) in stored.into_iter().zip(pending) {
+            if healthy && !self.get_chain_mut(kind).push_linked(block.clone()) {
+                log::error!("[ACTOR] {} {:?} chain out of step with the storage.", self.instance.identifier, kind);
+                healthy = false;
+            }
+
+            if let Some(v) = key {
+                self.idempotency.record(&self.instance.identifier, kind, v, block.clone());
+            }
+
+            self.events.publish(CBCAEvent::BlockAppended {
+                instance_id: self.instance.identifier.clone(),
+                chain: kind,
+                block: Box::new(block.clone())
+            });
+
+            for (i, reply) in replies.into_iter().enumerate() {
+                let _ = reply.send(Ok(CBCAReceipt { block: block.clone(), duplicate: i > 0 }));
+            }
+        }
+
+        healthy
+    }
+
+    // Appends of both chains, each chain written in one go.
+    async fn append_all(
+        &mut self,
+        batch: Vec<(CBCAChainKind, CBCAPendingAppend)>
+    ) -> bool {
+        let (offers, messages): (Vec<_>, Vec<_>) = batch.into_iter()
+            .partition(|(kind, _)| *kind == CBCAChainKind::Offers);
+        let mut healthy: bool = true;
+
+        for (kind, pending) in [(CBCAChainKind::Offers, offers), (CBCAChainKind::Messages, messages)] {
+            if !pending.is_empty() {
+                healthy &= self.append(kind, pending.into_iter().map(|(_, v)| v).collect()).await;
+            }
+        }
+
+        healthy
+    }
+
+    // Returns false once the instance in memory no longer matches the storage.
+    async fn handle(&mut self, command: CBCAActorCommand) -> bool {
+        match command {
+            CBCAActorCommand::Append { kind, block, key, reply } => {
+                return self.append(kind, vec![(block, key, reply)]).await;
+            },
+            CBCAActorCommand::ReadRange { kind, from, limit, reply } => {
+                let _ = reply.send(Ok(self.instance.page(kind, from, limit)));
+            },
+            CBCAActorCommand::ReadAfter { kind, hash, limit, reply } => {
+                let page: Option<CBCAChainPage> = self.instance.get_chain(kind)
+                    .position(&hash)
+                    .map(|v| self.instance.page(kind, v + 1, limit));
+                let _ = reply.send(Ok(page));
+            },
+            CBCAActorCommand::Snapshot { reply } => {
+                let _ = reply.send(Ok(self.instance.clone()));
+            }
+        }
+
+        true
+    }
+}
+
+// Answers a command an actor can't run, its instance didn't load or is being reloaded.
+fn refuse(command: CBCAActorCommand, error: &std::io::Error) {
+    match command {
+        CBCAActorCommand::Append { reply, .. } => { let _ = reply.send(Err(copy_error(error))); },
+        CBCAActorCommand::ReadRange { reply, .. } => { let _ = reply.send(Err(copy_error(error))); },
+        CBCAActorCommand::ReadAfter { reply, .. } => { let _ = reply.send(Err(copy_error(error))); },
+        CBCAActorCommand::Snapshot { reply } => { let _ = reply.send(Err(copy_error(error))); }
+    }
+}
+
+// The actors of the instances in use, started on the first write and stopped once idle.
+#[derive(Debug, Clone)]
+pub struct CBCAActors {
+    actors: Arc<Mutex<HashMap<String, mpsc::Sender<CBCAActorMessage>>>>,
+    events: CBCAEventBus,
+    idempotency: CBCAIdempotencyCache,
+    stats: CBCAStats
+}
+
+impl CBCAActors {
+    pub fn spawn(
+        stats: CBCAStats,
+        events: CBCAEventBus,
+        idempotency: CBCAIdempotencyCache
+    ) -> Self {
+        Self {
+            actors: Arc::new(Mutex::new(HashMap::new())),
+            events,
+            idempotency,
+            stats
+        }
+    }
+
+    fn actors(&self) -> MutexGuard<'_, HashMap<String, mpsc::Sender<CBCAActorMessage>>> {
+        match self.actors.lock() {
+            Ok(v) => v,
+            Err(v) => v.into_inner()
+        }
+    }
+
+    // Instances held in memory by a running actor.
+    pub fn get_len(&self) -> usize {
+        self.actors().values().filter(|v| !v.is_closed()).count()
+    }
+
+    fn live(&self, instance_id: &str) -> Option<mpsc::Sender<CBCAActorMessage>> {
+        self.actors().get(instance_id).filter(|v| !v.is_closed()).cloned()
+    }
+
+    // The sender is registered before the instance loads, so a second caller never starts
+    // a second actor for the same instance.
+    fn live_or_start(
+        &self,
+        instance_id: &str,
+        storage: &Arc<dyn CBCAStorage>
+    ) -> mpsc::Sender<CBCAActorMessage> {
+        let mut actors = self.actors();
+
+        if let Some(v) = actors.get(instance_id).filter(|v| !v.is_closed()) {
+            return v.clone();
+        }
+
+        let (sender, receiver) = mpsc::channel::<CBCAActorMessage>(CBCA_ACTOR_BACKLOG);
+        actors.insert(instance_id.to_string(), sender.clone());
+        tokio::spawn(self.clone().run(instance_id.to_string(), Arc::clone(storage), receiver));
+
+        sender
+    }
+
+    async fn run(
+        self,
+        instance_id: String,
+        storage: Arc<dyn CBCAStorage>,
+        mut receiver: mpsc::Receiver<CBCAActorMessage>
+    ) {
+        let instance: CBCAInstance = match storage.load(&instance_id).await {
+            Ok(v) => v,
+            Err(e) => {
+                self.stop(&mut receiver);
+                while let Some(message) = receiver.recv().await {
+                    refuse(message.command, &e);
+                }
+                return;
+            }
+        };
+
+        log::debug!("[ACTOR] {} started.", instance_id);
+        let mut actor: CBCAInstanceActor = CBCAInstanceActor {
+            instance,
+            storage,
+            events: self.events.clone(),
+            idempotency: self.idempotency.clone()
+        };
+        let mut healthy: bool = true;
+        let mut next: Option<CBCAActorMessage> = None;
+
+        loop {
+            let message: CBCAActorMessage = match next.take() {
+                Some(v) => v,
+                None => match tokio::time::timeout(CBCA_ACTOR_IDLE, receiver.recv()).await {
+                    Ok(Some(v)) => v,
+                    _ => break
+                }
+            };
+            self.stats.record_lock_wait(message.sent.elapsed());
+
+            healthy = match message.command {
+                CBCAActorCommand::Append { kind, block, key, reply } => {
+                    let mut batch: Vec<(CBCAChainKind, CBCAPendingAppend)> = vec![(kind, (block, key, reply))];
+                    next = self.gather(&mut receiver, &mut batch);
+                    actor.append_all(batch).await
+                },
+                command => actor.handle(command).await
+            };
+
+            if !healthy {
+                break;
+            }
+        }
+
+        // Commands already sent are still answered, senders coming after start a new actor
+        // which loads the instance again.
+        self.stop(&mut receiver);
+        let stale: std::io::Error = std::io::Error::other(format!("{} is reloading, try again.", instance_id));
+
+        loop {
+            let message: CBCAActorMessage = match next.take() {
+                Some(v) => v,
+                None => match receiver.recv().await {
+                    Some(v) => v,
+                    None => break
+                }
+            };
+
+            if healthy {
+                healthy = actor.handle(message.command).await;
+            } else {
+                refuse(message.command, &stale);
+            }
+        }
+
+        log::debug!("[ACTOR] {} stopped.", instance_id);
+    }
+
+    // Takes the appends already queued behind the first one of a batch, until the queue is empty,
+    // the batch is full or another command comes, which is returned to run after the batch.
+    // Nothing waits, appends sent while a batch syncs make up the next one.
+    fn gather(
+        &self,
+        receiver: &mut mpsc::Receiver<CBCAActorMessage>,
+        batch: &mut Vec<(CBCAChainKind, CBCAPendingAppend)>
+    ) -> Option<CBCAActorMessage> {
+        while batch.len() < CBCA_BATCH_BLOCKS {
+            let message: CBCAActorMessage = match receiver.try_recv() {
+                Ok(v) => v,
+                Err(_) => return None
+            };
+
+            match message.command {
+                CBCAActorCommand::Append { kind, block, key, reply } => {
+                    self.stats.record_lock_wait(message.sent.elapsed());
+                    batch.push((kind, (block, key, reply)));
+                },
+                command => return Some(CBCAActorMessage { sent: message.sent, command })
+            }
+        }
+
+        None
+    }
+
+    fn stop(&self, receiver: &mut mpsc::Receiver<CBCAActorMessage>) {
+        receiver.close();
+        self.actors().retain(|_, v| !v.is_closed());
+    }
+
+    // Queues a command on the actor of the instance, started if needed, and returns without
+    // waiting for it to run. Commands queued one after the other run in that order.
+    pub async fn tell<T>(
+        &self,
+        instance_id: &str,
+        storage: &Arc<dyn CBCAStorage>,
+        command: impl FnOnce(CBCAReply<T>) -> CBCAActorCommand
+    ) -> CBCAAnswer<T> {
+        let (reply, answer) = oneshot::channel::<Result<T, std::io::Error>>();
+        let mut message: CBCAActorMessage = CBCAActorMessage { sent: Instant::now(), command: command(reply) };
+
+        // An actor stopping right now refuses the message, the next one takes it.
+        while let Err(SendError(v)) = self.live_or_start(instance_id, storage).send(message).await {
+            message = v;
+        }
+
+        answer
+    }
+
+    // Runs a command on the actor of the instance without starting one, None when the instance
+    // has none running.
+    pub async fn ask_live<T>(
+        &self,
+        instance_id: &str,
+        command: impl FnOnce(CBCAReply<T>) -> CBCAActorCommand
+    ) -> Option<Result<T, std::io::Error>> {
+        let (reply, answer) = oneshot::channel::<Result<T, std::io::Error>>();
+        let message: CBCAActorMessage = CBCAActorMessage { sent: Instant::now(), command: command(reply) };
+
+        self.live(instance_id)?.send(message).await.ok()?;
+        Some(wait(instance_id, answer).await)
+    }
+}
+
+// Waits for the answer of a command queued with `tell`.
+pub async fn wait<T>(instance_id: &str, answer: CBCAAnswer<T>) -> Result<T, std::io::Error> {
+    answer.await.map_err(|_| std::io::Error::other(format!("actor of {} stopped.", instance_id)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use shared::{fchain::CBCAConfig, payload::issue_instance_id, request::CBCAInstanceSummary};
+
+    use crate::storage::CBCAMemoryStorage;
+    use super::*;
+
+    // Counts the writes reaching the memory storage.
+    #[derive(Debug, Default)]
+    struct CBCACountingStorage {
+        inner: CBCAMemoryStorage,
+        batches: AtomicUsize
+    }
+
+    #[async_trait]
+    impl CBCAStorage for CBCACountingStorage {
+        fn get_name(&self) -> &'static str {
+            "counting"
+        }
+
+        async fn create(&self, instance: &CBCAInstance) -> Result<(), std::io::Error> {
+            self.inner.create(instance).await
+        }
+
+        async fn append_batch(
+            &self,
+            instance_id: &str,
+            kind: CBCAChainKind,
+            blocks: Vec<CBCABlock>
+        ) -> Result<Vec<CBCABlock>, std::io::Error> {
+            self.batches.fetch_add(1, Ordering::SeqCst);
+            self.inner.append_batch(instance_id, kind, blocks).await
+        }
+
+        async fn read_range(
+            &self,
+            instance_id: &str,
+            kind: CBCAChainKind,
+            from: usize,
+            limit: usize
+        ) -> Result<CBCAChainPage, std::io::Error> {
+            self.inner.read_range(instance_id, kind, from, limit).await
+        }
+
+        async fn position(
+            &self,
+            instance_id: &str,
+            kind: CBCAChainKind,
+            hash: &str
+        ) -> Result<Option<usize>, std::io::Error> {
+            self.inner.position(instance_id, kind, hash).await
+        }
+
+        async fn load(&self, instance_id: &str) -> Result<CBCAInstance, std::io::Error> {
+            self.inner.load(instance_id).await
+        }
+
+        async fn summary(&self, instance_id: &str) -> Result<CBCAInstanceSummary, std::io::Error> {
+            self.inner.summary(instance_id).await
+        }
+
+        async fn list(&self) -> Result<Vec<String>, std::io::Error> {
+            self.inner.list().await
+        }
+    }
+
+    #[tokio::test]
+    async fn appends_sent_together_share_writes() {
+        let counting: Arc<CBCACountingStorage> = Arc::new(CBCACountingStorage::default());
+        let storage: Arc<dyn CBCAStorage> = counting.clone();
+        let actors: CBCAActors = CBCAActors::spawn(
+            CBCAStats::spawn(),
+            CBCAEventBus::spawn(16),
+            CBCAIdempotencyCache::spawn(16, Duration::from_secs(60))
+        );
+
+        let config: CBCAConfig = CBCAConfig::spawn(
+            None, false, None, 300, "d".to_string(), "n".to_string(), "EUR".to_string()
+        ).unwrap();
+        let instance: CBCAInstance = CBCAInstance::spawn(config, &issue_instance_id());
+        let instance_id: String = instance.identifier.clone();
+        storage.create(&instance).await.unwrap();
+
+        let sent: usize = 32;
+        let mut answers: Vec<CBCAAnswer<CBCAReceipt>> = Vec::new();
+        for i in 0..sent {
+            let block: CBCABlock = CBCABlock::block_creator_message(format!("m{}", i), "a".to_string(), instance_id.clone());
+            answers.push(actors.tell(&instance_id, &storage, |reply| CBCAActorCommand::Append {
+                kind: CBCAChainKind::Messages,
+                block: Box::new(block),
+                key: None,
+                reply
+            }).await);
+        }
+
+        let mut hashes: Vec<Option<String>> = Vec::new();
+        for answer in answers {
+            hashes.push(wait(&instance_id, answer).await.unwrap().block.get_hash());
+        }
+
+        // Every caller got its own block, in the order they were sent.
+        let stored: CBCAInstance = storage.load(&instance_id).await.unwrap();
+        assert_eq!(stored.messages_chain.first_invalid(), None);
+        assert_eq!(stored.messages_chain.blocks().iter().map(|v| v.get_hash()).collect::<Vec<_>>(), hashes);
+
+        let batches: usize = counting.batches.load(Ordering::SeqCst);
+        assert!(batches < sent, "{} appends took {} writes", sent, batches);
+    }
+
+    #[tokio::test]
+    async fn replayed_keys_append_once() {
+        let storage: Arc<dyn CBCAStorage> = Arc::new(CBCAMemoryStorage::default());
+        let actors: CBCAActors = CBCAActors::spawn(
+            CBCAStats::spawn(),
+            CBCAEventBus::spawn(16),
+            CBCAIdempotencyCache::spawn(16, Duration::from_secs(60))
+        );
+
+        let config: CBCAConfig = CBCAConfig::spawn(
+            None, false, None, 300, "d".to_string(), "n".to_string(), "EUR".to_string()
+        ).unwrap();
+        let instance: CBCAInstance = CBCAInstance::spawn(config, &issue_instance_id());
+        let instance_id: String = instance.identifier.clone();
+        storage.create(&instance).await.unwrap();
+
+        let append = |content: &str| {
+            let block: CBCABlock = CBCABlock::block_creator_message(content.to_string(), "a".to_string(), instance_id.clone());
+            actors.tell(&instance_id, &storage, |reply| CBCAActorCommand::Append {
+                kind: CBCAChainKind::Messages,
+                block: Box::new(block),
+                key: Some("k".to_string()),
+                reply
+            })
+        };
+
+        // Three with the same key queued together, then a retry once they were written.
+        let mut answers: Vec<CBCAAnswer<CBCAReceipt>> = Vec::new();
+        for content in ["m0", "m1", "m2"] {
+            answers.push(append(content).await);
+        }
+
+        let mut receipts: Vec<CBCAReceipt> = Vec::new();
+        for answer in answers {
+            receipts.push(wait(&instance_id, answer).await.unwrap());
+        }
+        receipts.push(wait(&instance_id, append("m3").await).await.unwrap());
+
+        assert_eq!(receipts.iter().map(|v| v.duplicate).collect::<Vec<_>>(), vec![false, true, true, true]);
+        assert!(receipts.iter().all(|v| v.block.get_hash() == receipts[0].block.get_hash()));
+        assert_eq!(storage.load(&instance_id).await.unwrap().messages_chain.len(), 1);
+
+        // The same key on the other chain is another request.
+        let block: CBCABlock = CBCABlock::block_creator_offer(2.0, "a".to_string(), instance_id.clone(), None);
+        let answer: CBCAAnswer<CBCAReceipt> = actors.tell(&instance_id, &storage, |reply| CBCAActorCommand::Append {
+            kind: CBCAChainKind::Offers,
+            block: Box::new(block),
+            key: Some("k".to_string()),
+            reply
+        }).await;
+        assert!(!wait(&instance_id, answer).await.unwrap().duplicate);
+    }
+}
@@ -0,0 +1,522 @@
+use std::{env, path::{Path, PathBuf}, str::FromStr, time::Duration};
+
+use clap::Parser;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use shared::{limits::{CBCALimits, CBCA_MAX_FRAME_SIZE}, tls::CBCATlsServerConfig};
+
+use crate::{
+    queue::{CBCA_QUEUE_CAPACITY, CBCA_QUEUE_WORKERS},
+    ratelimit::{CBCARateBudget, CBCARateLimits},
+    server::CBCA_MAX_CONNECTIONS,
+    shutdown::CBCA_SHUTDOWN_DEADLINE,
+    storage::CBCAStorageConfig
+};
+
+// Options of the command line, each one wins over the config file and the environment.
+#[derive(Debug, Parser)]
+#[command(name = "cbca-protocol-server", version, about = "Serves the BCA protocol routines.")]
+pub struct CBCAArgs {
+    #[arg(short, long, value_name = "FILE", help = "TOML file read before the CBCA_* variables and these options")]
+    pub config: Option<PathBuf>,
+    #[arg(long, help = "Validate the configuration, print it and exit")]
+    pub check_config: bool,
+    #[arg(long, value_name = "LEVEL", help = "error, warn, info, debug or trace")]
+    pub log_level: Option<String>,
+    #[arg(long, help = "Address the routines and the gateway listen on")]
+    pub host: Option<String>,
+    #[arg(long, value_name = "DIR", help = "Listen on <name>.sock files in this directory instead of ports")]
+    pub unix_dir: Option<PathBuf>,
+    #[arg(long)]
+    pub message_port: Option<u16>,
+    #[arg(long)]
+    pub instance_port: Option<u16>,
+    #[arg(long)]
+    pub offer_port: Option<u16>,
+    #[arg(long)]
+    pub subscribe_port: Option<u16>,
+    #[arg(long)]
+    pub query_port: Option<u16>,
+    #[arg(long, help = "Opened only with an admin token")]
+    pub admin_port: Option<u16>,
+    #[arg(long, help = "Serves the WebSocket and REST gateway")]
+    pub gateway_port: Option<u16>,
+    #[arg(long, value_name = "BACKEND", help = "json[:<dir>], memory or sqlite[:<file>]")]
+    pub storage: Option<String>,
+    #[arg(long, value_name = "DIR", help = "Where json and sqlite keep instances without a path of their own")]
+    pub data_dir: Option<PathBuf>,
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+    #[arg(long, value_name = "BYTES")]
+    pub max_payload_size: Option<usize>,
+    #[arg(long, value_name = "MS")]
+    pub idle_timeout_ms: Option<u64>,
+    #[arg(long, value_name = "FILE")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, value_name = "FILE")]
+    pub tls_key: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", help = "Require client certificates signed by this CA")]
+    pub tls_client_ca: Option<PathBuf>,
+    #[arg(long, value_name = "MS", help = "How long SIGINT and SIGTERM wait for work under way")]
+    pub shutdown_deadline_ms: Option<u64>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CBCABindConfig {
+    pub host: String,
+    pub unix_dir: Option<PathBuf>,
+    // Octal, who may connect to the sockets.
+    pub unix_mode: String,
+    pub message: u16,
+    pub instance: u16,
+    pub offer: u16,
+    pub subscribe: u16,
+    pub query: u16,
+    // Only opened with an admin token.
+    pub admin: u16,
+    // The WebSocket and REST gateway, off unless set.
+    pub gateway: Option<u16>
+}
+
+impl Default for CBCABindConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            unix_dir: None,
+            unix_mode: "660".to_string(),
+            message: 8686,
+            instance: 8687,
+            offer: 8688,
+            subscribe: 8689,
+            query: 8690,
+            admin: 8691,
+            gateway: None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CBCAStorageSection {
+    // json[:<dir>], memory or sqlite[:<file>], without a path the data directory is used.
+    pub backend: String,
+    pub data_dir: PathBuf
+}
+
+impl Default for CBCAStorageSection {
+    fn default() -> Self {
+        Self { backend: "json".to_string(), data_dir: PathBuf::from("data") }
+    }
+}
+
+// Timeouts in milliseconds, see CBCALimits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CBCALimitsSection {
+    pub idle_timeout_ms: u64,
+    pub header_timeout_ms: u64,
+    pub body_timeout_ms: u64,
+    pub write_timeout_ms: u64,
+    pub heartbeat_ms: u64,
+    pub keepalive_ms: u64,
+    pub max_payload_size: usize,
+    pub max_connections: usize
+}
+
+impl CBCALimitsSection {
+    fn from_limits(limits: CBCALimits, max_connections: usize) -> Self {
+        Self {
+            idle_timeout_ms: limits.idle_timeout.as_millis() as u64,
+            header_timeout_ms: limits.header_timeout.as_millis() as u64,
+            body_timeout_ms: limits.body_timeout.as_millis() as u64,
+            write_timeout_ms: limits.write_timeout.as_millis() as u64,
+            heartbeat_ms: limits.heartbeat_interval.as_millis() as u64,
+            keepalive_ms: limits.keepalive.as_millis() as u64,
+            max_payload_size: limits.max_payload_size,
+            max_connections
+        }
+    }
+
+    pub fn to_limits(&self) -> CBCALimits {
+        CBCALimits {
+            idle_timeout: Duration::from_millis(self.idle_timeout_ms),
+            header_timeout: Duration::from_millis(self.header_timeout_ms),
+            body_timeout: Duration::from_millis(self.body_timeout_ms),
+            write_timeout: Duration::from_millis(self.write_timeout_ms),
+            heartbeat_interval: Duration::from_millis(self.heartbeat_ms),
+            keepalive: Duration::from_millis(self.keepalive_ms),
+            max_payload_size: self.max_payload_size
+        }
+    }
+}
+
+impl Default for CBCALimitsSection {
+    fn default() -> Self {
+        Self::from_limits(CBCALimits::default(), CBCA_MAX_CONNECTIONS)
+    }
+}
+
+// Budgets written "burst/per_minute" or "off".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CBCARateSection {
+    pub message: String,
+    pub offer: String,
+    pub instance: String
+}
+
+impl CBCARateSection {
+    fn from_limits(limits: CBCARateLimits) -> Self {
+        Self {
+            message: limits.message.to_string(),
+            offer: limits.offer.to_string(),
+            instance: limits.instance.to_string()
+        }
+    }
+
+    pub fn to_limits(&self) -> Option<CBCARateLimits> {
+        Some(
+            CBCARateLimits {
+                message: CBCARateBudget::parse(&self.message)?,
+                offer: CBCARateBudget::parse(&self.offer)?,
+                instance: CBCARateBudget::parse(&self.instance)?
+            }
+        )
+    }
+}
+
+impl Default for CBCARateSection {
+    fn default() -> Self {
+        Self::from_limits(CBCARateLimits::default())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CBCAQueueSection {
+    pub capacity: usize,
+    pub workers: usize
+}
+
+impl Default for CBCAQueueSection {
+    fn default() -> Self {
+        Self { capacity: CBCA_QUEUE_CAPACITY, workers: CBCA_QUEUE_WORKERS }
+    }
+}
+
+// TLS is on once both the certificate and the key are set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CBCATlsSection {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>
+}
+
+impl CBCATlsSection {
+    pub fn to_tls(&self) -> Option<CBCATlsServerConfig> {
+        match (&self.cert, &self.key) {
+            (Some(c), Some(k)) => Some(CBCATlsServerConfig::spawn(c.clone(), k.clone(), self.client_ca.clone())),
+            _ => None
+        }
+    }
+}
+
+// Past the deadline the server exits with work still under way, recovery repairs the chains.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CBCAShutdownSection {
+    pub deadline_ms: u64
+}
+
+impl CBCAShutdownSection {
+    pub fn get_deadline(&self) -> Duration {
+        Duration::from_millis(self.deadline_ms)
+    }
+}
+
+impl Default for CBCAShutdownSection {
+    fn default() -> Self {
+        Self { deadline_ms: CBCA_SHUTDOWN_DEADLINE.as_millis() as u64 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CBCAAdminSection {
+    // The admin routine is off without it.
+    pub token: Option<String>
+}
+
+// Everything the server starts with. Defaults, then the TOML file, then the CBCA_* variables,
+// then the command line, each one overriding the one before.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CBCAServerConfig {
+    // error, warn, info, debug or trace.
+    pub log_level: String,
+    pub bind: CBCABindConfig,
+    pub storage: CBCAStorageSection,
+    pub limits: CBCALimitsSection,
+    pub rate: CBCARateSection,
+    pub queue: CBCAQueueSection,
+    pub tls: CBCATlsSection,
+    pub shutdown: CBCAShutdownSection,
+    pub admin: CBCAAdminSection
+}
+
+impl Default for CBCAServerConfig {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_string(),
+            bind: CBCABindConfig::default(),
+            storage: CBCAStorageSection::default(),
+            limits: CBCALimitsSection::default(),
+            rate: CBCARateSection::default(),
+            queue: CBCAQueueSection::default(),
+            tls: CBCATlsSection::default(),
+            shutdown: CBCAShutdownSection::default(),
+            admin: CBCAAdminSection::default()
+        }
+    }
+}
+
+fn invalid_config(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, reason)
+}
+
+fn env_parse<T: FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().and_then(|v| v.parse::<T>().ok())
+}
+
+impl CBCAServerConfig {
+    // The defaults without a file.
+    pub fn load(path: Option<&Path>) -> Result<Self, std::io::Error> {
+        let path: &Path = match path {
+            Some(v) => v,
+            None => return Ok(Self::default())
+        };
+
+        let buf: String = std::fs::read_to_string(path)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+
+        toml::from_str(&buf).map_err(|e| invalid_config(format!("{}: {}", path.display(), e)))
+    }
+
+    // Every CBCA_* variable the server read before it had a config file.
+    pub fn apply_env(&mut self) {
+        if let Ok(v) = env::var("CBCA_LOG_LEVEL") {
+            self.log_level = v;
+        }
+
+        if let Some(v) = env::var_os("CBCA_UNIX_DIR") {
+            self.bind.unix_dir = Some(PathBuf::from(v));
+        }
+
+        if let Ok(v) = env::var("CBCA_UNIX_MODE") {
+            self.bind.unix_mode = v;
+        }
+
+        if let Some(v) = env_parse::<u16>("CBCA_GATEWAY_PORT") {
+            self.bind.gateway = Some(v);
+        }
+
+        if let Ok(v) = env::var("CBCA_STORAGE") {
+            self.storage.backend = v;
+        }
+
+        let limits: CBCALimits = self.limits.to_limits().with_env();
+        let max_connections: usize = env_parse("CBCA_MAX_CONNECTIONS").unwrap_or(self.limits.max_connections);
+        self.limits = CBCALimitsSection::from_limits(limits, max_connections);
+
+        // Budgets the file got wrong are left for `validate` to report.
+        if let Some(v) = self.rate.to_limits() {
+            self.rate = CBCARateSection::from_limits(v.with_env());
+        }
+
+        self.queue.capacity = env_parse("CBCA_QUEUE_CAPACITY").unwrap_or(self.queue.capacity);
+        self.queue.workers = env_parse("CBCA_QUEUE_WORKERS").unwrap_or(self.queue.workers);
+
+        if let (Some(c), Some(k)) = (env::var_os("CBCA_TLS_CERT"), env::var_os("CBCA_TLS_KEY")) {
+            self.tls.cert = Some(PathBuf::from(c));
+            self.tls.key = Some(PathBuf::from(k));
+            self.tls.client_ca = env::var_os("CBCA_TLS_CLIENT_CA").map(PathBuf::from);
+        }
+
+        self.shutdown.deadline_ms = env_parse("CBCA_SHUTDOWN_DEADLINE_MS").unwrap_or(self.shutdown.deadline_ms);
+
+        if let Ok(v) = env::var("CBCA_ADMIN_TOKEN") {
+            self.admin.token = Some(v);
+        }
+    }
+
+    pub fn apply_args(&mut self, args: &CBCAArgs) {
+        let bind: &mut CBCABindConfig = &mut self.bind;
+
+        if let Some(v) = &args.log_level { self.log_level = v.clone(); }
+        if let Some(v) = &args.host { bind.host = v.clone(); }
+        if let Some(v) = &args.unix_dir { bind.unix_dir = Some(v.clone()); }
+        if let Some(v) = args.message_port { bind.message = v; }
+        if let Some(v) = args.instance_port { bind.instance = v; }
+        if let Some(v) = args.offer_port { bind.offer = v; }
+        if let Some(v) = args.subscribe_port { bind.subscribe = v; }
+        if let Some(v) = args.query_port { bind.query = v; }
+        if let Some(v) = args.admin_port { bind.admin = v; }
+        if let Some(v) = args.gateway_port { bind.gateway = Some(v); }
+        if let Some(v) = &args.storage { self.storage.backend = v.clone(); }
+        if let Some(v) = &args.data_dir { self.storage.data_dir = v.clone(); }
+        if let Some(v) = args.max_connections { self.limits.max_connections = v; }
+        if let Some(v) = args.max_payload_size { self.limits.max_payload_size = v; }
+        if let Some(v) = args.idle_timeout_ms { self.limits.idle_timeout_ms = v; }
+        if let Some(v) = &args.tls_cert { self.tls.cert = Some(v.clone()); }
+        if let Some(v) = &args.tls_key { self.tls.key = Some(v.clone()); }
+        if let Some(v) = &args.tls_client_ca { self.tls.client_ca = Some(v.clone()); }
+        if let Some(v) = args.shutdown_deadline_ms { self.shutdown.deadline_ms = v; }
+    }
+
+    pub fn get_log_level(&self) -> Option<LevelFilter> {
+        LevelFilter::from_str(&self.log_level).ok()
+    }
+
+    pub fn get_unix_mode(&self) -> Option<u32> {
+        u32::from_str_radix(&self.bind.unix_mode, 8).ok().filter(|v| *v <= 0o777)
+    }
+
+    // Never falls back to another backend, memory would lose every instance on exit.
+    pub fn get_storage(&self) -> Result<CBCAStorageConfig, std::io::Error> {
+        CBCAStorageConfig::parse(&self.storage.backend, &self.storage.data_dir).ok_or_else(|| invalid_config(format!(
+            "storage.backend {:?} isn't json[:<dir>], memory or sqlite[:<file>].",
+            self.storage.backend
+        )))
+    }
+
+    // An empty token keeps the admin routine closed, as it always did.
+    pub fn get_admin_token(&self) -> Option<&str> {
+        self.admin.token.as_deref().filter(|v| !v.is_empty())
+    }
+
+    // Ports used over TCP, the routines only when they don't listen on Unix sockets.
+    fn ports(&self) -> Vec<(&'static str, u16)> {
+        let bind: &CBCABindConfig = &self.bind;
+        let mut ports: Vec<(&'static str, u16)> = Vec::new();
+
+        if bind.unix_dir.is_none() {
+            ports.extend([
+                ("message", bind.message),
+                ("instance", bind.instance),
+                ("offer", bind.offer),
+                ("subscribe", bind.subscribe),
+                ("query", bind.query)
+            ]);
+
+            if self.get_admin_token().is_some() {
+                ports.push(("admin", bind.admin));
+            }
+        }
+
+        if let Some(v) = bind.gateway {
+            ports.push(("gateway", v));
+        }
+
+        ports
+    }
+
+    // Every problem found, not only the first one.
+    pub fn validate(&self) -> Result<(), std::io::Error> {
+        let mut problems: Vec<String> = Vec::new();
+
+        if self.get_log_level().is_none() {
+            problems.push(format!("log_level {:?} isn't error, warn, info, debug or trace.", self.log_level));
+        }
+
+        if self.bind.host.trim().is_empty() {
+            problems.push("bind.host is empty.".to_string());
+        }
+
+        if self.get_unix_mode().is_none() {
+            problems.push(format!("bind.unix_mode {:?} isn't an octal mode.", self.bind.unix_mode));
+        }
+
+        let ports: Vec<(&'static str, u16)> = self.ports();
+        for (i, (name, port)) in ports.iter().enumerate() {
+            if *port == 0 {
+                problems.push(format!("bind.{} port is 0.", name));
+            }
+
+            if let Some((other, _)) = ports[..i].iter().find(|(_, v)| v == port) {
+                problems.push(format!("bind.{} and bind.{} both use port {}.", other, name, port));
+            }
+        }
+
+        if let Err(e) = self.get_storage() {
+            problems.push(e.to_string());
+        }
+
+        let limits: &CBCALimitsSection = &self.limits;
+        for (name, value) in [
+            ("idle_timeout_ms", limits.idle_timeout_ms),
+            ("header_timeout_ms", limits.header_timeout_ms),
+            ("body_timeout_ms", limits.body_timeout_ms),
+            ("write_timeout_ms", limits.write_timeout_ms),
+            ("heartbeat_ms", limits.heartbeat_ms)
+        ] {
+            if value == 0 {
+                problems.push(format!("limits.{} is 0.", name));
+            }
+        }
+
+        // Peers drop a connection silent for their idle timeout, heartbeats must come sooner.
+        if limits.heartbeat_ms >= limits.idle_timeout_ms {
+            problems.push("limits.heartbeat_ms must be under limits.idle_timeout_ms.".to_string());
+        }
+
+        if limits.max_payload_size == 0 || limits.max_payload_size > CBCA_MAX_FRAME_SIZE {
+            problems.push(format!("limits.max_payload_size must be between 1 and {}.", CBCA_MAX_FRAME_SIZE));
+        }
+
+        if limits.max_connections == 0 {
+            problems.push("limits.max_connections is 0.".to_string());
+        }
+
+        for (name, value) in [("message", &self.rate.message), ("offer", &self.rate.offer), ("instance", &self.rate.instance)] {
+            if CBCARateBudget::parse(value).is_none() {
+                problems.push(format!("rate.{} {:?} isn't burst/per_minute or off.", name, value));
+            }
+        }
+
+        if self.queue.capacity == 0 || self.queue.workers == 0 {
+            problems.push("queue.capacity and queue.workers must be positive.".to_string());
+        }
+
+        match (&self.tls.cert, &self.tls.key, &self.tls.client_ca) {
+            (Some(_), Some(_), _) => {
+                if let Some(Err(e)) = self.tls.to_tls().map(|v| v.build_acceptor()) {
+                    problems.push(format!("tls files unusable, {}.", e));
+                }
+            },
+            (None, None, None) => {},
+            _ => problems.push("tls.cert and tls.key go together, tls.client_ca needs them.".to_string())
+        }
+
+        if self.shutdown.deadline_ms == 0 {
+            problems.push("shutdown.deadline_ms is 0.".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_config(problems.join("\n")))
+        }
+    }
+
+    // The effective configuration as a TOML file, the admin token hidden.
+    pub fn display(&self) -> Result<String, std::io::Error> {
+        let mut shown: Self = self.clone();
+        if shown.admin.token.is_some() {
+            shown.admin.token = Some("<hidden>".to_string());
+        }
+
+        toml::to_string_pretty(&shown).map_err(std::io::Error::other)
+    }
+}
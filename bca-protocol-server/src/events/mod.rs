@@ -0,0 +1,24 @@
+use shared::event::CBCAEvent;
+use tokio::sync::broadcast;
+
+// Fans out instance events to every subscribed connection, whatever its transport.
+#[derive(Debug, Clone)]
+pub struct CBCAEventBus {
+    sender: broadcast::Sender<CBCAEvent>
+}
+
+impl CBCAEventBus {
+    pub fn spawn(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: CBCAEvent) {
+        // Nobody listening isn't an error.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CBCAEvent> {
+        self.sender.subscribe()
+    }
+}
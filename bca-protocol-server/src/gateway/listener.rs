@@ -0,0 +1,233 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration
+};
+
+use axum::serve::Listener;
+use shared::{limits::CBCALimits, tls::TlsAcceptor, transport::{CBCAIncoming, CBCAStream}};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    time::{Instant, Sleep}
+};
+
+use crate::{server::accept_peer, shutdown::CBCAShutdown};
+
+// Connections through their handshake, waiting for axum to take them.
+const CBCA_GATEWAY_BACKLOG: usize = 64;
+// Pause after a failed accept, the process may be out of file descriptors.
+const CBCA_ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+fn timed_out(what: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, what.to_string())
+}
+
+// A gateway connection, its permit goes back once axum drops it. It is dropped once no byte
+// went either way for idle_timeout, or a write waited on the peer for write_timeout: HTTP
+// keeps connections open between requests, sessions keep theirs busy with heartbeats.
+pub struct CBCAGatewayStream {
+    stream: CBCAStream,
+    limits: CBCALimits,
+    idle: Pin<Box<Sleep>>,
+    // Set while a write or a flush waits on the peer.
+    writing: Option<Pin<Box<Sleep>>>,
+    _permit: OwnedSemaphorePermit
+}
+
+impl CBCAGatewayStream {
+    fn spawn(
+        stream: CBCAStream,
+        limits: CBCALimits,
+        permit: OwnedSemaphorePermit
+    ) -> Self {
+        Self {
+            stream,
+            limits,
+            idle: Box::pin(tokio::time::sleep(limits.idle_timeout)),
+            writing: None,
+            _permit: permit
+        }
+    }
+
+    fn touch(&mut self) {
+        let deadline: Instant = Instant::now() + self.limits.idle_timeout;
+        self.idle.as_mut().reset(deadline);
+    }
+
+    // Answers a write that made progress, or fails it once it waited too long.
+    fn check_write<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<std::io::Result<T>>
+    ) -> Poll<std::io::Result<T>> {
+        if poll.is_ready() {
+            self.writing = None;
+            return poll;
+        }
+
+        let timeout: Duration = self.limits.write_timeout;
+        let writing: &mut Pin<Box<Sleep>> = self.writing.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match writing.as_mut().poll(cx) {
+            Poll::Ready(_) => Poll::Ready(Err(timed_out("peer not reading"))),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+impl AsyncRead for CBCAGatewayStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>
+    ) -> Poll<std::io::Result<()>> {
+        let filled: usize = buf.filled().len();
+
+        match Pin::new(&mut self.stream).poll_read(cx, buf) {
+            Poll::Ready(v) => {
+                if buf.filled().len() > filled {
+                    self.touch();
+                }
+                Poll::Ready(v)
+            },
+            Poll::Pending => match self.idle.as_mut().poll(cx) {
+                Poll::Ready(_) => Poll::Ready(Err(timed_out("idle connection"))),
+                Poll::Pending => Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for CBCAGatewayStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8]
+    ) -> Poll<std::io::Result<usize>> {
+        let poll: Poll<std::io::Result<usize>> = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll && n > 0 {
+            self.touch();
+        }
+
+        self.check_write(cx, poll)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>
+    ) -> Poll<std::io::Result<()>> {
+        let poll: Poll<std::io::Result<()>> = Pin::new(&mut self.stream).poll_flush(cx);
+        self.check_write(cx, poll)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// Who a gateway connection comes from, the name is the one of its client certificate when
+// mutual TLS is on.
+#[derive(Debug, Clone)]
+pub struct CBCAGatewayPeer {
+    pub addr: SocketAddr,
+    pub name: Option<String>
+}
+
+// Accepts gateway peers as the routines accept theirs: a permit of max_connections each,
+// then the TLS handshake of the server. Handshakes run in their own tasks, a peer stalling
+// one doesn't hold the others. Accepting stops at shutdown.
+pub struct CBCAGatewayListener {
+    ready: mpsc::Receiver<(CBCAGatewayStream, CBCAGatewayPeer)>,
+    local_addr: SocketAddr
+}
+
+impl CBCAGatewayListener {
+    pub fn spawn(
+        listener: TcpListener,
+        tls: Option<TlsAcceptor>,
+        limits: CBCALimits,
+        connections: Arc<Semaphore>,
+        shutdown: CBCAShutdown
+    ) -> Result<Self, std::io::Error> {
+        let local_addr: SocketAddr = listener.local_addr()?;
+        let (sender, ready) = mpsc::channel::<(CBCAGatewayStream, CBCAGatewayPeer)>(CBCA_GATEWAY_BACKLOG);
+
+        tokio::spawn(Self::run(listener, tls, limits, connections, shutdown, sender));
+
+        Ok(Self { ready, local_addr })
+    }
+
+    async fn next(
+        listener: &TcpListener,
+        connections: &Arc<Semaphore>
+    ) -> Result<(TcpStream, SocketAddr, OwnedSemaphorePermit), std::io::Error> {
+        let (socket, addr) = listener.accept().await?;
+        // Taken after accepting, as the routines do.
+        let permit: OwnedSemaphorePermit = Arc::clone(connections)
+            .acquire_owned()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        Ok((socket, addr, permit))
+    }
+
+    async fn run(
+        listener: TcpListener,
+        tls: Option<TlsAcceptor>,
+        limits: CBCALimits,
+        connections: Arc<Semaphore>,
+        shutdown: CBCAShutdown,
+        sender: mpsc::Sender<(CBCAGatewayStream, CBCAGatewayPeer)>
+    ) {
+        loop {
+            let accepted = tokio::select! {
+                biased;
+                _ = shutdown.wait() => break,
+                v = Self::next(&listener, &connections) => v
+            };
+
+            let (socket, addr, permit) = match accepted {
+                Ok(v) => v,
+                Err(e) => {
+                    log::warn!("[GATEWAY] accept failed, {}.", e);
+                    tokio::time::sleep(CBCA_ACCEPT_RETRY).await;
+                    continue;
+                }
+            };
+
+            let tls: Option<TlsAcceptor> = tls.clone();
+            let sender: mpsc::Sender<(CBCAGatewayStream, CBCAGatewayPeer)> = sender.clone();
+
+            tokio::spawn(async move {
+                if let Some(stream) = accept_peer(CBCAIncoming::Tcp(socket), tls.as_ref(), limits).await {
+                    let peer: CBCAGatewayPeer = CBCAGatewayPeer { addr, name: stream.get_peer_name() };
+                    let _ = sender.send((CBCAGatewayStream::spawn(stream, limits, permit), peer)).await;
+                }
+            });
+        }
+    }
+}
+
+impl Listener for CBCAGatewayListener {
+    type Io = CBCAGatewayStream;
+    type Addr = CBCAGatewayPeer;
+
+    // Never returns once accepting stopped, axum stops asking at shutdown anyway.
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.ready.recv().await {
+            Some(v) => v,
+            None => std::future::pending().await
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(CBCAGatewayPeer { addr: self.local_addr, name: None })
+    }
+}
@@ -0,0 +1,258 @@
+mod listener;
+mod rest;
+mod sse;
+
+use std::{collections::HashSet, net::IpAddr, sync::Arc, time::Instant};
+
+use axum::{
+    extract::{ws::{Message, WebSocket, WebSocketUpgrade}, ConnectInfo, DefaultBodyLimit, State},
+    http::StatusCode,
+    response::Response,
+    routing::get,
+    serve::ListenerExt,
+    Extension, Json, Router
+};
+use shared::{
+    communication::{CBCAErrorCode, CBCAErrorPayload},
+    event::CBCAEvent,
+    limits::CBCALimits,
+    request::{CBCARequest, CBCARequestFrame, CBCAResponse},
+    tls::TlsAcceptor
+};
+use tokio::sync::{broadcast::error::RecvError, Semaphore};
+
+use listener::{CBCAGatewayListener, CBCAGatewayPeer};
+
+use crate::{
+    queue::CBCAQueue,
+    ratelimit::CBCARateKind,
+    server::CBCARoutineAddr,
+    shutdown::CBCAShutdown,
+    stats::CBCAConnectionGuard
+};
+
+// HTTP side of the server, for peers that can't speak the CBCATcpPayload framing.
+// Browsers use the WebSocket on /ws, other tools the REST routes described by /openapi.json
+// and displays the Server-Sent Events on /instances/{id}/events.
+pub struct CBCAGateway {
+    addr: String,
+    queue: CBCAQueue
+}
+
+pub fn http_error(error: std::io::Error) -> (StatusCode, Json<CBCAErrorPayload>) {
+    http_payload(CBCAErrorPayload::from(&error))
+}
+
+pub fn http_payload(payload: CBCAErrorPayload) -> (StatusCode, Json<CBCAErrorPayload>) {
+    (
+        match payload.code {
+            CBCAErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            CBCAErrorCode::NotFound => StatusCode::NOT_FOUND,
+            CBCAErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            CBCAErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            CBCAErrorCode::Busy => StatusCode::SERVICE_UNAVAILABLE,
+            CBCAErrorCode::Closed => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR
+        },
+        Json(payload)
+    )
+}
+
+// Runs a typed request through the same queue the TCP routines use, under the same rate limits
+// and the same visibility of private instances.
+pub async fn dispatch(
+    queue: &CBCAQueue,
+    request: CBCARequest,
+    peer: &CBCAGatewayPeer
+) -> Result<serde_json::Value, CBCAErrorPayload> {
+    let ip: Option<IpAddr> = Some(peer.addr.ip());
+
+    match &request {
+        CBCARequest::Instance(_) => queue.admit(CBCARateKind::Instance, ip, None)?,
+        CBCARequest::Message(v) => queue.admit(CBCARateKind::Message, ip, Some(&v.author))?,
+        CBCARequest::Offer(v) => queue.admit(CBCARateKind::Offer, ip, Some(&v.author))?,
+        _ => {}
+    }
+
+    let result: Result<serde_json::Value, std::io::Error> = match request {
+        CBCARequest::Instance(v) => queue.handle_add_instance(v)
+            .await
+            .map(serde_json::Value::String),
+        CBCARequest::Message(v) => match queue.handle_add_message(v).await {
+            Ok(receipt) => serde_json::to_value(receipt).map_err(std::io::Error::from),
+            Err(e) => Err(e)
+        },
+        CBCARequest::Offer(v) => match queue.handle_add_offer(v).await {
+            Ok(receipt) => serde_json::to_value(receipt).map_err(std::io::Error::from),
+            Err(e) => Err(e)
+        },
+        CBCARequest::Query(v) => queue.handle_query(v, peer.name.clone()).await,
+        CBCARequest::Subscribe(_) | CBCARequest::Unsubscribe(_) => Err(
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "subscriptions need a long-lived connection.")
+        )
+    };
+
+    result.map_err(|e| CBCAErrorPayload::from(&e))
+}
+
+impl CBCAGateway {
+    pub fn spawn(
+        addr: CBCARoutineAddr,
+        queue: CBCAQueue
+    ) -> Self {
+        Self {
+            addr: addr.get_full_addr(),
+            queue
+        }
+    }
+
+    // Bodies and WebSocket messages are held to the payload size of the frames.
+    pub fn router(
+        &self,
+        limits: CBCALimits
+    ) -> Router {
+        Router::new()
+            .route("/ws", get(ws_upgrade))
+            .merge(rest::router())
+            .merge(sse::router())
+            .layer(DefaultBodyLimit::max(limits.max_payload_size))
+            .layer(Extension(limits))
+            .with_state(self.queue.clone())
+    }
+
+    pub async fn routine(
+        &self,
+        tls: Option<TlsAcceptor>,
+        limits: CBCALimits,
+        connections: Arc<Semaphore>
+    ) -> Result<(), std::io::Error> {
+        let shutdown: CBCAShutdown = self.queue.get_shutdown().clone();
+        let listener: tokio::net::TcpListener = tokio::net::TcpListener::bind(&self.addr).await.map_err(|e| std::io::Error::new(
+            e.kind(),
+            format!("[GATEWAY] can't listen on {}, {}", self.addr, e)
+        ))?;
+        let listener: CBCAGatewayListener = CBCAGatewayListener::spawn(listener, tls, limits, connections, shutdown.clone())?;
+        log::info!("[GATEWAY] on {}.", self.addr);
+
+        // Peer addresses are kept for the rate limits and peer names for private instances,
+        // tapping the listener gives them to ConnectInfo. At shutdown, requests under way are answered and sessions end before
+        // this returns.
+        let router: Router = self.router(limits);
+        axum::serve(listener.tap_io(|_| {}), router.into_make_service_with_connect_info::<CBCAGatewayPeer>())
+            .with_graceful_shutdown(async move { shutdown.wait().await })
+            .await?;
+
+        log::info!("[GATEWAY] closed.");
+        Ok(())
+    }
+}
+
+async fn ws_upgrade(
+    State(queue): State<CBCAQueue>,
+    ConnectInfo(peer): ConnectInfo<CBCAGatewayPeer>,
+    Extension(limits): Extension<CBCALimits>,
+    ws: WebSocketUpgrade
+) -> Response {
+    ws.max_message_size(limits.max_payload_size)
+        .max_frame_size(limits.max_payload_size)
+        .on_upgrade(move |socket| ws_session(queue, socket, peer, limits))
+}
+
+async fn ws_reply(
+    socket: &mut WebSocket,
+    response: &CBCAResponse
+) -> Result<(), axum::Error> {
+    let serialized: String = serde_json::to_string(response).unwrap_or_default();
+    socket.send(Message::Text(serialized.into())).await
+}
+
+// One JSON text frame per request, events of subscribed instances are pushed in between.
+// A ping goes out every heartbeat_interval, a peer silent for idle_timeout is closed.
+async fn ws_session(
+    queue: CBCAQueue,
+    mut socket: WebSocket,
+    peer: CBCAGatewayPeer,
+    limits: CBCALimits
+) {
+    let _connection: CBCAConnectionGuard = queue.get_stats().connection();
+    let mut events = queue.get_events().subscribe();
+    let mut subscriptions: HashSet<String> = HashSet::new();
+    let mut heartbeat: tokio::time::Interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + limits.heartbeat_interval,
+        limits.heartbeat_interval
+    );
+    let mut seen: Instant = Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = queue.get_shutdown().wait() => {
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            },
+            _ = heartbeat.tick() => {
+                if seen.elapsed() >= limits.idle_timeout {
+                    log::debug!("[GATEWAY] {} silent for {:?}, closed.", peer.addr, limits.idle_timeout);
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+
+                if socket.send(Message::Ping(Default::default())).await.is_err() {
+                    break;
+                }
+            },
+            incoming = socket.recv() => {
+                seen = Instant::now();
+                let text = match incoming {
+                    Some(Ok(Message::Text(v))) => v,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue
+                };
+
+                let response: CBCAResponse = match serde_json::from_str::<CBCARequestFrame>(&text) {
+                    Ok(CBCARequestFrame { id, request: CBCARequest::Subscribe(v) }) => {
+                        match queue.handle_summary(&v.instance_id, peer.name.as_deref()).await {
+                            Ok(_) => {
+                                subscriptions.insert(v.instance_id);
+                                CBCAResponse::Ok { id, data: serde_json::Value::Bool(true) }
+                            },
+                            Err(e) => CBCAResponse::Error { id, error: CBCAErrorPayload::from(&e) }
+                        }
+                    },
+                    Ok(CBCARequestFrame { id, request: CBCARequest::Unsubscribe(v) }) => {
+                        CBCAResponse::Ok { id, data: serde_json::Value::Bool(subscriptions.remove(&v.instance_id)) }
+                    },
+                    Ok(CBCARequestFrame { id, request }) => match dispatch(&queue, request, &peer).await {
+                        Ok(data) => CBCAResponse::Ok { id, data },
+                        Err(error) => CBCAResponse::Error { id, error }
+                    },
+                    Err(e) => CBCAResponse::Error {
+                        id: None,
+                        error: CBCAErrorPayload::spawn(CBCAErrorCode::BadRequest, e.to_string())
+                    }
+                };
+
+                if ws_reply(&mut socket, &response).await.is_err() {
+                    break;
+                }
+            },
+            event = events.recv() => {
+                let event: CBCAEvent = match event {
+                    Ok(v) => v,
+                    Err(RecvError::Lagged(n)) => {
+                        log::warn!("[GATEWAY] subscriber lagged, {} events skipped.", n);
+                        continue;
+                    },
+                    Err(RecvError::Closed) => break
+                };
+
+                if !subscriptions.contains(event.get_instance_id()) {
+                    continue;
+                }
+
+                if ws_reply(&mut socket, &CBCAResponse::Event { event }).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router
+};
+use serde::Deserialize;
+use shared::{
+    communication::CBCAErrorPayload,
+    fchain::{CBCAChainKind, CBCAConfig},
+    payload::{CBCAChainRange, CBCAInstanceFilter, CBCAInstanceSort, IPayload, MPayload, OPayload},
+    request::{
+        CBCAAuctionStatus, CBCAChainPage, CBCAInstanceList, CBCAReceipt, CBCASettlement, CBCA_MAX_PAGE_SIZE
+    }
+};
+use utoipa::{IntoParams, OpenApi};
+
+use crate::{
+    gateway::{http_error, http_payload, listener::CBCAGatewayPeer},
+    queue::CBCAQueue,
+    ratelimit::CBCARateKind
+};
+
+type CBCAHttpResult<T> = Result<(StatusCode, Json<T>), (StatusCode, Json<CBCAErrorPayload>)>;
+
+// The header wins over the `idempotency_key` field of the body.
+fn idempotency_key(headers: &HeaderMap) -> Option<String> {
+    headers.get("idempotency-key")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+fn receipt_status(receipt: &CBCAReceipt) -> StatusCode {
+    if receipt.duplicate {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct CBCAPageQuery {
+    // Index of the first block.
+    #[serde(default)]
+    from: usize,
+    // Hash of the block to start after, wins over `from`.
+    after: Option<String>,
+    // Capped to CBCA_MAX_PAGE_SIZE.
+    limit: Option<usize>
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct CBCAListQuery {
+    // Words the name or the description must hold.
+    q: Option<String>,
+    status: Option<CBCAAuctionStatus>,
+    currency: Option<String>,
+    // Unix timestamp.
+    closes_before: Option<i64>,
+    min_bids: Option<usize>,
+    sort: Option<CBCAInstanceSort>,
+    #[serde(default)]
+    from: usize,
+    // Capped to CBCA_MAX_PAGE_SIZE.
+    limit: Option<usize>
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "BCA Protocol", description = "REST access to the auction instances."),
+    paths(create_instance, list_instances, read_chain, post_message, post_offer, read_settlement)
+)]
+pub struct CBCAApiDoc;
+
+pub fn router() -> Router<CBCAQueue> {
+    Router::new()
+        .route("/instances", post(create_instance).get(list_instances))
+        .route("/instances/{id}/chains/{chain}", get(read_chain))
+        .route("/instances/{id}/messages", post(post_message))
+        .route("/instances/{id}/offers", post(post_offer))
+        .route("/instances/{id}/settlement", get(read_settlement))
+        .route("/openapi.json", get(|| async { Json(CBCAApiDoc::openapi()) }))
+}
+
+#[utoipa::path(
+    post, path = "/instances",
+    request_body = CBCAConfig,
+    responses(
+        (status = 201, body = IPayload),
+        (status = 400, body = CBCAErrorPayload),
+        (status = 429, body = CBCAErrorPayload),
+        (status = 503, body = CBCAErrorPayload)
+    )
+)]
+async fn create_instance(
+    State(queue): State<CBCAQueue>,
+    ConnectInfo(peer): ConnectInfo<CBCAGatewayPeer>,
+    Json(config): Json<CBCAConfig>
+) -> CBCAHttpResult<IPayload> {
+    queue.admit(CBCARateKind::Instance, Some(peer.addr.ip()), None).map_err(http_payload)?;
+
+    let payload: IPayload = IPayload {
+        instance_id: String::new(),
+        config
+    };
+
+    let identifier: String = queue.handle_add_instance(payload.clone()).await.map_err(http_error)?;
+
+    Ok((StatusCode::CREATED, Json(IPayload { instance_id: identifier, ..payload })))
+}
+
+#[utoipa::path(
+    get, path = "/instances",
+    params(CBCAListQuery),
+    responses((status = 200, body = CBCAInstanceList))
+)]
+// Private instances are listed to the author named by the client certificate, if any.
+async fn list_instances(
+    State(queue): State<CBCAQueue>,
+    ConnectInfo(peer): ConnectInfo<CBCAGatewayPeer>,
+    Query(query): Query<CBCAListQuery>
+) -> CBCAHttpResult<CBCAInstanceList> {
+    let filter: CBCAInstanceFilter = CBCAInstanceFilter {
+        status: query.status,
+        currency: query.currency,
+        closes_before: query.closes_before,
+        min_bids: query.min_bids,
+        requester: peer.name,
+        sort: query.sort.unwrap_or_default(),
+        from: query.from,
+        limit: query.limit
+    };
+
+    Ok((StatusCode::OK, Json(queue.handle_find_instances(query.q.as_deref(), &filter))))
+}
+
+#[utoipa::path(
+    get, path = "/instances/{id}/chains/{chain}",
+    params(
+        ("id" = String, Path, description = "Instance identifier."),
+        ("chain" = CBCAChainKind, Path, description = "Chain to read."),
+        CBCAPageQuery
+    ),
+    responses(
+        (status = 200, body = CBCAChainPage),
+        (status = 404, body = CBCAErrorPayload)
+    )
+)]
+async fn read_chain(
+    State(queue): State<CBCAQueue>,
+    ConnectInfo(peer): ConnectInfo<CBCAGatewayPeer>,
+    Path((id, chain)): Path<(String, CBCAChainKind)>,
+    Query(query): Query<CBCAPageQuery>
+) -> CBCAHttpResult<CBCAChainPage> {
+    let limit: usize = query.limit.unwrap_or(CBCA_MAX_PAGE_SIZE);
+    let range: CBCAChainRange = match query.after {
+        Some(hash) => CBCAChainRange::After { hash, limit },
+        None => CBCAChainRange::Index { from: query.from, limit }
+    };
+    let page: CBCAChainPage = queue.handle_read_chain(&id, chain, range, peer.name.as_deref()).await.map_err(http_error)?;
+    Ok((StatusCode::OK, Json(page)))
+}
+
+#[utoipa::path(
+    post, path = "/instances/{id}/messages",
+    params(
+        ("id" = String, Path, description = "Instance identifier."),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key aren't appended twice.")
+    ),
+    request_body = MPayload,
+    responses(
+        (status = 201, body = CBCAReceipt),
+        (status = 200, description = "Idempotency key replayed, nothing appended.", body = CBCAReceipt),
+        (status = 400, body = CBCAErrorPayload),
+        (status = 404, body = CBCAErrorPayload),
+        (status = 429, body = CBCAErrorPayload),
+        (status = 503, body = CBCAErrorPayload)
+    )
+)]
+async fn post_message(
+    State(queue): State<CBCAQueue>,
+    ConnectInfo(peer): ConnectInfo<CBCAGatewayPeer>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<MPayload>
+) -> CBCAHttpResult<CBCAReceipt> {
+    queue.admit(CBCARateKind::Message, Some(peer.addr.ip()), Some(&payload.author)).map_err(http_payload)?;
+
+    let idempotency_key: Option<String> = idempotency_key(&headers).or(payload.idempotency_key.clone());
+    let receipt: CBCAReceipt = queue.handle_add_message(MPayload { instance_id: id, idempotency_key, ..payload })
+        .await
+        .map_err(http_error)?;
+    Ok((receipt_status(&receipt), Json(receipt)))
+}
+
+#[utoipa::path(
+    post, path = "/instances/{id}/offers",
+    params(
+        ("id" = String, Path, description = "Instance identifier."),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retries with the same key aren't appended twice.")
+    ),
+    request_body = OPayload,
+    responses(
+        (status = 201, body = CBCAReceipt),
+        (status = 200, description = "Idempotency key replayed, nothing appended.", body = CBCAReceipt),
+        (status = 400, body = CBCAErrorPayload),
+        (status = 404, body = CBCAErrorPayload),
+        (status = 409, description = "The auction is closed.", body = CBCAErrorPayload),
+        (status = 429, body = CBCAErrorPayload),
+        (status = 503, body = CBCAErrorPayload)
+    )
+)]
+async fn post_offer(
+    State(queue): State<CBCAQueue>,
+    ConnectInfo(peer): ConnectInfo<CBCAGatewayPeer>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<OPayload>
+) -> CBCAHttpResult<CBCAReceipt> {
+    queue.admit(CBCARateKind::Offer, Some(peer.addr.ip()), Some(&payload.author)).map_err(http_payload)?;
+
+    let idempotency_key: Option<String> = idempotency_key(&headers).or(payload.idempotency_key.clone());
+    let receipt: CBCAReceipt = queue.handle_add_offer(OPayload { instance_id: id, idempotency_key, ..payload })
+        .await
+        .map_err(http_error)?;
+    Ok((receipt_status(&receipt), Json(receipt)))
+}
+
+#[utoipa::path(
+    get, path = "/instances/{id}/settlement",
+    params(("id" = String, Path, description = "Instance identifier.")),
+    responses(
+        (status = 200, body = CBCASettlement),
+        (status = 404, body = CBCAErrorPayload)
+    )
+)]
+async fn read_settlement(
+    State(queue): State<CBCAQueue>,
+    ConnectInfo(peer): ConnectInfo<CBCAGatewayPeer>,
+    Path(id): Path<String>
+) -> CBCAHttpResult<CBCASettlement> {
+    let settlement: CBCASettlement = queue.handle_settlement(&id, peer.name.as_deref()).await.map_err(http_error)?;
+    Ok((StatusCode::OK, Json(settlement)))
+}
@@ -0,0 +1,99 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+    Extension, Json, Router
+};
+use chrono::Utc;
+use futures_util::{stream, Stream};
+use shared::{communication::CBCAErrorPayload, event::CBCAEvent, limits::CBCALimits, request::CBCAInstanceSummary};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    gateway::{http_error, listener::CBCAGatewayPeer},
+    queue::CBCAQueue,
+    shutdown::CBCAShutdown
+};
+
+pub fn router() -> Router<CBCAQueue> {
+    Router::new()
+        .route("/instances/{id}/events", get(instance_events))
+}
+
+struct CBCAFeed {
+    instance_id: String,
+    closes_at: Option<i64>,
+    events: broadcast::Receiver<CBCAEvent>,
+    ticker: tokio::time::Interval,
+    first: Option<CBCAEvent>,
+    shutdown: CBCAShutdown
+}
+
+fn to_sse(event: &CBCAEvent) -> Event {
+    Event::default()
+        .event(event.get_name())
+        .data(serde_json::to_string(event).unwrap_or_default())
+}
+
+impl CBCAFeed {
+    async fn next(&mut self) -> Option<CBCAEvent> {
+        if let Some(v) = self.first.take() {
+            return Some(v);
+        }
+
+        loop {
+            tokio::select! {
+                _ = self.shutdown.wait() => return None,
+                event = self.events.recv() => match event {
+                    Ok(v) if v.get_instance_id() == self.instance_id => return Some(v),
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None
+                },
+                _ = self.ticker.tick() => {
+                    let remaining: Option<i64> = self.closes_at.map(|v| v - Utc::now().timestamp());
+
+                    if let Some(v) = remaining && v > 0 {
+                        return Some(CBCAEvent::Countdown {
+                            instance_id: self.instance_id.clone(),
+                            remaining: v
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Read-only feed for displays: current status first, then appended blocks,
+// status changes and a countdown tick every second until the auction closes or the server
+// shuts down. A comment goes out every heartbeat_interval so the connection never looks idle.
+async fn instance_events(
+    State(queue): State<CBCAQueue>,
+    ConnectInfo(peer): ConnectInfo<CBCAGatewayPeer>,
+    Extension(limits): Extension<CBCALimits>,
+    Path(id): Path<String>
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, Json<CBCAErrorPayload>)> {
+    let events: broadcast::Receiver<CBCAEvent> = queue.get_events().subscribe();
+    let summary: CBCAInstanceSummary = queue.handle_summary(&id, peer.name.as_deref())
+        .await
+        .map_err(http_error)?;
+
+    let feed: CBCAFeed = CBCAFeed {
+        first: Some(CBCAEvent::StatusChanged { instance_id: id.clone(), status: summary.status }),
+        instance_id: id,
+        closes_at: summary.closes_at,
+        events,
+        ticker: tokio::time::interval(Duration::from_secs(1)),
+        shutdown: queue.get_shutdown().clone()
+    };
+
+    let stream = stream::unfold(feed, |mut feed| async move {
+        let event: CBCAEvent = feed.next().await?;
+        Some((Ok(to_sse(&event)), feed))
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(limits.heartbeat_interval)))
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant}
+};
+
+use shared::{block::CBCABlock, fchain::CBCAChainKind};
+
+pub const MAX_KEY_LEN: usize = 128;
+pub const DEFAULT_WINDOW_CAPACITY: usize = 1024;
+pub const DEFAULT_WINDOW_TTL: Duration = Duration::from_secs(600);
+// How often windows left empty are dropped.
+const CBCA_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// One window per chain of an instance, a key used for an offer says nothing about messages.
+type CBCAWindowKey = (String, CBCAChainKind);
+
+pub fn check_key(key: Option<&str>) -> Result<(), std::io::Error> {
+    match key {
+        Some(v) if v.is_empty() || v.len() > MAX_KEY_LEN => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("idempotency key must be between 1 and {} bytes.", MAX_KEY_LEN)
+        )),
+        _ => Ok(())
+    }
+}
+
+// Keys seen recently in one chain, oldest first.
+#[derive(Debug, Default)]
+struct CBCADedupeWindow {
+    order: VecDeque<(String, Instant)>,
+    blocks: HashMap<String, CBCABlock>
+}
+
+impl CBCADedupeWindow {
+    fn evict(&mut self, capacity: usize, ttl: Duration) {
+        while let Some((key, seen)) = self.order.front() {
+            if self.order.len() <= capacity && seen.elapsed() < ttl {
+                break;
+            }
+
+            self.blocks.remove(key);
+            self.order.pop_front();
+        }
+    }
+
+    fn record(&mut self, key: String, block: CBCABlock) {
+        self.order.push_back((key.clone(), Instant::now()));
+        self.blocks.insert(key, block);
+    }
+}
+
+#[derive(Debug)]
+struct CBCAWindows {
+    windows: HashMap<CBCAWindowKey, CBCADedupeWindow>,
+    swept: Instant
+}
+
+// Remembers which block each idempotency key produced, so a retried request gets the original
+// block back instead of appending a second one. The actor of an instance is the only one
+// asking about its chains, it looks a key up and records it between two appends.
+// Windows only live in memory: a key is only recognised by the process that appended it,
+// a retry reaching the server after a restart is appended again.
+#[derive(Debug, Clone)]
+pub struct CBCAIdempotencyCache {
+    windows: Arc<Mutex<CBCAWindows>>,
+    capacity: usize,
+    ttl: Duration
+}
+
+impl CBCAIdempotencyCache {
+    pub fn spawn(
+        capacity: usize,
+        ttl: Duration
+    ) -> Self {
+        Self {
+            windows: Arc::new(Mutex::new(CBCAWindows {
+                windows: HashMap::new(),
+                swept: Instant::now()
+            })),
+            capacity,
+            ttl
+        }
+    }
+
+    fn windows(&self) -> MutexGuard<'_, CBCAWindows> {
+        match self.windows.lock() {
+            Ok(v) => v,
+            Err(v) => v.into_inner()
+        }
+    }
+
+    // The block `key` appended to this chain, while it is still in the window.
+    pub fn lookup(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        key: &str
+    ) -> Option<CBCABlock> {
+        let mut lock = self.windows();
+        let window: &mut CBCADedupeWindow = lock.windows.get_mut(&(instance_id.to_string(), kind))?;
+        window.evict(self.capacity, self.ttl);
+
+        window.blocks.get(key).cloned()
+    }
+
+    pub fn record(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        key: String,
+        block: CBCABlock
+    ) {
+        let mut lock = self.windows();
+
+        if lock.swept.elapsed() >= CBCA_SWEEP_INTERVAL {
+            self.sweep(&mut lock);
+        }
+
+        let window: &mut CBCADedupeWindow = lock.windows.entry((instance_id.to_string(), kind)).or_default();
+        window.evict(self.capacity, self.ttl);
+        window.record(key, block);
+    }
+
+    // Drops the windows whose keys all expired.
+    fn sweep(&self, lock: &mut CBCAWindows) {
+        lock.swept = Instant::now();
+
+        lock.windows.retain(|_, v| {
+            v.evict(self.capacity, self.ttl);
+            !v.order.is_empty()
+        });
+    }
+
+    // Drops the window of a chain no longer appended to, e.g. the offers of a closed auction.
+    pub fn forget(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind
+    ) {
+        self.windows().windows.remove(&(instance_id.to_string(), kind));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(content: &str) -> CBCABlock {
+        let mut block: CBCABlock = CBCABlock::block_creator_message(content.to_string(), "a".to_string(), "i".to_string());
+        block.hash_block().unwrap();
+        block
+    }
+
+    #[test]
+    fn replayed_key_gets_its_block_back() {
+        let cache: CBCAIdempotencyCache = CBCAIdempotencyCache::spawn(16, DEFAULT_WINDOW_TTL);
+        assert!(cache.lookup("i", CBCAChainKind::Offers, "k").is_none());
+
+        let first: CBCABlock = block("first");
+        cache.record("i", CBCAChainKind::Offers, "k".to_string(), first.clone());
+        assert_eq!(cache.lookup("i", CBCAChainKind::Offers, "k").unwrap().get_hash(), first.get_hash());
+        assert!(cache.lookup("i", CBCAChainKind::Offers, "other").is_none());
+    }
+
+    #[test]
+    fn keys_belong_to_one_chain_of_one_instance() {
+        let cache: CBCAIdempotencyCache = CBCAIdempotencyCache::spawn(16, DEFAULT_WINDOW_TTL);
+        cache.record("i", CBCAChainKind::Offers, "k".to_string(), block("offer"));
+
+        assert!(cache.lookup("i", CBCAChainKind::Messages, "k").is_none());
+        assert!(cache.lookup("j", CBCAChainKind::Offers, "k").is_none());
+    }
+
+    #[test]
+    fn keys_expire_and_overflow() {
+        let cache: CBCAIdempotencyCache = CBCAIdempotencyCache::spawn(2, Duration::from_millis(50));
+        for key in ["a", "b", "c"] {
+            cache.record("i", CBCAChainKind::Offers, key.to_string(), block(key));
+        }
+
+        // The oldest key made room for the third one.
+        assert!(cache.lookup("i", CBCAChainKind::Offers, "a").is_none());
+        assert!(cache.lookup("i", CBCAChainKind::Offers, "c").is_some());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(cache.lookup("i", CBCAChainKind::Offers, "c").is_none());
+
+        // Windows left empty are dropped at the next sweep.
+        cache.sweep(&mut cache.windows());
+        assert!(cache.windows().windows.is_empty());
+    }
+
+    #[test]
+    fn closed_chain_forgets_its_keys() {
+        let cache: CBCAIdempotencyCache = CBCAIdempotencyCache::spawn(16, DEFAULT_WINDOW_TTL);
+        cache.record("i", CBCAChainKind::Offers, "k".to_string(), block("offer"));
+        cache.record("i", CBCAChainKind::Messages, "k".to_string(), block("message"));
+
+        cache.forget("i", CBCAChainKind::Offers);
+        assert!(cache.lookup("i", CBCAChainKind::Offers, "k").is_none());
+        assert!(cache.lookup("i", CBCAChainKind::Messages, "k").is_some());
+    }
+
+    #[test]
+    fn keys_are_bounded() {
+        assert!(check_key(None).is_ok());
+        assert!(check_key(Some("k")).is_ok());
+        assert!(check_key(Some("")).is_err());
+        assert!(check_key(Some(&"k".repeat(MAX_KEY_LEN + 1))).is_err());
+    }
+}
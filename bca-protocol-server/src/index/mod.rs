@@ -0,0 +1,153 @@
+use std::{cmp::Reverse, collections::HashMap, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}};
+
+use chrono::Utc;
+use shared::{
+    fchain::CBCAConfig,
+    payload::{CBCAInstanceFilter, CBCAInstanceSort},
+    request::{CBCAAuctionStatus, CBCAInstanceList, CBCAInstanceListing, CBCA_MAX_PAGE_SIZE}
+};
+
+// Enough of an instance to list it and to know who may find it.
+#[derive(Debug, Clone)]
+struct CBCAIndexEntry {
+    config: CBCAConfig,
+    closes_at: Option<i64>,
+    bids: usize
+}
+
+impl CBCAIndexEntry {
+    // Same rule as the stored state, instances without an end are open.
+    fn status(&self, now: i64) -> CBCAAuctionStatus {
+        match self.closes_at {
+            Some(v) if now >= v => CBCAAuctionStatus::Closed,
+            _ => CBCAAuctionStatus::Open
+        }
+    }
+
+    fn matches(
+        &self,
+        words: &[String],
+        filter: &CBCAInstanceFilter,
+        now: i64
+    ) -> bool {
+        let name: String = self.config.get_name().to_lowercase();
+        let description: String = self.config.get_description().to_lowercase();
+
+        self.config.is_visible_to(filter.requester.as_deref())
+            && words.iter().all(|v| name.contains(v.as_str()) || description.contains(v.as_str()))
+            && filter.status.is_none_or(|v| v == self.status(now))
+            && filter.currency.as_deref().is_none_or(|v| v.eq_ignore_ascii_case(self.config.get_currency()))
+            && filter.closes_before.is_none_or(|v| self.closes_at.is_some_and(|c| c < v))
+            && filter.min_bids.is_none_or(|v| self.bids >= v)
+    }
+
+    fn listing(
+        &self,
+        instance_id: &str,
+        now: i64
+    ) -> CBCAInstanceListing {
+        CBCAInstanceListing {
+            instance_id: instance_id.to_string(),
+            name: self.config.get_name().to_string(),
+            description: self.config.get_description().to_string(),
+            currency: self.config.get_currency().to_string(),
+            status: self.status(now),
+            closes_at: self.closes_at,
+            private: self.config.is_private(),
+            bids: self.bids
+        }
+    }
+}
+
+// Every instance of the storage, filled at startup and kept up to date by the workers, so
+// listing and searching never read the storage.
+#[derive(Debug, Clone, Default)]
+pub struct CBCAInstanceIndex {
+    entries: Arc<RwLock<HashMap<String, CBCAIndexEntry>>>
+}
+
+impl CBCAInstanceIndex {
+    pub fn spawn() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> RwLockReadGuard<'_, HashMap<String, CBCAIndexEntry>> {
+        match self.entries.read() {
+            Ok(v) => v,
+            Err(v) => v.into_inner()
+        }
+    }
+
+    fn entries_mut(&self) -> RwLockWriteGuard<'_, HashMap<String, CBCAIndexEntry>> {
+        match self.entries.write() {
+            Ok(v) => v,
+            Err(v) => v.into_inner()
+        }
+    }
+
+    pub fn get_len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn insert(
+        &self,
+        instance_id: String,
+        config: CBCAConfig,
+        closes_at: Option<i64>,
+        bids: usize
+    ) {
+        self.entries_mut().insert(instance_id, CBCAIndexEntry { config, closes_at, bids });
+    }
+
+    // Instances the index doesn't hold are left for the storage to report.
+    pub fn is_visible_to(
+        &self,
+        instance_id: &str,
+        requester: Option<&str>
+    ) -> bool {
+        self.entries().get(instance_id).is_none_or(|v| v.config.is_visible_to(requester))
+    }
+
+    pub fn record_bid(&self, instance_id: &str) {
+        if let Some(v) = self.entries_mut().get_mut(instance_id) {
+            v.bids += 1;
+        }
+    }
+
+    // Instances visible to the requester matching `text`, if any, and the filter, sorted then paged.
+    pub fn query(
+        &self,
+        text: Option<&str>,
+        filter: &CBCAInstanceFilter
+    ) -> CBCAInstanceList {
+        let now: i64 = Utc::now().timestamp();
+        let words: Vec<String> = text.unwrap_or_default()
+            .split_whitespace()
+            .map(|v| v.to_lowercase())
+            .collect();
+
+        let entries = self.entries();
+        let mut found: Vec<(&String, &CBCAIndexEntry)> = entries.iter()
+            .filter(|(_, v)| v.matches(&words, filter, now))
+            .collect();
+
+        // The identifier breaks ties, so pages don't overlap.
+        match filter.sort {
+            CBCAInstanceSort::ClosesAt => found.sort_by_key(|(id, v)| (v.closes_at.is_none(), v.closes_at, *id)),
+            CBCAInstanceSort::Bids => found.sort_by_key(|(id, v)| (Reverse(v.bids), *id)),
+            CBCAInstanceSort::Name => found.sort_by_cached_key(|(id, v)| (v.config.get_name().to_lowercase(), *id))
+        }
+
+        let limit: usize = filter.limit.unwrap_or(CBCA_MAX_PAGE_SIZE).min(CBCA_MAX_PAGE_SIZE);
+
+        CBCAInstanceList {
+            total: found.len(),
+            from: filter.from,
+            instances: found.iter()
+                .skip(filter.from)
+                .take(limit)
+                .map(|(id, v)| v.listing(id, now))
+                .collect()
+        }
+    }
+}
@@ -1,15 +1,52 @@
+use chrono::Utc;
 use shared::{
-    block::{CBCABlock}, 
-    fchain::{CBCAChain, CBCAConfig}
+    block::{CBCABlock, CBCABlockType}, 
+    fchain::{CBCAChain, CBCAChainKind, CBCAConfig},
+    request::{CBCAAuctionStatus, CBCAChainPage, CBCASettlement}
 };
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+// When the auction runs, `duration` of the config is counted in seconds from creation.
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub struct CBCAInstanceState {
+    pub opened_at: i64,
+    pub closes_at: i64
+}
+
+impl CBCAInstanceState {
+    pub fn spawn(duration: u32) -> Self {
+        let now: i64 = Utc::now().timestamp();
+
+        Self {
+            opened_at: now,
+            closes_at: now + duration as i64
+        }
+    }
+
+    pub fn status(&self) -> CBCAAuctionStatus {
+        if Utc::now().timestamp() >= self.closes_at {
+            CBCAAuctionStatus::Closed
+        } else {
+            CBCAAuctionStatus::Open
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct CBCAInstance {
     pub identifier: String,
     pub offers_chain: CBCAChain,
     pub messages_chain: CBCAChain,
     pub config: CBCAConfig,
-    pub started: bool
+    pub started: bool,
+    pub state: Option<CBCAInstanceState>
+}
+
+// Instances without a recorded state predate it and are considered open.
+pub fn status_of(state: &Option<CBCAInstanceState>) -> CBCAAuctionStatus {
+    match state {
+        Some(v) => v.status(),
+        None => CBCAAuctionStatus::Open
+    }
 }
 
 impl CBCAInstance {
@@ -21,11 +58,67 @@ impl CBCAInstance {
             identifier: identifier.to_string(),
             offers_chain: CBCAChain::spawn(identifier.to_string()),
             messages_chain: CBCAChain::spawn(identifier.to_string()),
+            state: Some(CBCAInstanceState::spawn(config.get_duration())),
             config,
             started: false
         }
     }
 
+    pub fn get_chain(&self, kind: CBCAChainKind) -> &CBCAChain {
+        match kind {
+            CBCAChainKind::Offers => &self.offers_chain,
+            CBCAChainKind::Messages => &self.messages_chain
+        }
+    }
+
+    pub fn page(
+        &self,
+        kind: CBCAChainKind,
+        from: usize,
+        limit: usize
+    ) -> CBCAChainPage {
+        let chain: &CBCAChain = self.get_chain(kind);
+
+        CBCAChainPage {
+            instance_id: self.identifier.clone(),
+            chain: kind,
+            head_hash: chain.get_last_hash(),
+            total: chain.len(),
+            from,
+            blocks: chain.page(from, limit).to_vec()
+        }
+    }
+
+    // Whether an offer made at `timestamp` takes part in the auction.
+    pub fn is_in_time(&self, timestamp: i64) -> bool {
+        self.state.is_none_or(|v| timestamp < v.closes_at)
+    }
+
+    // Offers made after the close are left out, whatever got them on the chain.
+    pub fn settlement(&self) -> CBCASettlement {
+        let floor: f32 = self.config.get_start_price().unwrap_or(0.0);
+        let mut winner: Option<(f32, &CBCABlock)> = None;
+        let mut offers: usize = 0;
+
+        for block in self.offers_chain.blocks().iter().filter(|v| self.is_in_time(v.get_timestamp())) {
+            offers += 1;
+
+            if let CBCABlockType::OFFER(offer) = block.get_payload() 
+                && offer.amount >= floor 
+                && winner.is_none_or(|(best, _)| offer.amount > best) {
+                    winner = Some((offer.amount, block));
+            }
+        }
+
+        CBCASettlement {
+            instance_id: self.identifier.clone(),
+            status: status_of(&self.state),
+            closes_at: self.state.map(|v| v.closes_at),
+            offers,
+            winner: winner.map(|(_, block)| block.clone())
+        }
+    }
+
     pub fn add_message(
         &mut self, 
         content: String, 
@@ -48,6 +141,6 @@ impl CBCAInstance {
     }
 
     pub fn display(&self) -> () {
-        println!("{:?}", self);
+        log::trace!("{:?}", self);
     }
 }
\ No newline at end of file
@@ -0,0 +1,29 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+// Prints the records of the server and of the shared crate as they always were, one tagged line
+// each. Records of the other libraries underneath are left out.
+struct CBCALogger;
+
+impl Log for CBCALogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let target: &str = metadata.target();
+        metadata.level() <= log::max_level()
+            && (target.starts_with(env!("CARGO_CRATE_NAME")) || target.starts_with("shared::"))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static CBCA_LOGGER: CBCALogger = CBCALogger;
+
+pub fn init(level: LevelFilter) {
+    if log::set_logger(&CBCA_LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
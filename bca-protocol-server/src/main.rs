@@ -1,28 +1,136 @@
 mod server;
+mod actor;
+mod config;
 mod queue;
 mod instance;
 mod manager;
+mod events;
+mod gateway;
+mod idempotency;
+mod index;
+mod logger;
+mod stats;
+mod ratelimit;
+mod shutdown;
+mod storage;
 
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
+use clap::Parser;
 use tokio;
 use server::CBCAServer;
-use queue::CBCAQueue;
+use log::LevelFilter;
 
-use crate::server::CBCARoutineAddr;
+use crate::{
+    config::{CBCAArgs, CBCAServerConfig},
+    server::CBCARoutineAddr,
+    storage::{CBCARecoveryReport, CBCAStorage, CBCAStorageConfig}
+};
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
-    let serv: CBCAServer = CBCAServer::spawn(
-        CBCARoutineAddr::spawn("127.0.0.1".to_string(), "8686".to_string()),
-        CBCARoutineAddr::spawn("127.0.0.1".to_string(), "8687".to_string()),
-        CBCARoutineAddr::spawn("127.0.0.1".to_string(), "8688".to_string())
-    )?;
+    // The config file, then the CBCA_* variables, then the command line.
+    let args: CBCAArgs = CBCAArgs::parse();
+    let mut config: CBCAServerConfig = CBCAServerConfig::load(args.config.as_deref())?;
+    config.apply_env();
+    config.apply_args(&args);
+
+    let valid: Result<(), std::io::Error> = config.validate();
+    if args.check_config {
+        print!("{}", config.display()?);
+        match valid {
+            Ok(_) => println!("# configuration ok."),
+            Err(e) => {
+                eprintln!("configuration invalid:\n{}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    valid?;
+
+    logger::init(config.get_log_level().unwrap_or(LevelFilter::Info));
+
+    // Routines listen on `bind.host` ports, or on <name>.sock files in `bind.unix_dir`.
+    let routine_addr = |port: u16, name: &str| -> CBCARoutineAddr {
+        match &config.bind.unix_dir {
+            Some(v) => CBCARoutineAddr::unix(v.join(format!("{}.sock", name))),
+            None => CBCARoutineAddr::spawn(config.bind.host.clone(), port.to_string())
+        }
+    };
 
-    let _ = tokio::join!(
-        biased;
-        serv.run_routines()
+    // Every chain is checked before serving, corrupt instances are set aside.
+    let storage_config: CBCAStorageConfig = config.get_storage()?;
+    let storage: Arc<dyn CBCAStorage> = storage_config.open().await?;
+    let report: CBCARecoveryReport = storage.recover().await?;
+    log::info!(
+        "[RECOVERY] {} instances checked, {} repaired, {} corrupt.",
+        report.checked, report.repaired, report.corrupt.len()
     );
 
+    let mut serv: CBCAServer = CBCAServer::spawn(
+        routine_addr(config.bind.message, "message"),
+        routine_addr(config.bind.instance, "instance"),
+        routine_addr(config.bind.offer, "offer"),
+        routine_addr(config.bind.subscribe, "subscribe"),
+        routine_addr(config.bind.query, "query"),
+        storage
+    )?;
+
+    if let Some(v) = config.get_unix_mode() {
+        serv.set_unix_mode(v);
+    }
+
+    serv.set_limits(config.limits.to_limits());
+    serv.set_max_connections(config.limits.max_connections);
+
+    if let Some(v) = config.rate.to_limits() {
+        serv.set_rate_limits(v);
+    }
+
+    // Sized before the gateway copies the queue.
+    serv.set_work_queue(config.queue.capacity, config.queue.workers);
+
+    if let Some(port) = config.bind.gateway {
+        serv.enable_gateway(CBCARoutineAddr::spawn(config.bind.host.clone(), port.to_string()));
+    }
+
+    if let Some(token) = config.get_admin_token() {
+        serv.enable_admin(routine_addr(config.bind.admin, "admin"), token.to_string());
+    }
+
+    if let Some(v) = config.tls.to_tls() {
+        serv.enable_tls(&v)?;
+    }
+
+    // Every connection runs in its own task, holding the server.
+    let serv: Arc<CBCAServer> = Arc::new(serv);
+    let mut routines = tokio::spawn({
+        let serv: Arc<CBCAServer> = Arc::clone(&serv);
+        async move { serv.run_routines().await }
+    });
+
+    let signal: &'static str = tokio::select! {
+        // Routines only end by themselves when one of them failed.
+        v = &mut routines => {
+            let ended: Result<(), std::io::Error> = v.map_err(std::io::Error::other)?;
+            if let Err(e) = &ended {
+                log::error!("[E] routine failed, {}.", e);
+            }
+            return ended;
+        },
+        v = shutdown::wait_signal() => v?
+    };
+    log::info!("[SHUTDOWN] {} received, draining for {} ms at most.", signal, config.shutdown.deadline_ms);
+    serv.shutdown();
+
+    match tokio::time::timeout(config.shutdown.get_deadline(), routines).await {
+        Ok(v) => {
+            v.map_err(std::io::Error::other)??;
+            log::info!("[SHUTDOWN] done.");
+        },
+        Err(_) => log::warn!("[SHUTDOWN] deadline passed, exiting with work under way.")
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,152 +1,168 @@
-use std::{env, path::PathBuf, sync::Arc};
-use tokio::{fs::OpenOptions, io::{AsyncReadExt, AsyncWriteExt}};
+use std::sync::Arc;
 use shared::{
-    block::CBCABlock, 
-    fchain::CBCAChain, 
-    payload::{MPayload, OPayload}
+    block::CBCABlock,
+    fchain::CBCAChainKind,
+    payload::{validate_instance_id, MPayload, OPayload},
+    request::{CBCAChainPage, CBCAInstanceSummary, CBCAReceipt}
 };
-use crate::instance::CBCAInstance;
+use crate::{
+    actor::{CBCAActorCommand, CBCAActors, CBCAAnswer},
+    events::CBCAEventBus,
+    idempotency::CBCAIdempotencyCache,
+    instance::CBCAInstance,
+    stats::CBCAStats,
+    storage::CBCAStorage
+};
+
+// Only identifiers the server issues reach the storage, whatever the backend.
+fn validate(instance_id: &str) -> Result<(), std::io::Error> {
+    validate_instance_id(instance_id)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
 
+// Writes go to the actor of their instance, so instances are written in parallel and each
+// chain in order, appended blocks are announced by the actor. Reads use the actor when it
+// runs, the storage otherwise.
 #[derive(Debug, Clone)]
 pub struct CBCAManager {
-    access: Arc<tokio::sync::Mutex<()>>,
-    current_path: std::path::PathBuf
+    actors: CBCAActors,
+    storage: Arc<dyn CBCAStorage>
 }
 
 impl CBCAManager {
-    pub fn spawn() -> Result<Self, std::io::Error> {
-        Ok(
-            Self {
-                access: Arc::new(tokio::sync::Mutex::new(())),
-                current_path: env::current_dir()?.join("data")
-            }
-        )
+    pub fn spawn(
+        stats: CBCAStats,
+        events: CBCAEventBus,
+        idempotency: CBCAIdempotencyCache,
+        storage: Arc<dyn CBCAStorage>
+    ) -> Self {
+        Self {
+            actors: CBCAActors::spawn(stats, events, idempotency),
+            storage
+        }
     }
 
-    pub async fn hard_push_msg(
-        &self, 
-        payload: MPayload
-    ) -> Result<(), std::io::Error> {
-        let _ = self.access.lock().await;
-        
-        let path: String = self.current_path
-                .join(&payload.instance_id.replace("-", "."))
-                .join("m.bca.json")
-                .display()
-                .to_string();
-
-        let mut file_r = OpenOptions::new()
-            .read(true)
-            .open(&path)
-            .await?;
-
-        let mut buf: String = String::new();
-        let _ = file_r.read_to_string(&mut buf).await?;
-
-        if buf.len() == 0 {
-            panic!("[E] empty buffer.");
-        } else {
-            drop(file_r);
+    pub async fn hard_load(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAInstance, std::io::Error> {
+        validate(instance_id)?;
+
+        match self.actors.ask_live(instance_id, |reply| CBCAActorCommand::Snapshot { reply }).await {
+            Some(v) => v,
+            None => self.storage.load(instance_id).await
         }
+    }
 
-        let mut file_w = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&path)
-            .await?;
+    // Only the config and the state, chains are left in the storage.
+    pub async fn hard_summary(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAInstanceSummary, std::io::Error> {
+        validate(instance_id)?;
+        self.storage.summary(instance_id).await
+    }
 
-        let mut parsed: CBCAChain = serde_json::from_str(&buf)?;
-        let capture: Option<String> = parsed.hash.clone();
-        let nhash_block = parsed.push(
-            CBCABlock::block_creator_message(payload.content, payload.author, payload.instance_id.clone())
-        )?;
-        let serialized: String = serde_json::to_string(&parsed)?;
-        println!("[UP 1/2] pushing {:?} in {:?}.", nhash_block, &payload.instance_id);
-        println!("[UP 2/2] chain hash changing from {:?} to {:?}.", capture, &parsed.hash);
+    pub async fn hard_read_range(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        from: usize,
+        limit: usize
+    ) -> Result<CBCAChainPage, std::io::Error> {
+        validate(instance_id)?;
+
+        match self.actors.ask_live(instance_id, |reply| CBCAActorCommand::ReadRange { kind, from, limit, reply }).await {
+            Some(v) => v,
+            None => self.storage.read_range(instance_id, kind, from, limit).await
+        }
+    }
 
-        file_w.write_all(serialized.as_bytes()).await?;
+    pub async fn hard_read_after(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        hash: &str,
+        limit: usize
+    ) -> Result<Option<CBCAChainPage>, std::io::Error> {
+        validate(instance_id)?;
+        let command = |reply| CBCAActorCommand::ReadAfter { kind, hash: hash.to_string(), limit, reply };
+
+        match self.actors.ask_live(instance_id, command).await {
+            Some(v) => v,
+            None => self.storage.read_after(instance_id, kind, hash, limit).await
+        }
+    }
 
-        Ok(())
+    pub async fn hard_head(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind
+    ) -> Result<(usize, Option<String>), std::io::Error> {
+        validate(instance_id)?;
+        self.storage.head(instance_id, kind).await
     }
 
-    pub async fn hard_push_offer(
-        &self, 
-        payload: OPayload
-    ) -> Result<(), std::io::Error> {
-        let _ = self.access.lock().await;
-        let path: String = self.current_path
-            .join(&payload.instance_id.replace("-", "."))
-            .join("o.bca.json")
-            .display()
-            .to_string();
-
-        let mut file_r = OpenOptions::new()
-            .read(true)
-            .open(&path)
-            .await?;
-
-        let mut buf: String = String::new();
-        let _ = file_r.read_to_string(&mut buf).await?;
-
-        if buf.len() == 0 {
-            panic!("[E] empty buffer.");
-        } else {
-            drop(file_r);
-        }
+    pub fn get_loaded(&self) -> usize {
+        self.actors.get_len()
+    }
 
-        let mut file_w = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&path)
-            .await?;
+    pub async fn hard_flush(&self) -> Result<(), std::io::Error> {
+        self.storage.flush().await
+    }
 
-        let mut parsed: CBCAChain = serde_json::from_str(&buf)?;
-        let capture: Option<String> = parsed.hash.clone();
-        let nhash_block = parsed.push(CBCABlock::block_creator_offer(payload.amount, payload.author, payload.instance_id.clone(), payload.message))?;
-        let serialized: String = serde_json::to_string(&parsed)?;
-        println!("[UP 1/2] pushing {:?} in {:?}.", nhash_block, &payload.instance_id);
-        println!("[UP 2/2] chain hash changing from {:?} to {:?}.", capture, &parsed.hash);
+    pub async fn hard_list(&self) -> Result<Vec<String>, std::io::Error> {
+        self.storage.list().await
+    }
 
-        file_w.write_all(serialized.as_bytes()).await?;
+    // Queues the block on the actor of its instance without waiting for the write, blocks
+    // queued one after the other are appended in that order.
+    async fn hard_append(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        block: CBCABlock,
+        key: Option<String>
+    ) -> Result<CBCAAnswer<CBCAReceipt>, std::io::Error> {
+        validate(instance_id)?;
+
+        Ok(self.actors
+            .tell(instance_id, &self.storage, |reply| CBCAActorCommand::Append { kind, block: Box::new(block), key, reply })
+            .await)
+    }
 
-        Ok(())
+    pub async fn hard_push_msg(
+        &self,
+        payload: MPayload
+    ) -> Result<CBCAAnswer<CBCAReceipt>, std::io::Error> {
+        self.hard_append(
+            &payload.instance_id,
+            CBCAChainKind::Messages,
+            CBCABlock::block_creator_message(payload.content, payload.author, payload.instance_id.clone()),
+            payload.idempotency_key
+        ).await
+    }
+
+    pub async fn hard_push_offer(
+        &self,
+        payload: OPayload
+    ) -> Result<CBCAAnswer<CBCAReceipt>, std::io::Error> {
+        self.hard_append(
+            &payload.instance_id,
+            CBCAChainKind::Offers,
+            CBCABlock::block_creator_offer(payload.amount, payload.author, payload.instance_id.clone(), payload.message),
+            payload.idempotency_key
+        ).await
     }
 
-    
     pub async fn hard_create(
-        &self, 
+        &self,
         instance: CBCAInstance
     ) -> Result<String, std::io::Error> {
-        let _ =  self.access.lock().await;
-
-        println!("{:?}", instance);
-
-        let path: PathBuf = self.current_path
-            .join(instance.identifier.replace("-", "."));
-
-        println!("[CREATE 1/4] {}", &path.display().to_string());
-        tokio::fs::create_dir(&path).await?;
-
-        let offer_chain_path: String = path.join("o.bca.json").display().to_string();
-        println!("[CREATE 2/4] {}", &offer_chain_path);
-        let mut file = tokio::fs::File::create(offer_chain_path).await?;
-        let _ = file.write_all(
-            serde_json::to_string(&instance.offers_chain)?.as_bytes()
-        ).await?;
-
-        let message_chain_path: String = path.join("m.bca.json").display().to_string();
-        println!("[CREATE 3/4] {}", &message_chain_path);
-        let mut file = tokio::fs::File::create(message_chain_path).await?;
-        let _ = file.write_all(
-            serde_json::to_string(&instance.messages_chain)?.as_bytes()
-        ).await?;
-
-        let config_path: String = path.join("c.bca.json").display().to_string();
-        println!("[CREATE 4/4] {}", &config_path);
-        let mut file = tokio::fs::File::create(config_path).await?;
-        let _ = file.write_all(
-            serde_json::to_string(&instance.config)?.as_bytes()
-        ).await?;
+        validate(&instance.identifier)?;
+
+        log::trace!("{:?}", instance);
+        self.storage.create(&instance).await?;
 
         Ok(instance.identifier)
     }
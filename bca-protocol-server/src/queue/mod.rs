@@ -1,114 +1,502 @@
-use std::{ops::DerefMut, sync::Arc, time::Duration};
+mod work;
 
+use std::{net::IpAddr, sync::Arc, time::{Duration, Instant}};
+
+use chrono::Utc;
 use shared::{
-    block::CBCABlockType,
-    payload::{IPayload, MPayload, OPayload, Payload}
+    communication::CBCAErrorPayload,
+    debug::{
+        CBCAChainHeads, CBCAChainVerdict, CBCADebugCommand, CBCAServerStats, CBCAVerifyReport
+    },
+    event::CBCAEvent,
+    fchain::{CBCAChain, CBCAChainKind, CBCAConfig},
+    payload::{issue_instance_id, CBCAChainRange, CBCAInstanceFilter, IPayload, MPayload, OPayload, QPayload},
+    request::{
+        CBCAAuctionStatus, CBCAChainPage, CBCAInstanceList, CBCAInstanceSummary, CBCAReceipt, CBCASettlement,
+        CBCA_MAX_PAGE_SIZE
+    }
 };
 
+use tokio::{sync::oneshot, task::JoinSet};
+
 use crate::{
+    actor::{self, CBCAAnswer, CBCAReply},
+    events::CBCAEventBus,
+    idempotency::{check_key, CBCAIdempotencyCache, DEFAULT_WINDOW_CAPACITY, DEFAULT_WINDOW_TTL},
+    index::CBCAInstanceIndex,
     instance::CBCAInstance,
-    manager::CBCAManager
+    manager::CBCAManager,
+    ratelimit::{CBCARateKind, CBCARateLimiter},
+    shutdown::CBCAShutdown,
+    stats::CBCAStats,
+    storage::{not_found, CBCAStorage}
 };
 
+pub use work::{CBCAJob, CBCAWorkQueue, CBCA_QUEUE_CAPACITY, CBCA_QUEUE_WORKERS};
+use work::CBCAQueuedJob;
+
 #[derive(Debug, Clone)]
 pub struct CBCAQueue {
-    queue: Arc<tokio::sync::Mutex<Vec<CBCABlockType>>>,
-    remaining: usize,
-    manager: CBCAManager
+    work: CBCAWorkQueue,
+    manager: CBCAManager,
+    events: CBCAEventBus,
+    idempotency: CBCAIdempotencyCache,
+    index: CBCAInstanceIndex,
+    limiter: CBCARateLimiter,
+    shutdown: CBCAShutdown,
+    stats: CBCAStats
+}
+
+fn invalid_input(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, reason)
 }
 
 impl CBCAQueue{
-    pub fn spawn() -> Result<Self, std::io::Error> {
-        Ok(
-            Self {
-                queue: Arc::new(tokio::sync::Mutex::new(Vec::new())),
-                remaining: 0,
-                manager: CBCAManager::spawn()?
+    pub fn spawn(storage: Arc<dyn CBCAStorage>) -> Self {
+        let stats: CBCAStats = CBCAStats::spawn();
+        let events: CBCAEventBus = CBCAEventBus::spawn(1024);
+        let idempotency: CBCAIdempotencyCache = CBCAIdempotencyCache::spawn(DEFAULT_WINDOW_CAPACITY, DEFAULT_WINDOW_TTL);
+
+        Self {
+            work: CBCAWorkQueue::spawn(CBCA_QUEUE_CAPACITY, CBCA_QUEUE_WORKERS),
+            manager: CBCAManager::spawn(stats.clone(), events.clone(), idempotency.clone(), storage),
+            events,
+            idempotency,
+            index: CBCAInstanceIndex::spawn(),
+            limiter: CBCARateLimiter::default(),
+            shutdown: CBCAShutdown::spawn(),
+            stats
+        }
+    }
+
+    pub fn get_events(&self) -> &CBCAEventBus {
+        &self.events
+    }
+
+    pub fn get_stats(&self) -> &CBCAStats {
+        &self.stats
+    }
+
+    pub fn get_limiter(&self) -> &CBCARateLimiter {
+        &self.limiter
+    }
+
+    // Triggered once the server stops accepting, long-lived sessions end on it.
+    pub fn get_shutdown(&self) -> &CBCAShutdown {
+        &self.shutdown
+    }
+
+    pub fn set_work_queue(
+        &mut self,
+        work: CBCAWorkQueue
+    ) {
+        self.work = work;
+    }
+
+    // Spends a token of `kind` for the peer address and the author before a request is handled.
+    // `ip` is None for Unix socket peers, instances they create aren't limited.
+    pub fn admit(
+        &self,
+        kind: CBCARateKind,
+        ip: Option<IpAddr>,
+        author: Option<&str>
+    ) -> Result<(), CBCAErrorPayload> {
+        self.limiter.check(kind, ip, author).map_err(|wait| {
+            log::warn!("[RATE] {:?} limited, ip={:?} author={:?}.", kind, ip, author);
+            CBCAErrorPayload::rate_limited(wait)
+        })
+    }
+
+    // Hands a write to the workers and waits for its answer.
+    async fn submit<T>(
+        &self,
+        job: impl FnOnce(CBCAReply<T>) -> CBCAJob
+    ) -> Result<T, std::io::Error> {
+        let (reply, answer) = oneshot::channel::<Result<T, std::io::Error>>();
+
+        if let Err(e) = self.work.submit(job(reply)).await {
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                log::warn!("[QUEUE] full, {} jobs waiting.", self.work.get_depth());
+                self.stats.record_queue_rejected();
             }
-        )
+            return Err(e);
+        }
+
+        self.stats.record_queue_submitted();
+        answer.await.map_err(|_| std::io::Error::other("the job was dropped before running."))?
     }
 
     pub async fn handle_add_message(
         &self, 
         payload: MPayload
-    ) -> Result<(), std::io::Error> {
-        self.manager.hard_push_msg(payload).await?;
-        Ok(())
+    ) -> Result<CBCAReceipt, std::io::Error> {
+        self.submit(|reply| CBCAJob::Message(payload, reply)).await
     }
 
     pub async fn handle_add_offer(
         &self, 
         payload: OPayload
-    ) -> Result<(), std::io::Error> {
-        self.manager.hard_push_offer(payload).await?;
-        Ok(())
+    ) -> Result<CBCAReceipt, std::io::Error> {
+        self.submit(|reply| CBCAJob::Offer(payload, reply)).await
     }
 
     pub async fn handle_add_instance(
         &self,
         payload: IPayload
     ) -> Result<String, std::io::Error> {
-        println!("recu");
+        self.submit(|reply| CBCAJob::Instance(payload, reply)).await
+    }
+
+    // Queues the message on the actor of its instance, which appends it once per idempotency key.
+    async fn send_message(
+        &self,
+        payload: MPayload
+    ) -> Result<CBCAAnswer<CBCAReceipt>, std::io::Error> {
+        payload.validate().map_err(invalid_input)?;
+        check_key(payload.idempotency_key.as_deref())?;
+
+        self.manager.hard_push_msg(payload).await
+    }
+
+    async fn send_offer(
+        &self,
+        payload: OPayload
+    ) -> Result<CBCAAnswer<CBCAReceipt>, std::io::Error> {
+        payload.validate().map_err(invalid_input)?;
+        check_key(payload.idempotency_key.as_deref())?;
+
+        self.manager.hard_push_offer(payload).await
+    }
+
+    // Waits for the actor to append, offers it appended count as bids.
+    async fn receive(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        answer: Result<CBCAAnswer<CBCAReceipt>, std::io::Error>
+    ) -> Result<CBCAReceipt, std::io::Error> {
+        let receipt: CBCAReceipt = actor::wait(instance_id, answer?).await?;
+        log::debug!("[UP] pushing {:?} in {:?}.", receipt.block.get_hash(), instance_id);
+
+        if kind == CBCAChainKind::Offers && !receipt.duplicate {
+            self.index.record_bid(instance_id);
+        }
+
+        Ok(receipt)
+    }
+
+    async fn apply_instance(
+        &self,
+        payload: IPayload
+    ) -> Result<String, std::io::Error> {
+        payload.validate().map_err(invalid_input)?;
+        // The id asked for is ignored, the server issues every one of them.
         let instance: CBCAInstance = 
-            CBCAInstance::spawn(payload.extract_config().clone(), payload.get_instance_id());
+            CBCAInstance::spawn(payload.extract_config().clone(), &issue_instance_id());
+        let closes_at: Option<i64> = instance.state.map(|v| v.closes_at);
+        let config: CBCAConfig = instance.config.clone();
         let identifier: String = self.manager.hard_create(instance).await?;
+
+        self.index.insert(identifier.clone(), config, closes_at, 0);
+
+        self.events.publish(CBCAEvent::InstanceCreated { instance_id: identifier.clone() });
+
+        if let Some(v) = closes_at {
+            self.arm_closing(identifier.clone(), v);
+        }
+
         Ok(identifier)
     }
 
-    pub async fn routine(
-        &self
+    // Tells subscribers when the auction closes, nothing on disk changes at that moment.
+    // Offers are refused from then on, their keys are let go, a retry is refused as late.
+    fn arm_closing(
+        &self,
+        instance_id: String,
+        closes_at: i64
+    ) {
+        let events: CBCAEventBus = self.events.clone();
+        let idempotency: CBCAIdempotencyCache = self.idempotency.clone();
+        let remaining: i64 = closes_at - Utc::now().timestamp();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(remaining.max(0) as u64)).await;
+            idempotency.forget(&instance_id, CBCAChainKind::Offers);
+            events.publish(CBCAEvent::StatusChanged { 
+                instance_id, 
+                status: CBCAAuctionStatus::Closed 
+            });
+        });
+    }
+
+    // Private instances read as missing to the authors they don't invite.
+    fn check_visible(
+        &self,
+        instance_id: &str,
+        requester: Option<&str>
     ) -> Result<(), std::io::Error> {
-        loop {
-            if self.remaining > 0 {
-                let poped: Option<CBCABlockType> = self.remove_wait_action().await;
-
-                match poped {
-                    Some(v) => {
-                        let payload: CBCABlockType = v;
-
-                        match payload {
-                            CBCABlockType::MESSAGE(mpayload) => {
-                                self.handle_add_message(mpayload).await?;
-                            },
-                            CBCABlockType::OFFER(opayload) => {
-                                self.handle_add_offer(opayload).await?;
-                            },
-                            CBCABlockType::INSTANCE(ipayload) => {
-                                let response: Result<String, std::io::Error> = self.handle_add_instance(ipayload).await;
-                            }
-                        }
-                    },
-                    None => {
-                        println!("[E] error when pushing on chain.");
-                    }
-                }
-            } else {
-                println!("[...] no action...");
+        match self.index.is_visible_to(instance_id, requester) {
+            true => Ok(()),
+            false => Err(not_found(instance_id))
+        }
+    }
+
+    pub async fn handle_summary(
+        &self,
+        instance_id: &str,
+        requester: Option<&str>
+    ) -> Result<CBCAInstanceSummary, std::io::Error> {
+        self.check_visible(instance_id, requester)?;
+        self.manager.hard_summary(instance_id).await
+    }
+
+    pub async fn handle_list_instances(
+        &self
+    ) -> Result<Vec<CBCAInstanceSummary>, std::io::Error> {
+        let mut summaries: Vec<CBCAInstanceSummary> = Vec::new();
+
+        for identifier in self.manager.hard_list().await? {
+            match self.manager.hard_summary(&identifier).await {
+                Ok(v) => summaries.push(v),
+                Err(e) => log::error!("[E] unreadable instance {}, {}.", identifier, e)
             }
+        }
+
+        Ok(summaries)
+    }
+
+    // Read from the index, private instances only show up to the authors they invite.
+    pub fn handle_find_instances(
+        &self,
+        text: Option<&str>,
+        filter: &CBCAInstanceFilter
+    ) -> CBCAInstanceList {
+        self.index.query(text, filter)
+    }
+
+    pub async fn handle_read_chain(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        range: CBCAChainRange,
+        requester: Option<&str>
+    ) -> Result<CBCAChainPage, std::io::Error> {
+        self.check_visible(instance_id, requester)?;
 
-            tokio::time::sleep(Duration::from_secs_f64(2.0)).await;
-            tokio::task::yield_now().await;
+        match range {
+            CBCAChainRange::Index { from, limit } => 
+                self.manager.hard_read_range(instance_id, kind, from, limit.min(CBCA_MAX_PAGE_SIZE)).await,
+            CBCAChainRange::After { hash, limit } => self.manager
+                .hard_read_after(instance_id, kind, &hash, limit.min(CBCA_MAX_PAGE_SIZE))
+                .await?
+                .ok_or(std::io::Error::new(
+                    std::io::ErrorKind::NotFound, 
+                    format!("block {} not found.", hash)
+                ))
         }
     }
-    
-    pub async fn add_wait_action(
-        &self, 
-        payload: CBCABlockType
-    ) -> () {
-        println!("recu");
-        let mut lock = self.queue.lock().await;
-        lock.push(payload);
+
+    // `requester` is who the connection authenticated as, the one a filter claims is ignored.
+    pub async fn handle_query(
+        &self,
+        payload: QPayload,
+        requester: Option<String>
+    ) -> Result<serde_json::Value, std::io::Error> {
+        match payload {
+            QPayload::Chain { instance_id, chain, range } => {
+                let page: CBCAChainPage = self.handle_read_chain(&instance_id, chain, range, requester.as_deref()).await?;
+                Ok(serde_json::to_value(page)?)
+            },
+            QPayload::List { filter } => {
+                let filter: CBCAInstanceFilter = CBCAInstanceFilter { requester, ..filter };
+                Ok(serde_json::to_value(self.handle_find_instances(None, &filter))?)
+            },
+            QPayload::Search { text, filter } => {
+                let filter: CBCAInstanceFilter = CBCAInstanceFilter { requester, ..filter };
+                Ok(serde_json::to_value(self.handle_find_instances(Some(&text), &filter))?)
+            }
+        }
+    }
+
+    pub async fn handle_stats(
+        &self
+    ) -> Result<CBCAServerStats, std::io::Error> {
+        let mut heads: Vec<CBCAChainHeads> = Vec::new();
+
+        // Heads come from the storage, no chain is read and no instance loaded for them.
+        for identifier in self.manager.hard_list().await? {
+            let offers = self.manager.hard_head(&identifier, CBCAChainKind::Offers).await;
+            let messages = self.manager.hard_head(&identifier, CBCAChainKind::Messages).await;
+
+            match (offers, messages) {
+                (Ok((offers, offers_head)), Ok((messages, messages_head))) => heads.push(CBCAChainHeads {
+                    offers,
+                    offers_head,
+                    messages,
+                    messages_head,
+                    instance_id: identifier
+                }),
+                (Err(e), _) | (_, Err(e)) => log::error!("[E] unreadable instance {}, {}.", identifier, e)
+            }
+        }
+
+        Ok(
+            CBCAServerStats {
+                queue_depth: self.work.get_depth(),
+                open_connections: self.stats.get_open_connections(),
+                loaded_instances: self.manager.get_loaded(),
+                storage_lock: self.stats.get_lock_stats(),
+                queue: self.stats.get_queue_stats(
+                    self.work.get_depth(),
+                    self.work.get_capacity(),
+                    self.work.get_workers()
+                ),
+                heads
+            }
+        )
+    }
+
+    pub async fn handle_verify(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAVerifyReport, std::io::Error> {
+        let instance: CBCAInstance = self.manager.hard_load(instance_id).await?;
+        let verdict = |chain: &CBCAChain| -> CBCAChainVerdict {
+            let first_invalid: Option<usize> = chain.first_invalid();
+            CBCAChainVerdict { blocks: chain.len(), valid: first_invalid.is_none(), first_invalid }
+        };
+
+        Ok(
+            CBCAVerifyReport {
+                instance_id: instance_id.to_string(),
+                offers: verdict(&instance.offers_chain),
+                messages: verdict(&instance.messages_chain)
+            }
+        )
+    }
+
+    pub async fn handle_debug(
+        &self,
+        command: CBCADebugCommand
+    ) -> Result<serde_json::Value, std::io::Error> {
+        match command {
+            CBCADebugCommand::Stats => Ok(serde_json::to_value(self.handle_stats().await?)?),
+            CBCADebugCommand::Verify { instance_id } => 
+                Ok(serde_json::to_value(self.handle_verify(&instance_id).await?)?)
+        }
     }
 
-    pub async fn remove_wait_action(
+    pub async fn handle_settlement(
+        &self,
+        instance_id: &str,
+        requester: Option<&str>
+    ) -> Result<CBCASettlement, std::io::Error> {
+        self.check_visible(instance_id, requester)?;
+        let instance: CBCAInstance = self.manager.hard_load(instance_id).await?;
+        Ok(instance.settlement())
+    }
+
+    // Refuses new writes, the workers run the queued ones then `routine` returns.
+    pub fn close_work(&self) {
+        self.work.close();
+    }
+
+    // Fills the index from the storage, before any listener opens so no search or read misses
+    // an instance, and arms the closing of the open auctions.
+    pub async fn build_index(
         &self
-    ) -> Option<CBCABlockType> {
-        let mut lock = self.queue.lock().await;
-        let block: Option<CBCABlockType> = lock.pop();
-        block
+    ) -> Result<(), std::io::Error> {
+        for summary in self.handle_list_instances().await? {
+            let bids: usize = match self.manager.hard_read_range(&summary.instance_id, CBCAChainKind::Offers, 0, 0).await {
+                Ok(v) => v.total,
+                Err(e) => {
+                    log::error!("[E] unreadable offers of {}, {}.", summary.instance_id, e);
+                    0
+                }
+            };
+            self.index.insert(summary.instance_id.clone(), summary.config, summary.closes_at, bids);
+
+            if let (CBCAAuctionStatus::Open, Some(v)) = (summary.status, summary.closes_at) {
+                self.arm_closing(summary.instance_id, v);
+            }
+        }
+        log::info!("[INDEX] {} instances indexed.", self.index.get_len());
+
+        Ok(())
     }
 
-    pub fn display(&self) -> () {
-        println!("{:#?}", self.queue);
+    pub async fn routine(
+        &self
+    ) -> Result<(), std::io::Error> {
+        let workers: Vec<tokio::task::JoinHandle<()>> = (0..self.work.get_workers())
+            .map(|v| tokio::spawn(self.clone().work(v)))
+            .collect();
+        log::info!("[QUEUE] {} workers, {} jobs at most.", workers.len(), self.work.get_capacity());
+
+        for worker in workers {
+            worker.await?;
+        }
+
+        // Nothing writes anymore, what the storage buffers can go to disk.
+        self.manager.hard_flush().await?;
+        log::info!("[QUEUE] drained, storage flushed.");
+
+        Ok(())
+    }
+
+    // Hands the jobs of `worker` to the actors of their instances in the order they were
+    // submitted, which keeps each chain in that order. The worker doesn't wait for the writes,
+    // it goes on with the next job while they run and returns once every one was answered.
+    async fn work(self, worker: usize) {
+        let mut running: JoinSet<()> = JoinSet::new();
+
+        loop {
+            tokio::select! {
+                Some(_) = running.join_next(), if !running.is_empty() => {},
+                job = self.work.next(worker) => match job {
+                    Some(v) => self.dispatch(v, &mut running).await,
+                    None => break
+                }
+            }
+        }
+
+        while running.join_next().await.is_some() {}
     }
-}
\ No newline at end of file
+
+    async fn dispatch(
+        &self,
+        queued: CBCAQueuedJob,
+        running: &mut JoinSet<()>
+    ) {
+        let CBCAQueuedJob { submitted, job } = queued;
+        let started: Instant = Instant::now();
+        let queue: CBCAQueue = self.clone();
+
+        match job {
+            CBCAJob::Message(payload, reply) => {
+                let instance_id: String = payload.instance_id.clone();
+                let answer = self.send_message(payload).await;
+
+                running.spawn(async move {
+                    let _ = reply.send(queue.receive(&instance_id, CBCAChainKind::Messages, answer).await);
+                    queue.stats.record_queue_job(started - submitted, started.elapsed());
+                });
+            },
+            CBCAJob::Offer(payload, reply) => {
+                let instance_id: String = payload.instance_id.clone();
+                let answer = self.send_offer(payload).await;
+
+                running.spawn(async move {
+                    let _ = reply.send(queue.receive(&instance_id, CBCAChainKind::Offers, answer).await);
+                    queue.stats.record_queue_job(started - submitted, started.elapsed());
+                });
+            },
+            CBCAJob::Instance(payload, reply) => {
+                running.spawn(async move {
+                    let _ = reply.send(queue.apply_instance(payload).await);
+                    queue.stats.record_queue_job(started - submitted, started.elapsed());
+                });
+            }
+        }
+    }
+}
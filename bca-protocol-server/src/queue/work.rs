@@ -0,0 +1,131 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    time::{Duration, Instant}
+};
+
+use tokio::sync::{mpsc, Mutex};
+use shared::{payload::{IPayload, MPayload, OPayload}, request::CBCAReceipt};
+
+use crate::{actor::CBCAReply, shutdown::CBCAShutdown};
+
+// Writes waiting for the workers, shared out between them, submitters wait past it.
+pub const CBCA_QUEUE_CAPACITY: usize = 1024;
+pub const CBCA_QUEUE_WORKERS: usize = 4;
+// How long a submitter waits for room before its write is refused as busy.
+pub const CBCA_QUEUE_SUBMIT_WAIT: Duration = Duration::from_secs(2);
+
+// A write and where its answer goes, the connection that submitted it waits on the other end.
+pub enum CBCAJob {
+    Message(MPayload, CBCAReply<CBCAReceipt>),
+    Offer(OPayload, CBCAReply<CBCAReceipt>),
+    Instance(IPayload, CBCAReply<String>)
+}
+
+impl CBCAJob {
+    // The instance written to, None for a new one.
+    fn get_instance_id(&self) -> Option<&str> {
+        match self {
+            CBCAJob::Message(v, _) => Some(&v.instance_id),
+            CBCAJob::Offer(v, _) => Some(&v.instance_id),
+            CBCAJob::Instance(..) => None
+        }
+    }
+}
+
+pub struct CBCAQueuedJob {
+    pub submitted: Instant,
+    pub job: CBCAJob
+}
+
+// Bounded FIFOs of the writes, one per worker, shared by every copy of the queue. The writes
+// of an instance always go to the same worker, which hands them to the instance's actor in the
+// order they were submitted, new instances go to each worker in turn. Once closed, jobs are
+// refused and the workers stop after the ones already queued.
+#[derive(Debug, Clone)]
+pub struct CBCAWorkQueue {
+    jobs: Vec<mpsc::Sender<CBCAQueuedJob>>,
+    pending: Vec<Arc<Mutex<mpsc::Receiver<CBCAQueuedJob>>>>,
+    turn: Arc<AtomicUsize>,
+    closed: CBCAShutdown
+}
+
+impl CBCAWorkQueue {
+    pub fn spawn(
+        capacity: usize,
+        workers: usize
+    ) -> Self {
+        let workers: usize = workers.max(1);
+        let (jobs, pending) = (0..workers)
+            .map(|_| mpsc::channel::<CBCAQueuedJob>(capacity.div_ceil(workers).max(1)))
+            .map(|(jobs, pending)| (jobs, Arc::new(Mutex::new(pending))))
+            .unzip();
+
+        Self {
+            jobs,
+            pending,
+            turn: Arc::new(AtomicUsize::new(0)),
+            closed: CBCAShutdown::spawn()
+        }
+    }
+
+    pub fn get_workers(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn get_capacity(&self) -> usize {
+        self.jobs.iter().map(|v| v.max_capacity()).sum()
+    }
+
+    pub fn get_depth(&self) -> usize {
+        self.jobs.iter().map(|v| v.max_capacity() - v.capacity()).sum()
+    }
+
+    pub fn close(&self) {
+        self.closed.trigger();
+    }
+
+    fn worker_of(&self, job: &CBCAJob) -> usize {
+        match job.get_instance_id() {
+            Some(v) => {
+                let mut hasher: DefaultHasher = DefaultHasher::new();
+                v.hash(&mut hasher);
+                (hasher.finish() % self.jobs.len() as u64) as usize
+            },
+            None => self.turn.fetch_add(1, Ordering::Relaxed) % self.jobs.len()
+        }
+    }
+
+    // Waits up to CBCA_QUEUE_SUBMIT_WAIT for room, a full queue is WouldBlock.
+    pub async fn submit(
+        &self,
+        job: CBCAJob
+    ) -> Result<(), std::io::Error> {
+        if self.closed.is_triggered() {
+            return Err(std::io::Error::other("the server is shutting down."));
+        }
+
+        let jobs: &mpsc::Sender<CBCAQueuedJob> = &self.jobs[self.worker_of(&job)];
+        let queued: CBCAQueuedJob = CBCAQueuedJob { submitted: Instant::now(), job };
+
+        match tokio::time::timeout(CBCA_QUEUE_SUBMIT_WAIT, jobs.send(queued)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(_)) => Err(std::io::Error::other("the work queue is closed.")),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "the server is busy, try again."
+            ))
+        }
+    }
+
+    // The oldest job of `worker`, None once the queue is closed and empty.
+    pub async fn next(&self, worker: usize) -> Option<CBCAQueuedJob> {
+        let mut pending = self.pending[worker].lock().await;
+
+        tokio::select! {
+            biased;
+            v = pending.recv() => v,
+            _ = self.closed.wait() => pending.try_recv().ok()
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::HashMap,
+    env,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant}
+};
+
+// Past this many buckets, the full ones are dropped, they'd be created again as they were.
+const CBCA_MAX_RATE_BUCKETS: usize = 100_000;
+
+// What a request costs a token of, every kind has its own budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CBCARateKind {
+    Message,
+    Offer,
+    Instance
+}
+
+// Who a budget belongs to, Unix socket peers have no address and only count per author.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum CBCARateSource {
+    Ip(IpAddr),
+    Author(String)
+}
+
+// `burst` requests at once, then `per_minute` over time. A zero burst turns the limit off.
+#[derive(Clone, Copy, Debug)]
+pub struct CBCARateBudget {
+    pub burst: u32,
+    pub per_minute: u32
+}
+
+impl CBCARateBudget {
+    pub fn spawn(burst: u32, per_minute: u32) -> Self {
+        Self { burst, per_minute }
+    }
+
+    // "burst/per_minute", e.g. "20/60", or "off".
+    pub fn parse(value: &str) -> Option<Self> {
+        if value == "off" {
+            return Some(Self::spawn(0, 0));
+        }
+
+        let (burst, per_minute) = value.split_once('/')?;
+        Some(Self::spawn(burst.trim().parse().ok()?, per_minute.trim().parse().ok()?))
+    }
+
+    fn is_off(&self) -> bool {
+        self.burst == 0
+    }
+}
+
+// Written the way `parse` reads it.
+impl std::fmt::Display for CBCARateBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_off() {
+            write!(f, "off")
+        } else {
+            write!(f, "{}/{}", self.burst, self.per_minute)
+        }
+    }
+}
+
+// Budgets applied to every source IP and every author, separately.
+#[derive(Clone, Copy, Debug)]
+pub struct CBCARateLimits {
+    pub message: CBCARateBudget,
+    pub offer: CBCARateBudget,
+    pub instance: CBCARateBudget
+}
+
+impl Default for CBCARateLimits {
+    fn default() -> Self {
+        Self {
+            message: CBCARateBudget::spawn(20, 60),
+            offer: CBCARateBudget::spawn(10, 30),
+            instance: CBCARateBudget::spawn(3, 6)
+        }
+    }
+}
+
+impl CBCARateLimits {
+    // Budgets overridden by CBCA_RATE_MESSAGE, CBCA_RATE_OFFER and CBCA_RATE_INSTANCE.
+    pub fn with_env(self) -> Self {
+        let budget = |name: &str, default: CBCARateBudget| -> CBCARateBudget {
+            env::var(name).ok().and_then(|v| CBCARateBudget::parse(&v)).unwrap_or(default)
+        };
+
+        Self {
+            message: budget("CBCA_RATE_MESSAGE", self.message),
+            offer: budget("CBCA_RATE_OFFER", self.offer),
+            instance: budget("CBCA_RATE_INSTANCE", self.instance)
+        }
+    }
+
+    fn get_budget(&self, kind: CBCARateKind) -> CBCARateBudget {
+        match kind {
+            CBCARateKind::Message => self.message,
+            CBCARateKind::Offer => self.offer,
+            CBCARateKind::Instance => self.instance
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CBCATokenBucket {
+    tokens: f64,
+    refilled: Instant
+}
+
+impl CBCATokenBucket {
+    fn refill(&mut self, budget: CBCARateBudget, now: Instant) {
+        let rate: f64 = budget.per_minute as f64 / 60.0;
+        let elapsed: f64 = now.duration_since(self.refilled).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate).min(budget.burst as f64);
+        self.refilled = now;
+    }
+
+    // How long until a token is there, zero when one already is.
+    fn wait(&self, budget: CBCARateBudget) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+
+        if budget.per_minute == 0 {
+            return Duration::MAX;
+        }
+
+        Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / budget.per_minute as f64)
+    }
+
+    fn is_full(&self, budget: CBCARateBudget) -> bool {
+        self.tokens >= budget.burst as f64
+    }
+}
+
+#[derive(Debug, Default)]
+struct CBCARateState {
+    limits: CBCARateLimits,
+    buckets: HashMap<(CBCARateKind, CBCARateSource), CBCATokenBucket>
+}
+
+// Token buckets per source IP and per author, shared by the TCP routines and the gateway.
+#[derive(Debug, Clone, Default)]
+pub struct CBCARateLimiter {
+    state: Arc<Mutex<CBCARateState>>
+}
+
+impl CBCARateLimiter {
+    // Buckets already filled keep their tokens, they are capped to the new burst on next use.
+    pub fn set_limits(&self, limits: CBCARateLimits) {
+        match self.state.lock() {
+            Ok(mut v) => v.limits = limits,
+            Err(v) => v.into_inner().limits = limits
+        }
+    }
+
+    // Takes a token from the bucket of the IP and of the author, or from neither.
+    // The error is how long to wait before a retry can pass.
+    pub fn check(
+        &self,
+        kind: CBCARateKind,
+        ip: Option<IpAddr>,
+        author: Option<&str>
+    ) -> Result<(), Duration> {
+        let mut lock = match self.state.lock() {
+            Ok(v) => v,
+            Err(v) => v.into_inner()
+        };
+
+        let limits: CBCARateLimits = lock.limits;
+        let budget: CBCARateBudget = limits.get_budget(kind);
+
+        if budget.is_off() {
+            return Ok(());
+        }
+
+        let now: Instant = Instant::now();
+        let sources: Vec<CBCARateSource> = ip
+            .map(CBCARateSource::Ip)
+            .into_iter()
+            .chain(author.map(|v| CBCARateSource::Author(v.to_string())))
+            .collect();
+
+        if lock.buckets.len() >= CBCA_MAX_RATE_BUCKETS {
+            lock.buckets.retain(|(kind, _), bucket| {
+                bucket.refill(limits.get_budget(*kind), now);
+                !bucket.is_full(limits.get_budget(*kind))
+            });
+        }
+
+        let mut wait: Duration = Duration::ZERO;
+        for source in &sources {
+            let bucket: &mut CBCATokenBucket = lock.buckets
+                .entry((kind, source.clone()))
+                .or_insert(CBCATokenBucket { tokens: budget.burst as f64, refilled: now });
+
+            bucket.refill(budget, now);
+            wait = wait.max(bucket.wait(budget));
+        }
+
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        for source in sources {
+            if let Some(v) = lock.buckets.get_mut(&(kind, source)) {
+                v.tokens -= 1.0;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,114 +1,389 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::str::from_utf8;
 use std::time::Duration;
 use std::{sync::Arc};
 use std::io::Error;
 use shared::communication::{self, CBCATcpPayload, CBCATcpPayloadType};
-use shared::payload::{IPayload, MPayload, OPayload};
+use shared::handshake::{self, CBCAFeature, CBCAHelloAck};
+use shared::limits::CBCALimits;
+use shared::communication::{CBCAErrorCode, CBCAErrorPayload};
+use shared::event::CBCAEvent;
+use shared::payload::{DPayload, IPayload, MPayload, OPayload, QPayload, SPayload};
+use shared::request::CBCAReceipt;
+use serde::de::DeserializeOwned;
+use shared::tls::{CBCATlsServerConfig, TlsAcceptor};
+use shared::transport::{CBCAEndpoint, CBCAIncoming, CBCAListener, CBCAStream};
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpSocket};
 
-use crate::queue::CBCAQueue;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::gateway::CBCAGateway;
+use crate::queue::{CBCAQueue, CBCAWorkQueue};
+use crate::ratelimit::{CBCARateKind, CBCARateLimits};
+use crate::shutdown::CBCAShutdown;
+use crate::stats::CBCAConnectionGuard;
+use crate::storage::CBCAStorage;
+
+pub const CBCA_MAX_CONNECTIONS: usize = 1024;
 
 pub struct CBCAServer {
     addr_instance: CBCARoutineAddr,
     addr_offer: CBCARoutineAddr,
     addr_message: CBCARoutineAddr,
-    shared_queue: CBCAQueue
+    addr_subscribe: CBCARoutineAddr,
+    addr_query: CBCARoutineAddr,
+    admin: Option<CBCAAdminAccess>,
+    gateway: Option<CBCAGateway>,
+    shared_queue: CBCAQueue,
+    features: Vec<CBCAFeature>,
+    tls: Option<TlsAcceptor>,
+    limits: CBCALimits,
+    unix_mode: u32,
+    // Permits of the open connections, over every routine and the gateway.
+    connections: Arc<Semaphore>,
+    max_connections: usize
+}
+
+// Which routine a connection was accepted by.
+#[derive(Clone, Copy, Debug)]
+enum CBCARoutine {
+    Instance,
+    Message,
+    Offer,
+    Subscribe,
+    Query,
+    Admin
+}
+
+impl CBCARoutine {
+    fn get_tag(&self) -> &'static str {
+        match self {
+            CBCARoutine::Instance => "INSTANCE",
+            CBCARoutine::Message => "MESSAGE",
+            CBCARoutine::Offer => "OFFER",
+            CBCARoutine::Subscribe => "SUBSCRIBE",
+            CBCARoutine::Query => "QUERY",
+            CBCARoutine::Admin => "ADMIN"
+        }
+    }
 }
 
 pub struct CBCARoutineAddr {
-    ip: String,
-    port: String
+    endpoint: CBCAEndpoint
 }
 
 impl CBCARoutineAddr {
     pub fn spawn(ip: String, port: String) -> Self {
-        Self { ip, port }
+        Self { endpoint: CBCAEndpoint::Tcp(format!("{}:{}", ip, port)) }
+    }
+
+    pub fn unix(path: PathBuf) -> Self {
+        Self { endpoint: CBCAEndpoint::Unix(path) }
     }
 
     pub fn get_full_addr(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
+        self.endpoint.to_string()
+    }
+
+    pub fn get_endpoint(&self) -> &CBCAEndpoint {
+        &self.endpoint
     }
 }
 
+// Where the admin routine listens and the token its Debug frames must carry.
+pub struct CBCAAdminAccess {
+    addr: CBCARoutineAddr,
+    token: String
+}
+
+// Compares every byte so the time taken doesn't tell how much of the token was right.
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected.bytes().zip(given.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+// Runs the TLS handshake, when there is one, and applies the limits. A peer stalling the
+// handshake counts as a header read.
+pub async fn accept_peer(
+    socket: CBCAIncoming,
+    tls: Option<&TlsAcceptor>,
+    limits: CBCALimits
+) -> Option<CBCAStream> {
+    let accepting = CBCAStream::accept(socket, tls);
+
+    let mut stream: CBCAStream = match tokio::time::timeout(limits.header_timeout, accepting).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => {
+            log::warn!("[TLS] handshake failed, {}.", e);
+            return None;
+        },
+        Err(_) => {
+            log::warn!("[TLS] handshake timed out.");
+            return None;
+        }
+    };
+
+    if let Err(e) = stream.set_limits(limits) {
+        log::warn!("[LIMITS] keepalive not set, {}.", e);
+    }
+
+    Some(stream)
+}
+
 impl CBCAServer {
     pub fn spawn(
         addr_message: CBCARoutineAddr,
         addr_instance: CBCARoutineAddr,
-        addr_offer: CBCARoutineAddr
+        addr_offer: CBCARoutineAddr,
+        addr_subscribe: CBCARoutineAddr,
+        addr_query: CBCARoutineAddr,
+        storage: Arc<dyn CBCAStorage>
     ) -> Result<Self, std::io::Error> {
         Ok(
             Self {
                 addr_instance,
                 addr_offer,
                 addr_message,
-                shared_queue: CBCAQueue::spawn()?
+                addr_subscribe,
+                addr_query,
+                admin: None,
+                gateway: None,
+                shared_queue: CBCAQueue::spawn(storage),
+                features: vec![CBCAFeature::Compression, CBCAFeature::Subscriptions, CBCAFeature::Heartbeats],
+                tls: None,
+                limits: CBCALimits::default(),
+                unix_mode: 0o660,
+                connections: Arc::new(Semaphore::new(CBCA_MAX_CONNECTIONS)),
+                max_connections: CBCA_MAX_CONNECTIONS
             }
         )
     }
 
+    // Every listener speaks TLS from now on, plain TCP peers are refused.
+    pub fn enable_tls(
+        &mut self,
+        config: &CBCATlsServerConfig
+    ) -> Result<(), std::io::Error> {
+        self.tls = Some(config.build_acceptor()?);
+        Ok(())
+    }
+
+    // Serves WebSocket peers on `addr`, next to the TCP routines.
+    pub fn enable_gateway(
+        &mut self,
+        addr: CBCARoutineAddr
+    ) {
+        self.gateway = Some(CBCAGateway::spawn(addr, self.shared_queue.clone()));
+    }
+
+    // Serves Debug frames on `addr`, only to peers knowing `token`.
+    pub fn enable_admin(
+        &mut self,
+        addr: CBCARoutineAddr,
+        token: String
+    ) {
+        self.admin = Some(CBCAAdminAccess { addr, token });
+    }
+
+    pub fn set_limits(
+        &mut self,
+        limits: CBCALimits
+    ) {
+        self.limits = limits;
+    }
+
+    // Connections served at once, accepting waits past it.
+    pub fn set_max_connections(
+        &mut self,
+        max_connections: usize
+    ) {
+        self.max_connections = max_connections.max(1);
+        self.connections = Arc::new(Semaphore::new(self.max_connections));
+    }
+
+    // Budgets of messages, offers and instances per source IP and per author.
+    pub fn set_rate_limits(
+        &mut self,
+        limits: CBCARateLimits
+    ) {
+        self.shared_queue.get_limiter().set_limits(limits);
+    }
+
+    // Writes waiting at most and workers running them, set before the gateway is enabled
+    // since it holds a copy of the queue.
+    pub fn set_work_queue(
+        &mut self,
+        capacity: usize,
+        workers: usize
+    ) {
+        self.shared_queue.set_work_queue(CBCAWorkQueue::spawn(capacity, workers));
+    }
+
+    // Permissions given to the Unix sockets, only their owner and group may connect by default.
+    pub fn set_unix_mode(
+        &mut self,
+        unix_mode: u32
+    ) {
+        self.unix_mode = unix_mode;
+    }
+
+    pub async fn bind(
+        &self,
+        addr: &CBCARoutineAddr
+    ) -> Result<CBCAListener, std::io::Error> {
+        CBCAListener::bind(addr.get_endpoint(), self.unix_mode).await
+    }
+
+    pub async fn accept(
+        &self,
+        socket: CBCAIncoming
+    ) -> Option<CBCAStream> {
+        accept_peer(socket, self.tls.as_ref(), self.limits).await
+    }
+
+    // Serves until `shutdown`, then returns once the connections are done, the queued writes
+    // ran and the storage is flushed. The first routine failing, a port that can't be bound
+    // for one, ends every other one and is returned. The index is built before anything binds.
     pub async fn run_routines(
-        &self
+        self: &Arc<Self>
     ) -> Result<(), std::io::Error> {
-        let _ = tokio::join!(
-            biased;
-            self.routine_instance(),
-            self.routine_message(),
-            self.routine_offer(),
+        self.shared_queue.build_index().await?;
+
+        let accepting = async {
+            tokio::try_join!(
+                self.routine_instance(),
+                self.routine_message(),
+                self.routine_offer(),
+                self.routine_subscribe(),
+                self.routine_query(),
+                self.routine_admin(),
+                self.routine_gateway()
+            )?;
+
+            self.drain().await;
+            Ok::<(), std::io::Error>(())
+        };
+
+        tokio::try_join!(
+            accepting,
             self.shared_queue.routine()
-        );
+        )?;
 
         Ok(())
     }
 
+    // Stops accepting, every routine returns once its listener is closed.
+    pub fn shutdown(&self) {
+        self.shared_queue.get_shutdown().trigger();
+    }
+
+    // Connections still open answer their request, their writes go through the queue
+    // before it closes. Gateway connections hold permits of the same semaphore, so its
+    // requests are waited for too.
+    async fn drain(&self) {
+        let open: usize = self.max_connections - self.connections.available_permits();
+        log::info!("[SHUTDOWN] listeners closed, waiting for {} connections.", open);
+
+        let _ = self.connections.acquire_many(self.max_connections as u32).await;
+        self.shared_queue.close_work();
+    }
+
+    pub async fn handshake(
+        &self,
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>
+    ) -> Option<CBCAHelloAck> {
+        match handshake::server_handshake(stream, &self.features).await {
+            Ok(v) => Some(v),
+            Err(e) => {
+                log::warn!("[HANDSHAKE] peer refused, {:?}.", e);
+                None
+            }
+        }
+    }
+
     pub async fn handle_instance(
         &self, 
         raw_payload: String,
-        stream: Arc<tokio::sync::Mutex<tokio::net::TcpStream>>
-    ) -> Result<(), std::io::Error> {
-        let instance: IPayload = serde_json::from_str(&raw_payload)?;
-        let identifier: Result<String, std::io::Error>= self.shared_queue.handle_add_instance(instance).await;
-        
-        let response: CBCATcpPayload = CBCATcpPayload::spawn(
-            if let Ok(_) = identifier { 
-                CBCATcpPayloadType::Data
-            } else { 
-                CBCATcpPayloadType::Error
-            }, 
-            if let Ok(v) = identifier { 
-                v 
-            } else { 
-                "queue doesnt push an instance.".to_string() 
-            }
-        );
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>,
+        peer: Option<IpAddr>
+    ) -> Result<(), std::io::Error> {
+        let instance: IPayload = match Self::parse(&raw_payload) {
+            Ok(v) => v,
+            Err(e) => return Self::send_error(stream, &e).await
+        };
 
-        let lock_for_res: Arc<tokio::sync::Mutex<tokio::net::TcpStream>> = Arc::clone(&stream);
-        response.send(lock_for_res).await?;
+        if let Err(e) = self.shared_queue.admit(CBCARateKind::Instance, peer, None) {
+            return Self::send_error(stream, &e).await;
+        }
+
+        let response: CBCATcpPayload = match self.shared_queue.handle_add_instance(instance).await {
+            Ok(v) => CBCATcpPayload::spawn(CBCATcpPayloadType::Data, v),
+            Err(e) => CBCATcpPayload::spawn(
+                CBCATcpPayloadType::Error,
+                serde_json::to_string(&CBCAErrorPayload::from(&e))?
+            )
+        };
 
+        response.send(stream).await?;
         Ok(())
     }
 
+    // A body that does not parse is answered like any other refusal, the connection stays usable.
+    fn parse<T: DeserializeOwned>(raw_payload: &str) -> Result<T, CBCAErrorPayload> {
+        serde_json::from_str(raw_payload)
+            .map_err(|e| CBCAErrorPayload::spawn(CBCAErrorCode::BadRequest, e.to_string()))
+    }
+
+    async fn send_error(
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>,
+        error: &CBCAErrorPayload
+    ) -> Result<(), std::io::Error> {
+        CBCATcpPayload::spawn(CBCATcpPayloadType::Error, serde_json::to_string(error)?)
+            .send(stream)
+            .await
+    }
+
+    // Version 1 peers only understand "true" and "false".
+    fn append_response(
+        version: u16,
+        pushing: Result<CBCAReceipt, CBCAErrorPayload>
+    ) -> Result<CBCATcpPayload, std::io::Error> {
+        Ok(
+            match (pushing, version) {
+                (Ok(_), 1) => CBCATcpPayload::spawn(CBCATcpPayloadType::Data, "true".to_string()),
+                (Err(_), 1) => CBCATcpPayload::spawn(CBCATcpPayloadType::Error, "false".to_string()),
+                (Ok(v), _) => CBCATcpPayload::spawn(
+                    CBCATcpPayloadType::Data, 
+                    serde_json::to_string(&v)?
+                ),
+                (Err(e), _) => CBCATcpPayload::spawn(
+                    CBCATcpPayloadType::Error, 
+                    serde_json::to_string(&e)?
+                )
+            }
+        )
+    }
+
     pub async fn handle_message(
         &self, 
         raw_payload: String,
-        stream: Arc<tokio::sync::Mutex<tokio::net::TcpStream>>
-    ) -> Result<(), std::io::Error> {
-        let message: MPayload = serde_json::from_str(&raw_payload)?;
-        let pushing = self.shared_queue.handle_add_message(message).await;
-
-        let response: CBCATcpPayload = CBCATcpPayload::spawn(
-            if let Ok(_) = pushing { 
-                CBCATcpPayloadType::Data
-            } else { 
-                CBCATcpPayloadType::Error
-            }, 
-            if let Ok(_) = pushing { 
-                "true".to_string()
-            } else { 
-                "false".to_string()
-            }
-        );
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>,
+        version: u16,
+        peer: Option<IpAddr>
+    ) -> Result<(), std::io::Error> {
+        let pushing: Result<CBCAReceipt, CBCAErrorPayload> = match Self::parse::<MPayload>(&raw_payload) {
+            Ok(message) => match self.shared_queue.admit(CBCARateKind::Message, peer, Some(&message.author)) {
+                Ok(_) => self.shared_queue.handle_add_message(message).await.map_err(|e| CBCAErrorPayload::from(&e)),
+                Err(e) => Err(e)
+            },
+            Err(e) => Err(e)
+        };
+
+        let response: CBCATcpPayload = Self::append_response(version, pushing)?;
 
-        let lock_for_res: Arc<tokio::sync::Mutex<tokio::net::TcpStream>> = Arc::clone(&stream);
+        let lock_for_res: Arc<tokio::sync::Mutex<CBCAStream>> = Arc::clone(&stream);
         response.send(lock_for_res).await?;
 
         Ok(())
@@ -117,111 +392,469 @@ impl CBCAServer {
     pub async fn handle_offer(
         &self, 
         raw_payload: String,
-        stream: Arc<tokio::sync::Mutex<tokio::net::TcpStream>>
-    ) -> Result<(), std::io::Error> {
-        let offer: OPayload = serde_json::from_str(&raw_payload)?;
-        let pushing: Result<(), std::io::Error> = self.shared_queue.handle_add_offer(offer).await;
-
-        let response: CBCATcpPayload = CBCATcpPayload::spawn(
-            if let Ok(_) = pushing { 
-                CBCATcpPayloadType::Data
-            } else { 
-                CBCATcpPayloadType::Error
-            }, 
-            if let Ok(_) = pushing { 
-                "true".to_string()
-            } else { 
-                "false".to_string()
-            }
-        );
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>,
+        version: u16,
+        peer: Option<IpAddr>
+    ) -> Result<(), std::io::Error> {
+        let pushing: Result<CBCAReceipt, CBCAErrorPayload> = match Self::parse::<OPayload>(&raw_payload) {
+            Ok(offer) => match self.shared_queue.admit(CBCARateKind::Offer, peer, Some(&offer.author)) {
+                Ok(_) => self.shared_queue.handle_add_offer(offer).await.map_err(|e| CBCAErrorPayload::from(&e)),
+                Err(e) => Err(e)
+            },
+            Err(e) => Err(e)
+        };
+
+        let response: CBCATcpPayload = Self::append_response(version, pushing)?;
 
-        let lock_for_res: Arc<tokio::sync::Mutex<tokio::net::TcpStream>> = Arc::clone(&stream);
+        let lock_for_res: Arc<tokio::sync::Mutex<CBCAStream>> = Arc::clone(&stream);
         response.send(lock_for_res).await?;
         Ok(())
     }
 
-    pub async fn routine_instance(
-        &self
+    // Answers with the requested data, or an Error frame carrying a CBCAErrorPayload.
+    // Private instances are only shown to the author named by the client certificate.
+    pub async fn handle_query(
+        &self,
+        raw_payload: String,
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>
     ) -> Result<(), std::io::Error> {
-        let listener: tokio::net::TcpListener = tokio::net::TcpListener::bind(self.addr_instance.get_full_addr()).await?;
-        println!("[INSTANCE] on {}.", self.addr_instance.get_full_addr());
+        let requester: Option<String> = stream.lock().await.get_peer_name();
+        let answer: Result<serde_json::Value, std::io::Error> = match serde_json::from_str::<QPayload>(&raw_payload) {
+            Ok(v) => self.shared_queue.handle_query(v, requester).await,
+            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))
+        };
+
+        let response: CBCATcpPayload = match answer {
+            Ok(v) => CBCATcpPayload::spawn(CBCATcpPayloadType::Data, serde_json::to_string(&v)?),
+            Err(e) => CBCATcpPayload::spawn(
+                CBCATcpPayloadType::Error,
+                serde_json::to_string(&CBCAErrorPayload::from(&e))?
+            )
+        };
+
+        response.send(stream).await?;
+        Ok(())
+    }
 
-        loop {
-            let (socket, _) = listener.accept().await?;
-            let shared_stream_original = Arc::new(
-                tokio::sync::Mutex::new(socket)
-            );
+    pub async fn handle_debug(
+        &self,
+        raw_payload: String,
+        token: &str,
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>
+    ) -> Result<(), std::io::Error> {
+        let answer: Result<serde_json::Value, std::io::Error> = match serde_json::from_str::<DPayload>(&raw_payload) {
+            Ok(v) if token_matches(token, &v.token) => {
+                log::info!("[ADMIN] {:?}.", v.command);
+                self.shared_queue.handle_debug(v.command).await
+            },
+            Ok(_) => Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "invalid admin token.")),
+            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))
+        };
+
+        let response: CBCATcpPayload = match answer {
+            Ok(v) => CBCATcpPayload::spawn(CBCATcpPayloadType::Debug, serde_json::to_string(&v)?),
+            Err(e) => CBCATcpPayload::spawn(
+                CBCATcpPayloadType::Error,
+                serde_json::to_string(&CBCAErrorPayload::from(&e))?
+            )
+        };
+
+        response.send(stream).await?;
+        Ok(())
+    }
+
+    pub async fn handle_subscribe(
+        &self,
+        raw_payload: String,
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>,
+        heartbeat: Option<Duration>,
+        connection: CBCAConnectionGuard
+    ) -> Result<(), std::io::Error> {
+        let subscription: SPayload = match Self::parse(&raw_payload) {
+            Ok(v) => v,
+            Err(e) => return Self::send_error(stream, &e).await
+        };
+
+        // Only instances the server issued, and the peer may see, can be followed.
+        let requester: Option<String> = stream.lock().await.get_peer_name();
+        if let Err(e) = self.shared_queue.handle_summary(&subscription.instance_id, requester.as_deref()).await {
+            return Self::send_error(stream, &CBCAErrorPayload::from(&e)).await;
+        }
+
+        let receiver: broadcast::Receiver<CBCAEvent> = self.shared_queue.get_events().subscribe();
+
+        CBCATcpPayload::spawn(CBCATcpPayloadType::Data, "true".to_string())
+            .send(Arc::clone(&stream))
+            .await?;
+
+        // The connection stays open, it must not hold the accept loop.
+        let shutdown: CBCAShutdown = self.shared_queue.get_shutdown().clone();
+        tokio::spawn(Self::stream_events(stream, subscription.instance_id, receiver, heartbeat, shutdown, connection));
+
+        Ok(())
+    }
 
-            let shared_stream_response: Arc<tokio::sync::Mutex<tokio::net::TcpStream>> = 
-                Arc::clone(&shared_stream_original);
-            let shared_stream_listener: Arc<tokio::sync::Mutex<tokio::net::TcpStream>> =  
-                Arc::clone(&shared_stream_original);
+    async fn stream_events(
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>,
+        instance_id: String,
+        mut receiver: broadcast::Receiver<CBCAEvent>,
+        heartbeat: Option<Duration>,
+        shutdown: CBCAShutdown,
+        _connection: CBCAConnectionGuard
+    ) {
+        loop {
+            // Without heartbeats negotiated, the peer only hears from us on events.
+            let waiting = async {
+                match heartbeat {
+                    Some(v) => tokio::time::timeout(v, receiver.recv()).await.ok(),
+                    None => Some(receiver.recv().await)
+                }
+            };
+
+            // The subscriber is dropped at shutdown, the connection closing tells it.
+            let received = match tokio::select! {
+                biased;
+                _ = shutdown.wait() => break,
+                v = waiting => v
+            } {
+                Some(r) => r,
+                None => {
+                    let beat: CBCATcpPayload = CBCATcpPayload::spawn(CBCATcpPayloadType::Heartbeat, String::new());
+                    if beat.send(Arc::clone(&stream)).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            let event: CBCAEvent = match received {
+                Ok(v) => v,
+                Err(RecvError::Lagged(n)) => {
+                    log::warn!("[SUBSCRIBE] subscriber lagged, {} events skipped.", n);
+                    continue;
+                },
+                Err(RecvError::Closed) => break
+            };
+
+            if event.get_instance_id() != instance_id {
+                continue;
+            }
 
-            let req: Result<String, communication::CBCATcpError> = 
-                CBCATcpPayload::read(shared_stream_listener, CBCATcpPayloadType::Reqwest).await;
+            let serialized: String = match serde_json::to_string(&event) {
+                Ok(v) => v,
+                Err(_) => continue
+            };
 
-            if req.is_ok() {
-                self.handle_instance(req.unwrap().to_string(), shared_stream_response).await?;
-                println!("instance ok.");
-            } else {
-                println!("instance error.");
+            if CBCATcpPayload::spawn(CBCATcpPayloadType::Data, serialized)
+                .send(Arc::clone(&stream))
+                .await
+                .is_err() {
+                    break;
             }
         }
+
+        log::debug!("[SUBSCRIBE] {} subscriber gone.", instance_id);
     }
 
-    pub async fn routine_offer(
+    // The gateway shares the TLS acceptor, the limits and the connection permits of the routines.
+    pub async fn routine_gateway(
         &self
     ) -> Result<(), std::io::Error> {
-        let listener: tokio::net::TcpListener = tokio::net::TcpListener::bind(self.addr_offer.get_full_addr()).await?;
-        println!("[OFFER] on {}.", self.addr_offer.get_full_addr());
+        match &self.gateway {
+            Some(v) => v.routine(self.tls.clone(), self.limits, Arc::clone(&self.connections)).await,
+            None => Ok(())
+        }
+    }
+
+    // Accepts peers of `routine` and serves each one in its own task, so a slow peer
+    // only holds its own connection. Past max_connections, the routine waits for one to
+    // end before accepting again and new peers queue up in the listen backlog.
+    async fn next(
+        &self,
+        listener: &CBCAListener
+    ) -> Result<(CBCAIncoming, OwnedSemaphorePermit), std::io::Error> {
+        let socket: CBCAIncoming = listener.accept().await?;
+        // Taken after accepting, idle routines would hold the permits otherwise.
+        let permit: OwnedSemaphorePermit = Arc::clone(&self.connections)
+            .acquire_owned()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        Ok((socket, permit))
+    }
+
+    async fn serve(
+        self: &Arc<Self>,
+        routine: CBCARoutine,
+        addr: &CBCARoutineAddr
+    ) -> Result<(), std::io::Error> {
+        let listener: CBCAListener = self.bind(addr).await.map_err(|e| std::io::Error::new(
+            e.kind(),
+            format!("[{}] can't listen on {}, {}", routine.get_tag(), addr.get_full_addr(), e)
+        ))?;
+        log::info!("[{}] on {}.", routine.get_tag(), addr.get_full_addr());
 
         loop {
-            let (socket, _) = listener.accept().await?;
-            let shared_stream_original = Arc::new(
-                tokio::sync::Mutex::new(socket)
-            );
+            // Waiting for a permit gives way to shutdown as well.
+            let (socket, permit) = tokio::select! {
+                biased;
+                _ = self.shared_queue.get_shutdown().wait() => break,
+                v = self.next(&listener) => v?
+            };
+            let connection: CBCAConnectionGuard = self.shared_queue.get_stats().connection().hold(permit);
+            let server: Arc<CBCAServer> = Arc::clone(self);
+
+            tokio::spawn(async move {
+                // A peer stalling the TLS handshake doesn't hold the accept loop either.
+                let stream: CBCAStream = match server.accept(socket).await {
+                    Some(v) => v,
+                    None => return
+                };
+
+                match routine {
+                    CBCARoutine::Instance => server.connection_instance(stream).await,
+                    CBCARoutine::Message => server.connection_message(stream).await,
+                    CBCARoutine::Offer => server.connection_offer(stream).await,
+                    CBCARoutine::Subscribe => server.connection_subscribe(stream, connection).await,
+                    CBCARoutine::Query => server.connection_query(stream).await,
+                    CBCARoutine::Admin => server.connection_admin(stream).await
+                }
+            });
+        }
 
-            let shared_stream_response: Arc<tokio::sync::Mutex<tokio::net::TcpStream>> = 
-                Arc::clone(&shared_stream_original);
-            let shared_stream_listener: Arc<tokio::sync::Mutex<tokio::net::TcpStream>> = 
-                Arc::clone(&shared_stream_original);
+        log::info!("[{}] closed.", routine.get_tag());
+        Ok(())
+    }
 
-            let req: Result<String, communication::CBCATcpError> = 
-                CBCATcpPayload::read(shared_stream_listener, CBCATcpPayloadType::Reqwest).await;
+    pub async fn routine_subscribe(
+        self: &Arc<Self>
+    ) -> Result<(), std::io::Error> {
+        self.serve(CBCARoutine::Subscribe, &self.addr_subscribe).await
+    }
 
-            if req.is_ok() {
-                self.handle_offer(req.unwrap(), shared_stream_response).await?;
-                println!("offer ok.");
-            } else {
-                println!("offer error.");
-            }
+    pub async fn routine_admin(
+        self: &Arc<Self>
+    ) -> Result<(), std::io::Error> {
+        match &self.admin {
+            Some(v) => self.serve(CBCARoutine::Admin, &v.addr).await,
+            None => Ok(())
         }
     }
 
-    pub async fn routine_message(&self) -> Result<(), std::io::Error> {
-        let listener: tokio::net::TcpListener = tokio::net::TcpListener::bind(self.addr_message.get_full_addr()).await?;
-        println!("[MESSAGE] on {}.", self.addr_message.get_full_addr());
+    pub async fn routine_query(
+        self: &Arc<Self>
+    ) -> Result<(), std::io::Error> {
+        self.serve(CBCARoutine::Query, &self.addr_query).await
+    }
 
-        loop {
-            let (socket, _) = listener.accept().await?;
-            let shared_stream_original = Arc::new(
-                tokio::sync::Mutex::new(socket)
-            );
+    pub async fn routine_instance(
+        self: &Arc<Self>
+    ) -> Result<(), std::io::Error> {
+        self.serve(CBCARoutine::Instance, &self.addr_instance).await
+    }
 
-            let shared_stream_response: Arc<tokio::sync::Mutex<tokio::net::TcpStream>> = 
-                Arc::clone(&shared_stream_original);
-            let shared_stream_listener: Arc<tokio::sync::Mutex<tokio::net::TcpStream>> = 
-                Arc::clone(&shared_stream_original);
+    pub async fn routine_offer(
+        self: &Arc<Self>
+    ) -> Result<(), std::io::Error> {
+        self.serve(CBCARoutine::Offer, &self.addr_offer).await
+    }
 
-            let req: Result<String, communication::CBCATcpError> = 
-                CBCATcpPayload::read(shared_stream_listener, CBCATcpPayloadType::Reqwest).await;
+    pub async fn routine_message(
+        self: &Arc<Self>
+    ) -> Result<(), std::io::Error> {
+        self.serve(CBCARoutine::Message, &self.addr_message).await
+    }
+
+    // The connection guard goes with the subscription, it stays open after this returns.
+    async fn connection_subscribe(
+        &self,
+        stream: CBCAStream,
+        connection: CBCAConnectionGuard
+    ) {
+        let shared_stream_original = Arc::new(
+            tokio::sync::Mutex::new(stream)
+        );
 
-            if req.is_ok() {
-                self.handle_message(req.unwrap(), shared_stream_response).await?;
-                println!("message ok.");
-            } else {
-                println!("message error.");
+        let ack: CBCAHelloAck = match self.handshake(Arc::clone(&shared_stream_original)).await {
+            Some(v) => v,
+            None => return
+        };
+
+        if !ack.has_feature(CBCAFeature::Subscriptions) {
+            let refusal: CBCAErrorPayload = CBCAErrorPayload::spawn(
+                CBCAErrorCode::Unsupported, 
+                "subscriptions weren't negotiated.".to_string()
+            );
+
+            if let Ok(v) = serde_json::to_string(&refusal) {
+                let _ = CBCATcpPayload::spawn(CBCATcpPayloadType::Error, v)
+                    .send(shared_stream_original)
+                    .await;
             }
+            return;
+        }
+
+        let heartbeat: Option<Duration> = ack
+            .has_feature(CBCAFeature::Heartbeats)
+            .then_some(self.limits.heartbeat_interval);
+
+        let req: Result<String, communication::CBCATcpError> = 
+            CBCATcpPayload::read(Arc::clone(&shared_stream_original), CBCATcpPayloadType::Reqwest).await;
+
+        match req {
+            Ok(v) => {
+                if let Err(e) = self.handle_subscribe(v, shared_stream_original, heartbeat, connection).await {
+                    log::error!("subscribe error, {}.", e);
+                }
+            },
+            Err(e) => CBCATcpPayload::refuse(shared_stream_original, &e).await
+        }
+    }
+
+    async fn connection_admin(
+        &self,
+        stream: CBCAStream
+    ) {
+        let admin: &CBCAAdminAccess = match &self.admin {
+            Some(v) => v,
+            None => return
+        };
+
+        let shared_stream_original = Arc::new(
+            tokio::sync::Mutex::new(stream)
+        );
+
+        if self.handshake(Arc::clone(&shared_stream_original)).await.is_none() {
+            return;
+        }
+
+        let req: Result<String, communication::CBCATcpError> = 
+            CBCATcpPayload::read(Arc::clone(&shared_stream_original), CBCATcpPayloadType::Debug).await;
+
+        match req {
+            Ok(v) => {
+                if let Err(e) = self.handle_debug(v, &admin.token, shared_stream_original).await {
+                    log::error!("admin error, {}.", e);
+                }
+            },
+            Err(e) => CBCATcpPayload::refuse(shared_stream_original, &e).await
         }
     }
-}
\ No newline at end of file
+
+    async fn connection_query(
+        &self,
+        stream: CBCAStream
+    ) {
+        let shared_stream_original = Arc::new(
+            tokio::sync::Mutex::new(stream)
+        );
+
+        if self.handshake(Arc::clone(&shared_stream_original)).await.is_none() {
+            return;
+        }
+
+        let req: Result<String, communication::CBCATcpError> = 
+            CBCATcpPayload::read(Arc::clone(&shared_stream_original), CBCATcpPayloadType::Reqwest).await;
+
+        match req {
+            Ok(v) => {
+                if let Err(e) = self.handle_query(v, shared_stream_original).await {
+                    log::error!("query error, {}.", e);
+                }
+            },
+            Err(e) => CBCATcpPayload::refuse(shared_stream_original, &e).await
+        }
+    }
+
+    async fn connection_instance(
+        &self,
+        stream: CBCAStream
+    ) {
+        let peer: Option<IpAddr> = stream.peer_addr().map(|v| v.ip());
+        let shared_stream_original = Arc::new(
+            tokio::sync::Mutex::new(stream)
+        );
+
+        if self.handshake(Arc::clone(&shared_stream_original)).await.is_none() {
+            return;
+        }
+
+        let shared_stream_response: Arc<tokio::sync::Mutex<CBCAStream>> = 
+            Arc::clone(&shared_stream_original);
+        let shared_stream_listener: Arc<tokio::sync::Mutex<CBCAStream>> =  
+            Arc::clone(&shared_stream_original);
+
+        let req: Result<String, communication::CBCATcpError> = 
+            CBCATcpPayload::read(shared_stream_listener, CBCATcpPayloadType::Reqwest).await;
+
+        match req {
+            Ok(v) => match self.handle_instance(v, shared_stream_response, peer).await {
+                Ok(_) => log::debug!("instance ok."),
+                Err(e) => log::error!("instance error, {}.", e)
+            },
+            Err(e) => CBCATcpPayload::refuse(shared_stream_response, &e).await
+        }
+    }
+
+    async fn connection_offer(
+        &self,
+        stream: CBCAStream
+    ) {
+        let peer: Option<IpAddr> = stream.peer_addr().map(|v| v.ip());
+        let shared_stream_original = Arc::new(
+            tokio::sync::Mutex::new(stream)
+        );
+
+        let ack: CBCAHelloAck = match self.handshake(Arc::clone(&shared_stream_original)).await {
+            Some(v) => v,
+            None => return
+        };
+
+        let shared_stream_response: Arc<tokio::sync::Mutex<CBCAStream>> = 
+            Arc::clone(&shared_stream_original);
+        let shared_stream_listener: Arc<tokio::sync::Mutex<CBCAStream>> = 
+            Arc::clone(&shared_stream_original);
+
+        let req: Result<String, communication::CBCATcpError> = 
+            CBCATcpPayload::read(shared_stream_listener, CBCATcpPayloadType::Reqwest).await;
+
+        match req {
+            Ok(v) => match self.handle_offer(v, shared_stream_response, ack.version, peer).await {
+                Ok(_) => log::debug!("offer ok."),
+                Err(e) => log::error!("offer error, {}.", e)
+            },
+            Err(e) => CBCATcpPayload::refuse(shared_stream_response, &e).await
+        }
+    }
+
+    async fn connection_message(
+        &self,
+        stream: CBCAStream
+    ) {
+        let peer: Option<IpAddr> = stream.peer_addr().map(|v| v.ip());
+        let shared_stream_original = Arc::new(
+            tokio::sync::Mutex::new(stream)
+        );
+
+        let ack: CBCAHelloAck = match self.handshake(Arc::clone(&shared_stream_original)).await {
+            Some(v) => v,
+            None => return
+        };
+
+        let shared_stream_response: Arc<tokio::sync::Mutex<CBCAStream>> = 
+            Arc::clone(&shared_stream_original);
+        let shared_stream_listener: Arc<tokio::sync::Mutex<CBCAStream>> = 
+            Arc::clone(&shared_stream_original);
+
+        let req: Result<String, communication::CBCATcpError> = 
+            CBCATcpPayload::read(shared_stream_listener, CBCATcpPayloadType::Reqwest).await;
+
+        match req {
+            Ok(v) => match self.handle_message(v, shared_stream_response, ack.version, peer).await {
+                Ok(_) => log::debug!("message ok."),
+                Err(e) => log::error!("message error, {}.", e)
+            },
+            Err(e) => CBCATcpPayload::refuse(shared_stream_response, &e).await
+        }
+    }
+}
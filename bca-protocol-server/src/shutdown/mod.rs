@@ -0,0 +1,43 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{signal::unix::{signal, SignalKind}, sync::watch};
+
+// How long the server drains before exiting anyway.
+pub const CBCA_SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
+
+// Set once and never reset, every copy sees it and every waiter wakes up.
+#[derive(Debug, Clone)]
+pub struct CBCAShutdown {
+    sender: Arc<watch::Sender<bool>>
+}
+
+impl CBCAShutdown {
+    pub fn spawn() -> Self {
+        Self { sender: Arc::new(watch::Sender::new(false)) }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    // Returns at once when already triggered.
+    pub async fn wait(&self) {
+        let mut receiver: watch::Receiver<bool> = self.sender.subscribe();
+        let _ = receiver.wait_for(|v| *v).await;
+    }
+}
+
+// The first SIGINT or SIGTERM, named.
+pub async fn wait_signal() -> Result<&'static str, std::io::Error> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = interrupt.recv() => Ok("SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM")
+    }
+}
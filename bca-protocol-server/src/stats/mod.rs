@@ -0,0 +1,124 @@
+use std::{
+    sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc},
+    time::Duration
+};
+
+use shared::debug::{CBCALockStats, CBCAQueueStats};
+use tokio::sync::OwnedSemaphorePermit;
+
+#[derive(Debug, Default)]
+struct CBCAStatsInner {
+    connections: AtomicUsize,
+    lock_waits: AtomicU64,
+    lock_wait_total_us: AtomicU64,
+    lock_wait_max_us: AtomicU64,
+    queue_submitted: AtomicU64,
+    queue_completed: AtomicU64,
+    queue_rejected: AtomicU64,
+    queue_wait_total_us: AtomicU64,
+    queue_wait_max_us: AtomicU64,
+    queue_run_total_us: AtomicU64,
+    queue_run_max_us: AtomicU64
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().min(u64::MAX as u128) as u64
+}
+
+// Counters read by the admin routine, cheap enough to update on every request.
+#[derive(Debug, Clone, Default)]
+pub struct CBCAStats {
+    inner: Arc<CBCAStatsInner>
+}
+
+// Counts a connection as open until it is dropped, its permit is given back then too.
+pub struct CBCAConnectionGuard {
+    stats: CBCAStats,
+    _permit: Option<OwnedSemaphorePermit>
+}
+
+impl CBCAConnectionGuard {
+    pub fn hold(
+        mut self,
+        permit: OwnedSemaphorePermit
+    ) -> Self {
+        self._permit = Some(permit);
+        self
+    }
+}
+
+impl Drop for CBCAConnectionGuard {
+    fn drop(&mut self) {
+        self.stats.inner.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl CBCAStats {
+    pub fn spawn() -> Self {
+        Self::default()
+    }
+
+    pub fn connection(&self) -> CBCAConnectionGuard {
+        self.inner.connections.fetch_add(1, Ordering::Relaxed);
+        CBCAConnectionGuard { stats: self.clone(), _permit: None }
+    }
+
+    pub fn get_open_connections(&self) -> usize {
+        self.inner.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn record_lock_wait(&self, wait: Duration) {
+        let micros: u64 = micros(wait);
+
+        self.inner.lock_waits.fetch_add(1, Ordering::Relaxed);
+        self.inner.lock_wait_total_us.fetch_add(micros, Ordering::Relaxed);
+        self.inner.lock_wait_max_us.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn get_lock_stats(&self) -> CBCALockStats {
+        CBCALockStats {
+            waits: self.inner.lock_waits.load(Ordering::Relaxed),
+            total_wait_us: self.inner.lock_wait_total_us.load(Ordering::Relaxed),
+            max_wait_us: self.inner.lock_wait_max_us.load(Ordering::Relaxed)
+        }
+    }
+
+    pub fn record_queue_submitted(&self) {
+        self.inner.queue_submitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_queue_rejected(&self) {
+        self.inner.queue_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // `wait` in the queue before a worker took the job, `run` from then to its reply.
+    pub fn record_queue_job(&self, wait: Duration, run: Duration) {
+        let (wait, run): (u64, u64) = (micros(wait), micros(run));
+
+        self.inner.queue_completed.fetch_add(1, Ordering::Relaxed);
+        self.inner.queue_wait_total_us.fetch_add(wait, Ordering::Relaxed);
+        self.inner.queue_wait_max_us.fetch_max(wait, Ordering::Relaxed);
+        self.inner.queue_run_total_us.fetch_add(run, Ordering::Relaxed);
+        self.inner.queue_run_max_us.fetch_max(run, Ordering::Relaxed);
+    }
+
+    pub fn get_queue_stats(
+        &self,
+        depth: usize,
+        capacity: usize,
+        workers: usize
+    ) -> CBCAQueueStats {
+        CBCAQueueStats {
+            depth,
+            capacity,
+            workers,
+            submitted: self.inner.queue_submitted.load(Ordering::Relaxed),
+            completed: self.inner.queue_completed.load(Ordering::Relaxed),
+            rejected: self.inner.queue_rejected.load(Ordering::Relaxed),
+            total_wait_us: self.inner.queue_wait_total_us.load(Ordering::Relaxed),
+            max_wait_us: self.inner.queue_wait_max_us.load(Ordering::Relaxed),
+            total_run_us: self.inner.queue_run_total_us.load(Ordering::Relaxed),
+            max_run_us: self.inner.queue_run_max_us.load(Ordering::Relaxed)
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use shared::{
+    block::CBCABlock,
+    fchain::{CBCAChain, CBCAChainKind, CBCAConfig},
+    payload::validate_instance_id,
+    request::{CBCAChainPage, CBCAInstanceSummary}
+};
+
+use crate::instance::{status_of, CBCAInstance, CBCAInstanceState};
+use super::{
+    invalid_data,
+    not_found,
+    segment::{CBCALogHead, CBCASegmentLog},
+    sync_dir,
+    write_atomic,
+    CBCARecoveryReport,
+    CBCAStorage
+};
+
+type CBCAHeadSlot = Arc<tokio::sync::Mutex<Option<CBCALogHead>>>;
+
+// Instances are written under this prefix then renamed, a crash never leaves half of one.
+const CBCA_CREATING_PREFIX: &str = ".creating.";
+// Where corrupt instances are moved at startup, next to a report of what was wrong.
+const CBCA_QUARANTINE_DIR: &str = ".quarantine";
+
+#[derive(Debug, serde::Serialize)]
+struct CBCAQuarantineReport<'a> {
+    instance_id: &'a str,
+    reason: String,
+    at: i64
+}
+
+// Says what couldn't be read, keeping the kind: only InvalidData means the files are corrupt.
+fn unreadable(what: String) -> impl FnOnce(std::io::Error) -> std::io::Error {
+    move |e| std::io::Error::new(e.kind(), format!("{} unreadable, {}", what, e))
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(
+    path: PathBuf
+) -> Result<T, std::io::Error> {
+    let buf: String = tokio::fs::read_to_string(path).await?;
+    Ok(serde_json::from_str(&buf)?)
+}
+
+// One directory per instance, the config and the state in JSON files, each chain in
+// append-only segments of one JSON block per line.
+#[derive(Debug)]
+pub struct CBCAJsonStorage {
+    current_path: PathBuf,
+    // Heads of the chains used since the start, recovered from disk the first time. Each
+    // has its own lock, held while a block is written, so chains are written in parallel.
+    heads: Arc<Mutex<HashMap<(String, CBCAChainKind), CBCAHeadSlot>>>
+}
+
+impl CBCAJsonStorage {
+    pub fn spawn(current_path: PathBuf) -> Self {
+        Self {
+            current_path,
+            heads: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    // Only identifiers the server issues map to a directory, checked before any path is built.
+    fn instance_path(&self, instance_id: &str) -> Result<PathBuf, std::io::Error> {
+        validate_instance_id(instance_id)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+        Ok(self.current_path.join(instance_id.replace("-", ".")))
+    }
+
+    async fn existing_instance_path(
+        &self,
+        instance_id: &str
+    ) -> Result<PathBuf, std::io::Error> {
+        let path: PathBuf = self.instance_path(instance_id)?;
+
+        if !tokio::fs::try_exists(&path).await? {
+            return Err(not_found(instance_id));
+        }
+
+        Ok(path)
+    }
+
+    // The state file only exists for instances created since it was introduced.
+    async fn read_state(
+        &self,
+        path: &Path
+    ) -> Result<Option<CBCAInstanceState>, std::io::Error> {
+        match read_json(path.join("s.bca.json")).await {
+            Ok(v) => Ok(Some(v)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    // Reads the config and the state, trims the chains cut by a crash and verifies them.
+    // Returns whether anything was trimmed. Files that don't hold what they should are
+    // InvalidData, any other error is the disk's and keeps its kind.
+    async fn check(
+        &self,
+        path: &Path,
+        instance_id: &str
+    ) -> Result<bool, std::io::Error> {
+        match read_json::<CBCAConfig>(path.join("c.bca.json")).await {
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(invalid_data("config missing".to_string())),
+            Err(e) => return Err(unreadable("config".to_string())(e))
+        }
+        self.read_state(path).await.map_err(unreadable("state".to_string()))?;
+
+        let mut repaired: bool = false;
+        for kind in [CBCAChainKind::Offers, CBCAChainKind::Messages] {
+            let log: CBCASegmentLog = CBCASegmentLog::spawn(path, kind);
+            let (head, trimmed): (CBCALogHead, usize) = log.recover_trimming().await
+                .map_err(unreadable(format!("{:?}", kind)))?;
+            let blocks: Vec<CBCABlock> = log.read(&head, 0, head.blocks).await
+                .map_err(unreadable(format!("{:?}", kind)))?;
+            let chain: CBCAChain = CBCAChain::restore(instance_id.to_string(), blocks);
+
+            if let Some(i) = chain.first_invalid() {
+                return Err(invalid_data(format!("{:?} block {} is invalid", kind, i)));
+            }
+
+            repaired |= trimmed > 0;
+            *self.slot(instance_id, kind).lock().await = Some(head);
+        }
+
+        Ok(repaired)
+    }
+
+    // Moves the instance out of the data directory, with the reason next to it.
+    async fn quarantine(
+        &self,
+        path: &Path,
+        instance_id: &str,
+        reason: String
+    ) -> Result<PathBuf, std::io::Error> {
+        let quarantine: PathBuf = self.current_path.join(CBCA_QUARANTINE_DIR);
+        tokio::fs::create_dir_all(&quarantine).await?;
+
+        let target: PathBuf = quarantine.join(format!(
+            "{}.{}", path.file_name().unwrap_or_default().to_string_lossy(), Utc::now().timestamp()
+        ));
+        tokio::fs::rename(path, &target).await?;
+        sync_dir(&self.current_path).await?;
+
+        self.heads().retain(|(v, _), _| v != instance_id);
+
+        let report: CBCAQuarantineReport = CBCAQuarantineReport { instance_id, reason, at: Utc::now().timestamp() };
+        write_atomic(&target.join("quarantine.json"), serde_json::to_string_pretty(&report)?.as_bytes()).await?;
+
+        Ok(target)
+    }
+
+    fn heads(&self) -> std::sync::MutexGuard<'_, HashMap<(String, CBCAChainKind), CBCAHeadSlot>> {
+        match self.heads.lock() {
+            Ok(v) => v,
+            Err(v) => v.into_inner()
+        }
+    }
+
+    fn slot(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind
+    ) -> CBCAHeadSlot {
+        Arc::clone(self.heads().entry((instance_id.to_string(), kind)).or_default())
+    }
+
+    async fn head(
+        &self,
+        log: &CBCASegmentLog,
+        instance_id: &str,
+        kind: CBCAChainKind
+    ) -> Result<CBCALogHead, std::io::Error> {
+        let slot: CBCAHeadSlot = self.slot(instance_id, kind);
+        let mut head = slot.lock().await;
+
+        match head.take() {
+            Some(v) => Ok(head.insert(v).clone()),
+            None => Ok(head.insert(log.recover().await?).clone())
+        }
+    }
+
+    async fn read_chain(
+        &self,
+        path: &Path,
+        instance_id: &str,
+        kind: CBCAChainKind
+    ) -> Result<CBCAChain, std::io::Error> {
+        let log: CBCASegmentLog = CBCASegmentLog::spawn(path, kind);
+        let head: CBCALogHead = self.head(&log, instance_id, kind).await?;
+
+        Ok(CBCAChain::restore(instance_id.to_string(), log.read(&head, 0, head.blocks).await?))
+    }
+}
+
+#[async_trait]
+impl CBCAStorage for CBCAJsonStorage {
+    fn get_name(&self) -> &'static str {
+        "json"
+    }
+
+    async fn create(
+        &self,
+        instance: &CBCAInstance
+    ) -> Result<(), std::io::Error> {
+        // Fails if the directory exists, an issued id is never reused.
+        let path: PathBuf = self.instance_path(&instance.identifier)?;
+        if tokio::fs::try_exists(&path).await? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("instance {} already exists.", instance.identifier)
+            ));
+        }
+
+        let staging: PathBuf = self.current_path.join(format!(
+            "{}{}", CBCA_CREATING_PREFIX, instance.identifier.replace("-", ".")
+        ));
+
+        log::debug!("[CREATE 1/3] {}", &staging.display().to_string());
+        tokio::fs::create_dir(&staging).await?;
+
+        // Chains start empty, their first segment comes with their first block.
+        let config_path: PathBuf = staging.join("c.bca.json");
+        log::debug!("[CREATE 2/3] {}", &config_path.display().to_string());
+        write_atomic(&config_path, serde_json::to_string(&instance.config)?.as_bytes()).await?;
+
+        if let Some(state) = &instance.state {
+            write_atomic(&staging.join("s.bca.json"), serde_json::to_string(state)?.as_bytes()).await?;
+        }
+
+        log::debug!("[CREATE 3/3] {}", &path.display().to_string());
+        tokio::fs::rename(&staging, &path).await?;
+        sync_dir(&self.current_path).await
+    }
+
+    async fn append_batch(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        blocks: Vec<CBCABlock>
+    ) -> Result<Vec<CBCABlock>, std::io::Error> {
+        let path: PathBuf = self.existing_instance_path(instance_id).await?;
+        let log: CBCASegmentLog = CBCASegmentLog::spawn(&path, kind);
+
+        let slot: CBCAHeadSlot = self.slot(instance_id, kind);
+        let mut guard = slot.lock().await;
+        let head: &mut CBCALogHead = match guard.take() {
+            Some(v) => guard.insert(v),
+            None => guard.insert(log.recover().await?)
+        };
+
+        let appended: Result<Vec<CBCABlock>, std::io::Error> = log.append_batch(head, blocks).await;
+        if appended.is_err() {
+            // The log cut the batch off, if that failed too the next append recovers the head first.
+            *guard = None;
+        }
+
+        appended
+    }
+
+    async fn read_range(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        from: usize,
+        limit: usize
+    ) -> Result<CBCAChainPage, std::io::Error> {
+        let path: PathBuf = self.existing_instance_path(instance_id).await?;
+        let log: CBCASegmentLog = CBCASegmentLog::spawn(&path, kind);
+        let head: CBCALogHead = self.head(&log, instance_id, kind).await?;
+
+        Ok(
+            CBCAChainPage {
+                instance_id: instance_id.to_string(),
+                chain: kind,
+                blocks: log.read(&head, from, limit).await?,
+                head_hash: head.hash,
+                total: head.blocks,
+                from
+            }
+        )
+    }
+
+    async fn position(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        hash: &str
+    ) -> Result<Option<usize>, std::io::Error> {
+        let path: PathBuf = self.existing_instance_path(instance_id).await?;
+        let log: CBCASegmentLog = CBCASegmentLog::spawn(&path, kind);
+        let head: CBCALogHead = self.head(&log, instance_id, kind).await?;
+
+        log.position(&head, hash).await
+    }
+
+    async fn load(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAInstance, std::io::Error> {
+        let path: PathBuf = self.existing_instance_path(instance_id).await?;
+
+        Ok(
+            CBCAInstance {
+                identifier: instance_id.to_string(),
+                offers_chain: self.read_chain(&path, instance_id, CBCAChainKind::Offers).await?,
+                messages_chain: self.read_chain(&path, instance_id, CBCAChainKind::Messages).await?,
+                config: read_json(path.join("c.bca.json")).await?,
+                started: false,
+                state: self.read_state(&path).await?
+            }
+        )
+    }
+
+    async fn summary(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAInstanceSummary, std::io::Error> {
+        let path: PathBuf = self.existing_instance_path(instance_id).await?;
+        let state: Option<CBCAInstanceState> = self.read_state(&path).await?;
+
+        Ok(
+            CBCAInstanceSummary {
+                instance_id: instance_id.to_string(),
+                config: read_json(path.join("c.bca.json")).await?,
+                status: status_of(&state),
+                closes_at: state.map(|v| v.closes_at)
+            }
+        )
+    }
+
+    async fn recover(&self) -> Result<CBCARecoveryReport, std::io::Error> {
+        let mut report: CBCARecoveryReport = CBCARecoveryReport::default();
+        let mut entries = tokio::fs::read_dir(&self.current_path).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let name: String = entry.file_name().to_string_lossy().to_string();
+
+            if name.starts_with(CBCA_CREATING_PREFIX) {
+                log::warn!("[RECOVERY] {} was never finished, removed.", entry.path().display());
+                tokio::fs::remove_dir_all(entry.path()).await?;
+                continue;
+            }
+
+            let identifier: String = name.replace(".", "-");
+            if !entry.file_type().await?.is_dir() || validate_instance_id(&identifier).is_err() {
+                continue;
+            }
+
+            report.checked += 1;
+            match self.check(&entry.path(), &identifier).await {
+                Ok(true) => report.repaired += 1,
+                Ok(false) => {},
+                // Only a corrupt instance is moved away, an instance the disk failed to read
+                // may be fine and startup stops instead.
+                Err(e) if e.kind() != std::io::ErrorKind::InvalidData => {
+                    log::error!("[RECOVERY] {} couldn't be checked, {}.", identifier, e);
+                    return Err(e);
+                },
+                Err(e) => {
+                    let reason: String = e.to_string().trim_end_matches('.').to_string();
+                    let target: PathBuf = self.quarantine(&entry.path(), &identifier, reason.clone()).await?;
+                    log::error!("[RECOVERY] {} quarantined in {}, {}.", identifier, target.display(), reason);
+                    report.corrupt.push((identifier, reason));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn list(&self) -> Result<Vec<String>, std::io::Error> {
+        let mut identifiers: Vec<String> = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.current_path).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            // Hidden entries are instances being created and the quarantine.
+            if !entry.file_type().await?.is_dir() || entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            let identifier: String = entry.file_name().to_string_lossy().replace(".", "-");
+            match validate_instance_id(&identifier) {
+                Ok(_) => identifiers.push(identifier),
+                Err(_) => log::error!("[E] {} isn't an instance, skipped.", entry.path().display())
+            }
+        }
+
+        identifiers.sort();
+        Ok(identifiers)
+    }
+
+    // Checkpoints every head used since the start, the next start reads no block again.
+    async fn flush(&self) -> Result<(), std::io::Error> {
+        let slots: Vec<((String, CBCAChainKind), CBCAHeadSlot)> = self.heads()
+            .iter()
+            .map(|(k, v)| (k.clone(), Arc::clone(v)))
+            .collect();
+
+        for ((instance_id, kind), slot) in slots {
+            // Waits for an append still writing, a head unknown after a failure is left alone.
+            let head = slot.lock().await;
+            if let Some(v) = head.as_ref() {
+                let path: PathBuf = self.existing_instance_path(&instance_id).await?;
+                CBCASegmentLog::spawn(&path, kind).write_checkpoint(v).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use shared::payload::issue_instance_id;
+
+    use super::*;
+
+    // A storage in a directory of its own with one instance holding three messages.
+    async fn stored() -> (PathBuf, String) {
+        let dir: PathBuf = std::env::temp_dir().join(format!("cbca-json-{}", issue_instance_id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let storage: CBCAJsonStorage = CBCAJsonStorage::spawn(dir.clone());
+
+        let config: CBCAConfig = CBCAConfig::spawn(
+            None, false, None, 300, "d".to_string(), "n".to_string(), "EUR".to_string()
+        ).unwrap();
+        let instance: CBCAInstance = CBCAInstance::spawn(config, &issue_instance_id());
+        storage.create(&instance).await.unwrap();
+
+        let blocks: Vec<CBCABlock> = (0..3)
+            .map(|v| CBCABlock::block_creator_message(format!("m{}", v), "a".to_string(), instance.identifier.clone()))
+            .collect();
+        storage.append_batch(&instance.identifier, CBCAChainKind::Messages, blocks).await.unwrap();
+
+        (dir, instance.identifier)
+    }
+
+    fn write_segment(dir: &Path, instance_id: &str, bytes: &[u8]) {
+        let path: PathBuf = dir.join(instance_id.replace("-", ".")).join("m.000000.bca.log");
+        std::fs::OpenOptions::new().append(true).open(path).unwrap().write_all(bytes).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recover_trims_a_cut_tail() {
+        let (dir, instance_id) = stored().await;
+        write_segment(&dir, &instance_id, b"{\"hash\":");
+
+        let storage: CBCAJsonStorage = CBCAJsonStorage::spawn(dir.clone());
+        let report: CBCARecoveryReport = storage.recover().await.unwrap();
+        assert_eq!((report.checked, report.repaired, report.corrupt.len()), (1, 1, 0));
+        assert_eq!(storage.load(&instance_id).await.unwrap().messages_chain.len(), 3);
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn recover_quarantines_a_corrupt_block() {
+        let (dir, instance_id) = stored().await;
+        write_segment(&dir, &instance_id, b"not a block\n");
+
+        let storage: CBCAJsonStorage = CBCAJsonStorage::spawn(dir.clone());
+        let report: CBCARecoveryReport = storage.recover().await.unwrap();
+        assert_eq!((report.checked, report.repaired), (1, 0));
+        assert_eq!(report.corrupt.len(), 1);
+        assert!(storage.list().await.unwrap().is_empty());
+
+        let mut quarantined = tokio::fs::read_dir(dir.join(CBCA_QUARANTINE_DIR)).await.unwrap();
+        let entry = quarantined.next_entry().await.unwrap().unwrap();
+        assert!(entry.path().join("quarantine.json").exists());
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn recover_stops_on_a_disk_error() {
+        let (dir, instance_id) = stored().await;
+        let config: PathBuf = dir.join(instance_id.replace("-", ".")).join("c.bca.json");
+        tokio::fs::remove_file(&config).await.unwrap();
+        tokio::fs::create_dir(&config).await.unwrap();
+
+        let storage: CBCAJsonStorage = CBCAJsonStorage::spawn(dir.clone());
+        let error: std::io::Error = storage.recover().await.unwrap_err();
+        assert_ne!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(storage.list().await.unwrap(), vec![instance_id]);
+        assert!(!dir.join(CBCA_QUARANTINE_DIR).exists());
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
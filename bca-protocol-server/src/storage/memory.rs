@@ -0,0 +1,126 @@
+use std::{collections::HashMap, sync::{Arc, Mutex, MutexGuard}};
+
+use async_trait::async_trait;
+use shared::{
+    block::CBCABlock,
+    fchain::{CBCAChain, CBCAChainKind},
+    request::{CBCAChainPage, CBCAInstanceSummary}
+};
+
+use crate::instance::{status_of, CBCAInstance};
+use super::{not_found, CBCAStorage};
+
+// Instances live as long as the process, for tests and throwaway servers.
+#[derive(Debug, Clone, Default)]
+pub struct CBCAMemoryStorage {
+    instances: Arc<Mutex<HashMap<String, CBCAInstance>>>
+}
+
+impl CBCAMemoryStorage {
+    fn instances(&self) -> MutexGuard<'_, HashMap<String, CBCAInstance>> {
+        match self.instances.lock() {
+            Ok(v) => v,
+            Err(v) => v.into_inner()
+        }
+    }
+
+    fn with_instance<T>(
+        &self,
+        instance_id: &str,
+        read: impl FnOnce(&CBCAInstance) -> T
+    ) -> Result<T, std::io::Error> {
+        self.instances()
+            .get(instance_id)
+            .map(read)
+            .ok_or(not_found(instance_id))
+    }
+}
+
+#[async_trait]
+impl CBCAStorage for CBCAMemoryStorage {
+    fn get_name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn create(
+        &self,
+        instance: &CBCAInstance
+    ) -> Result<(), std::io::Error> {
+        let mut instances = self.instances();
+
+        if instances.contains_key(&instance.identifier) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("instance {} already exists.", instance.identifier)
+            ));
+        }
+
+        instances.insert(instance.identifier.clone(), instance.clone());
+        Ok(())
+    }
+
+    async fn append_batch(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        blocks: Vec<CBCABlock>
+    ) -> Result<Vec<CBCABlock>, std::io::Error> {
+        let mut instances = self.instances();
+        let instance: &mut CBCAInstance = instances.get_mut(instance_id).ok_or(not_found(instance_id))?;
+        let chain: &mut CBCAChain = match kind {
+            CBCAChainKind::Offers => &mut instance.offers_chain,
+            CBCAChainKind::Messages => &mut instance.messages_chain
+        };
+
+        let count: usize = blocks.len();
+        for block in blocks {
+            chain.push(block)?;
+        }
+
+        Ok(chain.blocks()[chain.len() - count..].to_vec())
+    }
+
+    async fn read_range(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        from: usize,
+        limit: usize
+    ) -> Result<CBCAChainPage, std::io::Error> {
+        self.with_instance(instance_id, |v| v.page(kind, from, limit))
+    }
+
+    async fn position(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        hash: &str
+    ) -> Result<Option<usize>, std::io::Error> {
+        self.with_instance(instance_id, |v| v.get_chain(kind).position(hash))
+    }
+
+    async fn load(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAInstance, std::io::Error> {
+        self.with_instance(instance_id, |v| v.clone())
+    }
+
+    async fn summary(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAInstanceSummary, std::io::Error> {
+        self.with_instance(instance_id, |v| CBCAInstanceSummary {
+            instance_id: instance_id.to_string(),
+            config: v.config.clone(),
+            status: status_of(&v.state),
+            closes_at: v.state.map(|s| s.closes_at)
+        })
+    }
+
+    async fn list(&self) -> Result<Vec<String>, std::io::Error> {
+        let mut identifiers: Vec<String> = self.instances().keys().cloned().collect();
+        identifiers.sort();
+        Ok(identifiers)
+    }
+}
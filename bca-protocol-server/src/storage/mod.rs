@@ -0,0 +1,296 @@
+mod json;
+mod memory;
+mod segment;
+mod sqlite;
+
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+use shared::{
+    block::CBCABlock,
+    fchain::CBCAChainKind,
+    request::{CBCAChainPage, CBCAInstanceSummary}
+};
+
+use crate::instance::CBCAInstance;
+
+pub use json::CBCAJsonStorage;
+pub use memory::CBCAMemoryStorage;
+pub use sqlite::CBCASqliteStorage;
+
+pub fn not_found(instance_id: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("instance {} not found.", instance_id)
+    )
+}
+
+pub fn invalid_data(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+// Entries created or renamed in `dir` survive a crash once it's synced.
+pub async fn sync_dir(dir: &Path) -> Result<(), std::io::Error> {
+    tokio::fs::File::open(dir).await?.sync_all().await
+}
+
+// Writes next to `path` then renames over it, readers see the old file or the new one.
+pub async fn write_atomic(
+    path: &Path,
+    content: &[u8]
+) -> Result<(), std::io::Error> {
+    let temporary: PathBuf = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&temporary).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temporary, path).await?;
+
+    match path.parent() {
+        Some(v) => sync_dir(v).await,
+        None => Ok(())
+    }
+}
+
+// What the startup check found, corrupt instances are left out of the storage when it can.
+#[derive(Debug, Default)]
+pub struct CBCARecoveryReport {
+    pub checked: usize,
+    pub repaired: usize,
+    pub corrupt: Vec<(String, String)>
+}
+
+// Where instances and their chains are kept. Appends to an instance come one at a time from
+// its actor, calls for different instances can run at the same time.
+#[async_trait]
+pub trait CBCAStorage: std::fmt::Debug + Send + Sync {
+    fn get_name(&self) -> &'static str;
+
+    // Fails if the instance already exists.
+    async fn create(
+        &self,
+        instance: &CBCAInstance
+    ) -> Result<(), std::io::Error>;
+
+    // Links `blocks` one after the other to the head of the chain, hashes them and stores them
+    // in one durable write, then returns them as stored, in the same order.
+    async fn append_batch(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        blocks: Vec<CBCABlock>
+    ) -> Result<Vec<CBCABlock>, std::io::Error>;
+
+    // At most `limit` blocks from index `from`, empty past the end.
+    async fn read_range(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        from: usize,
+        limit: usize
+    ) -> Result<CBCAChainPage, std::io::Error>;
+
+    // Length of the chain and hash of its last block, without reading any block.
+    async fn head(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind
+    ) -> Result<(usize, Option<String>), std::io::Error> {
+        let page: CBCAChainPage = self.read_range(instance_id, kind, 0, 0).await?;
+        Ok((page.total, page.head_hash))
+    }
+
+    // Index of the block hashed `hash`, None if the chain doesn't hold it.
+    async fn position(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        hash: &str
+    ) -> Result<Option<usize>, std::io::Error>;
+
+    // The whole instance, both chains included.
+    async fn load(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAInstance, std::io::Error>;
+
+    // Only the config and the state, chains aren't read.
+    async fn summary(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAInstanceSummary, std::io::Error>;
+
+    // Every stored instance, sorted.
+    async fn list(&self) -> Result<Vec<String>, std::io::Error>;
+
+    // Run once the last write is done, at shutdown, so the next start has nothing to redo.
+    async fn flush(&self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    // Run once before serving, checks every chain of every instance.
+    async fn recover(&self) -> Result<CBCARecoveryReport, std::io::Error> {
+        let mut report: CBCARecoveryReport = CBCARecoveryReport::default();
+
+        for identifier in self.list().await? {
+            report.checked += 1;
+
+            let verdict: Result<(), std::io::Error> = match self.load(&identifier).await {
+                Ok(v) => match (v.offers_chain.first_invalid(), v.messages_chain.first_invalid()) {
+                    (None, None) => Ok(()),
+                    (Some(i), _) => Err(invalid_data(format!("offers block {} is invalid.", i))),
+                    (_, Some(i)) => Err(invalid_data(format!("messages block {} is invalid.", i)))
+                },
+                Err(e) => Err(e)
+            };
+
+            if let Err(e) = verdict {
+                log::error!("[RECOVERY] {} is corrupt, {}.", identifier, e);
+                report.corrupt.push((identifier, e.to_string()));
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn read_after(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        hash: &str,
+        limit: usize
+    ) -> Result<Option<CBCAChainPage>, std::io::Error> {
+        match self.position(instance_id, kind, hash).await? {
+            Some(v) => Ok(Some(self.read_range(instance_id, kind, v + 1, limit).await?)),
+            None => Ok(None)
+        }
+    }
+}
+
+// Which backend the server stores instances in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CBCAStorageConfig {
+    Json(PathBuf),
+    Memory,
+    Sqlite(PathBuf)
+}
+
+impl CBCAStorageConfig {
+    // "json[:<dir>]", "memory" or "sqlite[:<file>]", without a path `data_dir` is used.
+    pub fn parse(
+        value: &str,
+        data_dir: &Path
+    ) -> Option<Self> {
+        match value.split_once(':') {
+            Some(("json", v)) if !v.is_empty() => Some(Self::Json(PathBuf::from(v))),
+            Some(("sqlite", v)) if !v.is_empty() => Some(Self::Sqlite(PathBuf::from(v))),
+            None if value == "json" => Some(Self::Json(data_dir.to_path_buf())),
+            None if value == "sqlite" => Some(Self::Sqlite(data_dir.join("cbca.sqlite"))),
+            None if value == "memory" => Some(Self::Memory),
+            _ => None
+        }
+    }
+
+    pub async fn open(&self) -> Result<Arc<dyn CBCAStorage>, std::io::Error> {
+        let storage: Arc<dyn CBCAStorage> = match self {
+            Self::Json(v) => {
+                tokio::fs::create_dir_all(v).await?;
+                Arc::new(CBCAJsonStorage::spawn(v.clone()))
+            },
+            Self::Memory => Arc::new(CBCAMemoryStorage::default()),
+            Self::Sqlite(v) => {
+                if let Some(parent) = v.parent().filter(|v| !v.as_os_str().is_empty()) {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                Arc::new(CBCASqliteStorage::open(v.clone()).await?)
+            }
+        };
+
+        match self {
+            Self::Json(v) | Self::Sqlite(v) => log::info!("[STORAGE] {} backend in {}.", storage.get_name(), v.display()),
+            Self::Memory => log::warn!("[STORAGE] memory backend, instances are lost on exit.")
+        }
+        Ok(storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::{fchain::{CBCAChain, CBCAConfig}, payload::issue_instance_id};
+
+    use super::*;
+
+    fn blocks(instance_id: &str, from: usize, count: usize) -> Vec<CBCABlock> {
+        (from..from + count)
+            .map(|v| CBCABlock::block_creator_message(format!("m{}", v), "a".to_string(), instance_id.to_string()))
+            .collect()
+    }
+
+    // What every backend must answer the same way, whatever it stores blocks in.
+    async fn conformance(storage: Arc<dyn CBCAStorage>) {
+        let name: &str = storage.get_name();
+        let config: CBCAConfig = CBCAConfig::spawn(
+            None, false, None, 300, "d".to_string(), "n".to_string(), "EUR".to_string()
+        ).unwrap();
+        let instance: CBCAInstance = CBCAInstance::spawn(config, &issue_instance_id());
+        let id: &str = &instance.identifier;
+        storage.create(&instance).await.unwrap();
+        let error: std::io::Error = storage.create(&instance).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::AlreadyExists, "{}", name);
+
+        let mut hashes: Vec<Option<String>> = Vec::new();
+        for (from, count) in [(0, 3), (3, 2)] {
+            let appended: Vec<CBCABlock> = storage.append_batch(id, CBCAChainKind::Messages, blocks(id, from, count))
+                .await
+                .unwrap();
+            assert_eq!(appended.len(), count, "{}", name);
+            hashes.extend(appended.iter().map(|v| v.get_hash()));
+        }
+
+        let page: CBCAChainPage = storage.read_range(id, CBCAChainKind::Messages, 0, 10).await.unwrap();
+        assert_eq!((page.total, page.from, page.head_hash.clone()), (5, 0, hashes[4].clone()), "{}", name);
+        assert_eq!(page.blocks.iter().map(|v| v.get_hash()).collect::<Vec<_>>(), hashes, "{}", name);
+        assert_eq!(CBCAChain::restore(id.to_string(), page.blocks).first_invalid(), None, "{}", name);
+
+        let page: CBCAChainPage = storage.read_range(id, CBCAChainKind::Messages, 3, 1).await.unwrap();
+        assert_eq!(page.blocks.iter().map(|v| v.get_hash()).collect::<Vec<_>>(), hashes[3..4], "{}", name);
+        assert!(storage.read_range(id, CBCAChainKind::Messages, 5, 10).await.unwrap().blocks.is_empty(), "{}", name);
+        assert_eq!(storage.read_range(id, CBCAChainKind::Offers, 0, 10).await.unwrap().total, 0, "{}", name);
+
+        let third: String = hashes[2].clone().unwrap();
+        assert_eq!(storage.position(id, CBCAChainKind::Messages, &third).await.unwrap(), Some(2), "{}", name);
+        assert_eq!(storage.position(id, CBCAChainKind::Offers, &third).await.unwrap(), None, "{}", name);
+
+        let after: CBCAChainPage = storage.read_after(id, CBCAChainKind::Messages, &third, 10).await.unwrap().unwrap();
+        assert_eq!(after.blocks.iter().map(|v| v.get_hash()).collect::<Vec<_>>(), hashes[3..], "{}", name);
+        assert!(storage.read_after(id, CBCAChainKind::Messages, "unknown", 10).await.unwrap().is_none(), "{}", name);
+
+        assert_eq!(storage.head(id, CBCAChainKind::Messages).await.unwrap(), (5, hashes[4].clone()), "{}", name);
+        assert_eq!(storage.load(id).await.unwrap().messages_chain.len(), 5, "{}", name);
+        assert_eq!(storage.list().await.unwrap(), vec![id.to_string()], "{}", name);
+
+        let missing: String = issue_instance_id();
+        let error: std::io::Error = storage.append_batch(&missing, CBCAChainKind::Offers, blocks(&missing, 0, 1))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound, "{}", name);
+        let error: std::io::Error = storage.read_range(&missing, CBCAChainKind::Offers, 0, 1).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound, "{}", name);
+    }
+
+    #[tokio::test]
+    async fn backends_behave_alike() {
+        let dir: PathBuf = std::env::temp_dir().join(format!("cbca-storage-{}", issue_instance_id()));
+
+        for config in [
+            CBCAStorageConfig::Memory,
+            CBCAStorageConfig::Json(dir.join("json")),
+            CBCAStorageConfig::Sqlite(dir.join("cbca.sqlite"))
+        ] {
+            tokio::fs::create_dir_all(&dir).await.unwrap();
+            conformance(config.open().await.unwrap()).await;
+        }
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
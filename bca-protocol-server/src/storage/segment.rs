@@ -0,0 +1,384 @@
+use std::path::{Path, PathBuf};
+
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use shared::{block::CBCABlock, fchain::{CBCAChain, CBCAChainKind}};
+
+use super::{invalid_data, sync_dir, write_atomic};
+
+// Blocks held by one segment file, block `i` of a chain lives in segment `i / CBCA_SEGMENT_BLOCKS`.
+pub const CBCA_SEGMENT_BLOCKS: usize = 1024;
+// A head checkpoint is written every this many blocks, recovery only reads the blocks after it.
+pub const CBCA_CHECKPOINT_EVERY: usize = 64;
+
+// Complete records of a segment, without their line feed. Only the last one can be cut.
+fn records(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes.split_inclusive(|v| *v == b'\n')
+        .filter_map(|v| v.strip_suffix(b"\n"))
+}
+
+// Where the next block of a chain goes and the hash it must point to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct CBCALogHead {
+    pub blocks: usize,
+    pub hash: Option<String>,
+    pub segment: usize,
+    pub offset: u64
+}
+
+impl CBCALogHead {
+    fn advance(
+        &mut self,
+        hash: Option<String>,
+        record_len: usize
+    ) {
+        self.blocks += 1;
+        self.hash = hash;
+        self.offset += record_len as u64;
+
+        if self.blocks.is_multiple_of(CBCA_SEGMENT_BLOCKS) {
+            self.segment += 1;
+            self.offset = 0;
+        }
+    }
+
+    // Blocks of `segment` this head covers.
+    fn blocks_in(&self, segment: usize) -> usize {
+        self.blocks.saturating_sub(segment * CBCA_SEGMENT_BLOCKS).min(CBCA_SEGMENT_BLOCKS)
+    }
+}
+
+// One chain of an instance directory, appended one JSON line per block, never rewritten.
+#[derive(Debug, Clone)]
+pub struct CBCASegmentLog {
+    dir: PathBuf,
+    prefix: &'static str
+}
+
+impl CBCASegmentLog {
+    pub fn spawn(
+        dir: &Path,
+        kind: CBCAChainKind
+    ) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            prefix: match kind {
+                CBCAChainKind::Offers => "o",
+                CBCAChainKind::Messages => "m"
+            }
+        }
+    }
+
+    fn segment_path(&self, segment: usize) -> PathBuf {
+        self.dir.join(format!("{}.{:06}.bca.log", self.prefix, segment))
+    }
+
+    fn checkpoint_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.bca.head", self.prefix))
+    }
+
+    // The whole chain file used before segments.
+    fn legacy_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.bca.json", self.prefix))
+    }
+
+    async fn read_checkpoint(&self) -> Result<Option<CBCALogHead>, std::io::Error> {
+        match tokio::fs::read(self.checkpoint_path()).await {
+            Ok(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub async fn write_checkpoint(
+        &self,
+        head: &CBCALogHead
+    ) -> Result<(), std::io::Error> {
+        write_atomic(&self.checkpoint_path(), serde_json::to_string(head)?.as_bytes()).await
+    }
+
+    async fn read_segment(&self, segment: usize) -> Result<Vec<u8>, std::io::Error> {
+        match tokio::fs::read(self.segment_path(segment)).await {
+            Ok(v) => Ok(v),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub async fn recover(&self) -> Result<CBCALogHead, std::io::Error> {
+        Ok(self.recover_trimming().await?.0)
+    }
+
+    // Starts from the last checkpoint and checks every block written after it. A record cut
+    // by a crash is trimmed, the bytes trimmed are returned with the head. A complete record
+    // that doesn't follow the chain is an error.
+    pub async fn recover_trimming(&self) -> Result<(CBCALogHead, usize), std::io::Error> {
+        let mut head: CBCALogHead = match self.read_checkpoint().await? {
+            Some(v) => v,
+            None => self.migrate().await?
+        };
+
+        loop {
+            let segment: usize = head.segment;
+            let bytes: Vec<u8> = self.read_segment(segment).await?;
+
+            if (bytes.len() as u64) < head.offset {
+                return Err(invalid_data(format!(
+                    "{} holds {} bytes, its checkpoint {}.",
+                    self.segment_path(segment).display(), bytes.len(), head.offset
+                )));
+            }
+
+            let mut rest: &[u8] = &bytes[head.offset as usize..];
+            while head.segment == segment && let Some(end) = rest.iter().position(|v| *v == b'\n') {
+                let block: CBCABlock = serde_json::from_slice(&rest[..end])
+                    .map_err(|e| invalid_data(format!("block {} unreadable, {}.", head.blocks, e)))?;
+
+                if !block.verify_hash() || block.previous_hash != head.hash {
+                    return Err(invalid_data(format!("block {} doesn't follow the chain.", head.blocks)));
+                }
+
+                head.advance(block.get_hash(), end + 1);
+                rest = &rest[end + 1..];
+            }
+
+            if head.segment != segment {
+                continue;
+            }
+
+            if !rest.is_empty() {
+                log::warn!("[LOG] {} cut after block {}, {} bytes trimmed.", self.segment_path(segment).display(), head.blocks, rest.len());
+                let file = OpenOptions::new().write(true).open(self.segment_path(segment)).await?;
+                file.set_len(head.offset).await?;
+                file.sync_all().await?;
+            }
+
+            return Ok((head, rest.len()));
+        }
+    }
+
+    // Chains still in a single JSON file are copied to segments once, then the file is removed.
+    // Without it, the chain was never checkpointed and recovery starts from the first block.
+    async fn migrate(&self) -> Result<CBCALogHead, std::io::Error> {
+        let mut head: CBCALogHead = CBCALogHead::default();
+
+        let buf: String = match tokio::fs::read_to_string(self.legacy_path()).await {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(head),
+            Err(e) => return Err(e)
+        };
+
+        // Segments left by a migration cut before its checkpoint are written again.
+        for segment in 0.. {
+            match tokio::fs::remove_file(self.segment_path(segment)).await {
+                Ok(_) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e)
+            }
+        }
+
+        let chain: CBCAChain = serde_json::from_str(&buf)?;
+        for block in chain.blocks() {
+            let record: Vec<u8> = self.record(block)?;
+            self.write_record(&head, &record).await?;
+            head.advance(block.get_hash(), record.len());
+        }
+
+        self.write_checkpoint(&head).await?;
+        tokio::fs::remove_file(self.legacy_path()).await?;
+        log::info!("[LOG] {} moved to segments, {} blocks.", self.legacy_path().display(), head.blocks);
+
+        Ok(head)
+    }
+
+    fn record(&self, block: &CBCABlock) -> Result<Vec<u8>, std::io::Error> {
+        let mut record: Vec<u8> = serde_json::to_vec(block)?;
+        record.push(b'\n');
+        Ok(record)
+    }
+
+    async fn write_record(
+        &self,
+        head: &CBCALogHead,
+        records: &[u8]
+    ) -> Result<(), std::io::Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.segment_path(head.segment))
+            .await?;
+
+        file.write_all(records).await?;
+        file.sync_data().await?;
+
+        // A new segment is only durable once the directory lists it.
+        if head.offset == 0 {
+            sync_dir(&self.dir).await?;
+        }
+
+        Ok(())
+    }
+
+    // Links `blocks` one after the other to `head` and writes them at the end of the current
+    // segment with a single sync, one more when the batch fills the segment. `head` only moves
+    // once every block is written, a failed batch is cut off the segments it reached, so the
+    // caller can retry without recovery finding half of it.
+    pub async fn append_batch(
+        &self,
+        head: &mut CBCALogHead,
+        blocks: Vec<CBCABlock>
+    ) -> Result<Vec<CBCABlock>, std::io::Error> {
+        let mut next: CBCALogHead = head.clone();
+        let mut appended: Vec<CBCABlock> = Vec::with_capacity(blocks.len());
+        // Each write starts where the previous one left its segment.
+        let mut writes: Vec<(CBCALogHead, Vec<u8>)> = vec![(next.clone(), Vec::new())];
+
+        for block in blocks {
+            let mut block: CBCABlock = block;
+            block.previous_hash = next.hash.clone();
+            block.hash_block()?;
+
+            let record: Vec<u8> = self.record(&block)?;
+            if let Some((_, records)) = writes.last_mut() {
+                records.extend_from_slice(&record);
+            }
+            next.advance(block.get_hash(), record.len());
+            appended.push(block);
+
+            if next.offset == 0 {
+                writes.push((next.clone(), Vec::new()));
+            }
+        }
+
+        for (i, (start, records)) in writes.iter().enumerate() {
+            if records.is_empty() {
+                continue;
+            }
+
+            if let Err(e) = self.write_record(start, records).await {
+                self.rollback(&writes[..=i]).await;
+                return Err(e);
+            }
+        }
+
+        let checkpoints: usize = head.blocks / CBCA_CHECKPOINT_EVERY;
+        *head = next;
+
+        // The blocks are durable already, recovery only reads more of them without it.
+        if head.blocks / CBCA_CHECKPOINT_EVERY != checkpoints && let Err(e) = self.write_checkpoint(head).await {
+            log::warn!("[LOG] {} checkpoint not written, {}.", self.checkpoint_path().display(), e);
+        }
+
+        Ok(appended)
+    }
+
+    // Cuts the segments a failed batch wrote to back to where it started.
+    async fn rollback(&self, writes: &[(CBCALogHead, Vec<u8>)]) {
+        for (start, _) in writes {
+            let path: PathBuf = self.segment_path(start.segment);
+            let cut = async {
+                let file = OpenOptions::new().write(true).open(&path).await?;
+                file.set_len(start.offset).await?;
+                file.sync_all().await
+            };
+
+            match cut.await {
+                Ok(_) => {},
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => log::error!("[LOG] {} not cut back to {}, {}.", path.display(), start.offset, e)
+            }
+        }
+    }
+
+    // At most `limit` blocks from index `from`, only reading the segments holding them.
+    pub async fn read(
+        &self,
+        head: &CBCALogHead,
+        from: usize,
+        limit: usize
+    ) -> Result<Vec<CBCABlock>, std::io::Error> {
+        let end: usize = from.saturating_add(limit).min(head.blocks);
+        let mut blocks: Vec<CBCABlock> = Vec::new();
+        let mut index: usize = from;
+
+        while index < end {
+            let segment: usize = index / CBCA_SEGMENT_BLOCKS;
+            let first: usize = segment * CBCA_SEGMENT_BLOCKS;
+            let expected: usize = head.blocks_in(segment);
+            let bytes: Vec<u8> = self.read_segment(segment).await?;
+            let lines: Vec<&[u8]> = records(&bytes).take(expected).collect();
+
+            if lines.len() < expected {
+                return Err(invalid_data(format!("{} is missing blocks.", self.segment_path(segment).display())));
+            }
+
+            let last: usize = (end - first).min(expected);
+            for line in &lines[index - first..last] {
+                blocks.push(serde_json::from_slice(line)?);
+            }
+
+            index = first + last;
+        }
+
+        Ok(blocks)
+    }
+
+    pub async fn position(
+        &self,
+        head: &CBCALogHead,
+        hash: &str
+    ) -> Result<Option<usize>, std::io::Error> {
+        for segment in 0..=head.segment {
+            let bytes: Vec<u8> = self.read_segment(segment).await?;
+
+            for (i, line) in records(&bytes).take(head.blocks_in(segment)).enumerate() {
+                let block: CBCABlock = serde_json::from_slice(line)?;
+                if block.get_hash().as_deref() == Some(hash) {
+                    return Ok(Some(segment * CBCA_SEGMENT_BLOCKS + i));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::payload::issue_instance_id;
+
+    use super::*;
+
+    fn blocks(count: usize) -> Vec<CBCABlock> {
+        (0..count)
+            .map(|v| CBCABlock::block_creator_message(format!("m{}", v), "a".to_string(), "i".to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn failed_batch_leaves_nothing_behind() {
+        let dir: PathBuf = std::env::temp_dir().join(format!("cbca-segment-{}", issue_instance_id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let log: CBCASegmentLog = CBCASegmentLog::spawn(&dir, CBCAChainKind::Messages);
+
+        let mut head: CBCALogHead = CBCALogHead::default();
+        log.append_batch(&mut head, blocks(CBCA_SEGMENT_BLOCKS - 1)).await.unwrap();
+        let before: CBCALogHead = head.clone();
+
+        // The next segment can't be opened, the second write of a batch crossing into it fails
+        // once the first one is on disk.
+        tokio::fs::create_dir(log.segment_path(1)).await.unwrap();
+        assert!(log.append_batch(&mut head, blocks(2)).await.is_err());
+
+        assert_eq!(head, before);
+        assert_eq!(tokio::fs::metadata(log.segment_path(0)).await.unwrap().len(), before.offset);
+        assert_eq!(log.recover_trimming().await.unwrap(), (before.clone(), 0));
+
+        // A retry appends the blocks once.
+        tokio::fs::remove_dir(log.segment_path(1)).await.unwrap();
+        log.append_batch(&mut head, blocks(2)).await.unwrap();
+        assert_eq!(head.blocks, CBCA_SEGMENT_BLOCKS + 1);
+        assert_eq!(log.recover().await.unwrap(), head);
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+}
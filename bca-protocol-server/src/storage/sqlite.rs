@@ -0,0 +1,375 @@
+use std::{path::PathBuf, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, ErrorCode, OpenFlags, OptionalExtension, Transaction};
+use shared::{
+    block::CBCABlock,
+    fchain::{CBCAChain, CBCAChainKind, CBCAConfig},
+    request::{CBCAChainPage, CBCAInstanceSummary}
+};
+
+use crate::instance::{status_of, CBCAInstance, CBCAInstanceState};
+use super::{not_found, CBCAStorage};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS instances (
+        id TEXT PRIMARY KEY,
+        config TEXT NOT NULL,
+        state TEXT
+    );
+    CREATE TABLE IF NOT EXISTS blocks (
+        instance_id TEXT NOT NULL REFERENCES instances (id),
+        chain TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        block TEXT NOT NULL,
+        PRIMARY KEY (instance_id, chain, position)
+    );
+    CREATE INDEX IF NOT EXISTS blocks_hash ON blocks (instance_id, chain, hash);
+";
+
+// Read-only connections next to the writer, WAL lets them read while it writes.
+const CBCA_SQLITE_READERS: usize = 4;
+
+fn sql_error(error: rusqlite::Error) -> std::io::Error {
+    std::io::Error::other(error)
+}
+
+fn chain_name(kind: CBCAChainKind) -> &'static str {
+    match kind {
+        CBCAChainKind::Offers => "offers",
+        CBCAChainKind::Messages => "messages"
+    }
+}
+
+fn ensure_exists(
+    connection: &Connection,
+    instance_id: &str
+) -> Result<(), std::io::Error> {
+    connection.query_row("SELECT 1 FROM instances WHERE id = ?1", params![instance_id], |_| Ok(()))
+        .optional()
+        .map_err(sql_error)?
+        .ok_or(not_found(instance_id))
+}
+
+fn read_blocks(
+    connection: &Connection,
+    instance_id: &str,
+    kind: CBCAChainKind,
+    from: usize,
+    limit: usize
+) -> Result<Vec<CBCABlock>, std::io::Error> {
+    let mut statement = connection.prepare_cached(
+        "SELECT block FROM blocks WHERE instance_id = ?1 AND chain = ?2 AND position >= ?3
+         ORDER BY position LIMIT ?4"
+    ).map_err(sql_error)?;
+
+    let rows = statement.query_map(
+        params![instance_id, chain_name(kind), from as i64, limit.min(i64::MAX as usize) as i64],
+        |row| row.get::<_, String>(0)
+    ).map_err(sql_error)?;
+
+    let mut blocks: Vec<CBCABlock> = Vec::new();
+    for row in rows {
+        blocks.push(serde_json::from_str(&row.map_err(sql_error)?)?);
+    }
+
+    Ok(blocks)
+}
+
+// Returns the number of blocks of the chain and the hash of the last one.
+fn read_head(
+    connection: &Connection,
+    instance_id: &str,
+    kind: CBCAChainKind
+) -> Result<(usize, Option<String>), std::io::Error> {
+    connection.query_row(
+        "SELECT COUNT(*), (SELECT hash FROM blocks WHERE instance_id = ?1 AND chain = ?2 ORDER BY position DESC LIMIT 1)
+         FROM blocks WHERE instance_id = ?1 AND chain = ?2",
+        params![instance_id, chain_name(kind)],
+        |row| Ok((row.get::<_, i64>(0)? as usize, row.get::<_, Option<String>>(1)?))
+    ).map_err(sql_error)
+}
+
+fn read_meta(
+    connection: &Connection,
+    instance_id: &str
+) -> Result<(CBCAConfig, Option<CBCAInstanceState>), std::io::Error> {
+    let (config, state): (String, Option<String>) = connection.query_row(
+        "SELECT config, state FROM instances WHERE id = ?1",
+        params![instance_id],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).optional().map_err(sql_error)?.ok_or(not_found(instance_id))?;
+
+    Ok((
+        serde_json::from_str(&config)?,
+        match state {
+            Some(v) => Some(serde_json::from_str(&v)?),
+            None => None
+        }
+    ))
+}
+
+// Every instance and every block in one SQLite file, blocks get a row each. Writes go through
+// one connection, SQLite commits one transaction at a time anyway. Reads are shared out between
+// read-only connections, so they run next to the writes and to each other.
+#[derive(Debug)]
+pub struct CBCASqliteStorage {
+    writer: Arc<Mutex<Connection>>,
+    readers: Vec<Arc<Mutex<Connection>>>,
+    turn: AtomicUsize
+}
+
+impl CBCASqliteStorage {
+    pub async fn open(path: PathBuf) -> Result<Self, std::io::Error> {
+        let (writer, readers) = tokio::task::spawn_blocking(move || -> Result<_, std::io::Error> {
+            let writer: Connection = Connection::open(&path).map_err(sql_error)?;
+            writer.execute_batch(SCHEMA).map_err(sql_error)?;
+
+            // Opened once the schema exists, they never create anything.
+            let flags: OpenFlags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+            let readers: Vec<Connection> = (0..CBCA_SQLITE_READERS)
+                .map(|_| Connection::open_with_flags(&path, flags).map_err(sql_error))
+                .collect::<Result<_, _>>()?;
+
+            Ok((writer, readers))
+        }).await.map_err(std::io::Error::other)??;
+
+        Ok(Self {
+            writer: Arc::new(Mutex::new(writer)),
+            readers: readers.into_iter().map(|v| Arc::new(Mutex::new(v))).collect(),
+            turn: AtomicUsize::new(0)
+        })
+    }
+
+    // SQLite blocks, calls are moved off the runtime threads.
+    async fn run_on<T, F>(
+        connection: Arc<Mutex<Connection>>,
+        job: F
+    ) -> Result<T, std::io::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, std::io::Error> + Send + 'static
+    {
+        tokio::task::spawn_blocking(move || {
+            let mut connection = match connection.lock() {
+                Ok(v) => v,
+                Err(v) => v.into_inner()
+            };
+            job(&mut connection)
+        }).await.map_err(std::io::Error::other)?
+    }
+
+    async fn run<T, F>(
+        &self,
+        job: F
+    ) -> Result<T, std::io::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, std::io::Error> + Send + 'static
+    {
+        Self::run_on(Arc::clone(&self.writer), job).await
+    }
+
+    // Runs `job` on the next reader, in one read transaction so all its statements see the
+    // same blocks even while an append commits.
+    async fn read<T, F>(
+        &self,
+        job: F
+    ) -> Result<T, std::io::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, std::io::Error> + Send + 'static
+    {
+        let turn: usize = self.turn.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+        let reader: Arc<Mutex<Connection>> = Arc::clone(&self.readers[turn]);
+
+        Self::run_on(reader, move |connection| {
+            let transaction: Transaction = connection.transaction().map_err(sql_error)?;
+            job(&transaction)
+        }).await
+    }
+}
+
+#[async_trait]
+impl CBCAStorage for CBCASqliteStorage {
+    fn get_name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    async fn create(
+        &self,
+        instance: &CBCAInstance
+    ) -> Result<(), std::io::Error> {
+        let identifier: String = instance.identifier.clone();
+        let config: String = serde_json::to_string(&instance.config)?;
+        let state: Option<String> = match &instance.state {
+            Some(v) => Some(serde_json::to_string(v)?),
+            None => None
+        };
+
+        self.run(move |connection| {
+            // The primary key refuses an id already stored.
+            match connection.execute(
+                "INSERT INTO instances (id, config, state) VALUES (?1, ?2, ?3)",
+                params![identifier, config, state]
+            ) {
+                Ok(_) => Ok(()),
+                Err(e) if e.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) => Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("instance {} already exists.", identifier)
+                )),
+                Err(e) => Err(sql_error(e))
+            }
+        }).await
+    }
+
+    async fn append_batch(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        blocks: Vec<CBCABlock>
+    ) -> Result<Vec<CBCABlock>, std::io::Error> {
+        let instance_id: String = instance_id.to_string();
+
+        self.run(move |connection| {
+            let transaction: Transaction = connection.transaction().map_err(sql_error)?;
+            ensure_exists(&transaction, &instance_id)?;
+
+            let (mut total, mut head_hash): (usize, Option<String>) = read_head(&transaction, &instance_id, kind)?;
+            let mut appended: Vec<CBCABlock> = Vec::with_capacity(blocks.len());
+
+            for block in blocks {
+                let mut block: CBCABlock = block;
+                block.previous_hash = head_hash;
+                let hash: String = block.hash_block()?;
+
+                transaction.execute(
+                    "INSERT INTO blocks (instance_id, chain, position, hash, block) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![instance_id, chain_name(kind), total as i64, hash, serde_json::to_string(&block)?]
+                ).map_err(sql_error)?;
+
+                total += 1;
+                head_hash = Some(hash);
+                appended.push(block);
+            }
+
+            // One commit, so one sync, for the whole batch.
+            transaction.commit().map_err(sql_error)?;
+
+            Ok(appended)
+        }).await
+    }
+
+    async fn read_range(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        from: usize,
+        limit: usize
+    ) -> Result<CBCAChainPage, std::io::Error> {
+        let instance_id: String = instance_id.to_string();
+
+        self.read(move |connection| {
+            ensure_exists(connection, &instance_id)?;
+            let (total, head_hash): (usize, Option<String>) = read_head(connection, &instance_id, kind)?;
+            let blocks: Vec<CBCABlock> = read_blocks(connection, &instance_id, kind, from, limit)?;
+
+            Ok(
+                CBCAChainPage {
+                    instance_id,
+                    chain: kind,
+                    head_hash,
+                    total,
+                    from,
+                    blocks
+                }
+            )
+        }).await
+    }
+
+    async fn position(
+        &self,
+        instance_id: &str,
+        kind: CBCAChainKind,
+        hash: &str
+    ) -> Result<Option<usize>, std::io::Error> {
+        let instance_id: String = instance_id.to_string();
+        let hash: String = hash.to_string();
+
+        self.read(move |connection| {
+            ensure_exists(connection, &instance_id)?;
+            let position: Option<i64> = connection.query_row(
+                "SELECT MIN(position) FROM blocks WHERE instance_id = ?1 AND chain = ?2 AND hash = ?3",
+                params![instance_id, chain_name(kind), hash],
+                |row| row.get(0)
+            ).map_err(sql_error)?;
+
+            Ok(position.map(|v| v as usize))
+        }).await
+    }
+
+    async fn load(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAInstance, std::io::Error> {
+        let instance_id: String = instance_id.to_string();
+
+        self.read(move |connection| {
+            let (config, state) = read_meta(connection, &instance_id)?;
+            let chain = |kind: CBCAChainKind| -> Result<CBCAChain, std::io::Error> {
+                Ok(CBCAChain::restore(
+                    instance_id.clone(),
+                    read_blocks(connection, &instance_id, kind, 0, usize::MAX)?
+                ))
+            };
+
+            Ok(
+                CBCAInstance {
+                    offers_chain: chain(CBCAChainKind::Offers)?,
+                    messages_chain: chain(CBCAChainKind::Messages)?,
+                    identifier: instance_id.clone(),
+                    config,
+                    started: false,
+                    state
+                }
+            )
+        }).await
+    }
+
+    async fn summary(
+        &self,
+        instance_id: &str
+    ) -> Result<CBCAInstanceSummary, std::io::Error> {
+        let instance_id: String = instance_id.to_string();
+
+        self.read(move |connection| {
+            let (config, state) = read_meta(connection, &instance_id)?;
+
+            Ok(
+                CBCAInstanceSummary {
+                    instance_id,
+                    config,
+                    status: status_of(&state),
+                    closes_at: state.map(|v| v.closes_at)
+                }
+            )
+        }).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, std::io::Error> {
+        self.read(|connection| {
+            let mut statement = connection.prepare_cached("SELECT id FROM instances ORDER BY id")
+                .map_err(sql_error)?;
+            let rows = statement.query_map([], |row| row.get::<_, String>(0)).map_err(sql_error)?;
+
+            rows.map(|v| v.map_err(sql_error)).collect()
+        }).await
+    }
+
+    // Moves the write-ahead log into the database file and empties it.
+    async fn flush(&self) -> Result<(), std::io::Error> {
+        self.run(|connection| connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);").map_err(sql_error)).await
+    }
+}
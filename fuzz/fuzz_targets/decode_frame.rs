@@ -0,0 +1,25 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::communication::CBCATcpPayload;
+
+fuzz_target!(|data: &[u8]| {
+    let mut rest: &[u8] = data;
+
+    // Frames read back to back, as the proxy does with a connection.
+    while let Ok(Some((frame, encoding, consumed))) = CBCATcpPayload::decode_frame(rest) {
+        assert!(consumed >= 8 && consumed <= rest.len());
+
+        // A decoded frame built again must decode to the same content.
+        if let Ok(mut rebuilt) = frame.build_frame(encoding) {
+            rebuilt.resize(rebuilt.len().div_ceil(8) * 8, 0);
+
+            match CBCATcpPayload::decode_frame(&rebuilt) {
+                Ok(Some((again, _, _))) => assert_eq!(again.get_content(), frame.get_content()),
+                other => panic!("rebuilt frame unreadable: {:?}", other)
+            }
+        }
+
+        rest = &rest[consumed..];
+    }
+});
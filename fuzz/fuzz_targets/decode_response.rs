@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::communication::CBCATcpPayload;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = CBCATcpPayload::decode_response(&data.to_vec());
+});
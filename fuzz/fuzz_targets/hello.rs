@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::handshake::{CBCAFeature, CBCAHello, CBCA_SUPPORTED_VERSIONS};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(v) = serde_json::from_slice::<CBCAHello>(data) {
+        let _ = v.negotiate(
+            &CBCA_SUPPORTED_VERSIONS,
+            &[CBCAFeature::Compression, CBCAFeature::Subscriptions, CBCAFeature::Heartbeats]
+        );
+    }
+});
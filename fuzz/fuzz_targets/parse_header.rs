@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::communication::CBCATcpPayload;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(header) = data.first_chunk::<8>() {
+        let _ = CBCATcpPayload::parse_header(header);
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::payload::DPayload;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(v) = serde_json::from_slice::<DPayload>(data) {
+        let _ = serde_json::to_string(&v);
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use shared::payload::MPayload;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(v) = serde_json::from_slice::<MPayload>(data) {
+        let _ = v.validate();
+        let _ = serde_json::to_string(&v);
+    }
+});
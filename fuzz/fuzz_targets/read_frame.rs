@@ -0,0 +1,41 @@
+#![no_main]
+
+use std::{sync::Arc, time::Duration};
+
+use libfuzzer_sys::fuzz_target;
+use shared::{
+    communication::{CBCATcpPayload, CBCATcpPayloadType},
+    limits::CBCALimits,
+    transport::{CBCAIncoming, CBCAStream}
+};
+use tokio::io::AsyncWriteExt;
+
+// The bytes are written by a peer that hangs up afterwards, so every read ends.
+fuzz_target!(|data: &[u8]| {
+    let runtime: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let (reader, mut writer) = tokio::net::UnixStream::pair().unwrap();
+        let data: Vec<u8> = data.to_vec();
+
+        tokio::spawn(async move {
+            let _ = writer.write_all(&data).await;
+            let _ = writer.shutdown().await;
+        });
+
+        let mut stream: CBCAStream = CBCAStream::accept(CBCAIncoming::Unix(reader), None).await.unwrap();
+        let limits: CBCALimits = CBCALimits {
+            idle_timeout: Duration::from_millis(200),
+            header_timeout: Duration::from_millis(200),
+            body_timeout: Duration::from_millis(200),
+            ..CBCALimits::default()
+        };
+        stream.set_limits(limits).unwrap();
+
+        let stream: Arc<tokio::sync::Mutex<CBCAStream>> = Arc::new(tokio::sync::Mutex::new(stream));
+        while CBCATcpPayload::read_frame(Arc::clone(&stream), CBCATcpPayloadType::Reqwest).await.is_ok() {}
+    });
+});
@@ -5,6 +5,7 @@ use crate::{
     utils::hash_now
 };
 
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum CBCABlockType {
     MESSAGE(MPayload),
@@ -12,6 +13,7 @@ pub enum CBCABlockType {
     INSTANCE(IPayload)
 }
 
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CBCABlock {
     hash: Option<String>,
@@ -46,11 +48,11 @@ impl CBCABlock {
         }
     }
 
-    fn get_payload(&self) -> CBCABlockType {
+    pub fn get_payload(&self) -> CBCABlockType {
         self.payload.clone()
     }
 
-    fn get_timestamp(&self) -> i64 {
+    pub fn get_timestamp(&self) -> i64 {
         self.timestamp
     }
 
@@ -58,6 +60,21 @@ impl CBCABlock {
         self.previous_hash.clone()
     }
 
+    // The stored hash was computed before it was set, so hash a copy without it.
+    pub fn verify_hash(&self) -> bool {
+        let mut unhashed: CBCABlock = self.clone();
+        unhashed.hash = None;
+
+        match unhashed.hash_block() {
+            Ok(v) => self.hash.as_deref() == Some(v.as_str()),
+            Err(_) => false
+        }
+    }
+
+    pub fn follows(&self, previous: Option<&CBCABlock>) -> bool {
+        self.get_previous_hash() == previous.and_then(|v| v.get_hash())
+    }
+
     pub fn hash_block(
         &mut self,
     ) -> Result<String, serde_json::Error> {
@@ -79,7 +96,8 @@ impl CBCABlock {
                     amount,
                     author,
                     instance_id,
-                    message
+                    message,
+                    idempotency_key: None
                 }
             ),
             Utc::now().timestamp(),
@@ -99,7 +117,8 @@ impl CBCABlock {
                 MPayload {
                     content,
                     author,
-                    instance_id
+                    instance_id,
+                    idempotency_key: None
                 }
             ),
             Utc::now().timestamp(),
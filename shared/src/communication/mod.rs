@@ -1,10 +1,152 @@
 use std::{fmt::format, str::{from_utf8, Utf8Error}, sync::Arc, time::Duration};
 
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use crate::{
+    compression::{CBCAFrameEncoding, CBCA_COMPRESSION_THRESHOLD},
+    limits::{CBCALimits, CBCA_MAX_FRAME_SIZE},
+    transport::CBCAStream
+};
+
 #[derive(Debug)]
 pub enum CBCATcpError {
-    InvalidHeader(String)
+    InvalidHeader(String),
+    Rejected(CBCAErrorPayload),
+    TimedOut(String),
+    TooLarge(usize),
+    Io(std::io::Error)
+}
+
+async fn timed<T, F>(
+    limit: Duration,
+    reason: &str,
+    future: F
+) -> Result<T, CBCATcpError>
+where
+    F: std::future::Future<Output = Result<T, std::io::Error>>
+{
+    match tokio::time::timeout(limit, future).await {
+        Ok(v) => Ok(v?),
+        Err(_) => Err(CBCATcpError::TimedOut(format!("{} after {:?}", reason, limit)))
+    }
+}
+
+impl From<std::io::Error> for CBCATcpError {
+    fn from(value: std::io::Error) -> Self {
+        CBCATcpError::Io(value)
+    }
+}
+
+impl From<CBCATcpError> for std::io::Error {
+    fn from(value: CBCATcpError) -> Self {
+        match value {
+            CBCATcpError::InvalidHeader(v) => 
+                std::io::Error::new(std::io::ErrorKind::InvalidData, v),
+            CBCATcpError::Rejected(v) => 
+                std::io::Error::new(std::io::ErrorKind::ConnectionRefused, v.message),
+            CBCATcpError::TimedOut(v) => 
+                std::io::Error::new(std::io::ErrorKind::TimedOut, v),
+            CBCATcpError::TooLarge(v) => 
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("payload of {} bytes is too large.", v)),
+            CBCATcpError::Io(v) => v,
+        }
+    }
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CBCAErrorCode {
+    IncompatibleVersion,
+    BadRequest,
+    NotFound,
+    Unsupported,
+    Unauthorized,
+    Timeout,
+    PayloadTooLarge,
+    RateLimited,
+    Busy,
+    // The auction closed, it takes no more offers.
+    Closed,
+    Internal
+}
+
+// Body of an Error frame, so peers can react to the cause and not only print it.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAErrorPayload {
+    pub code: CBCAErrorCode,
+    pub message: String,
+    // Set with RateLimited, how long to wait before trying again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>
+}
+
+impl CBCAErrorPayload {
+    pub fn spawn(code: CBCAErrorCode, message: String) -> Self {
+        Self { code, message, retry_after_ms: None }
+    }
+
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        let retry_after_ms: u64 = retry_after.as_millis().min(u64::MAX as u128) as u64;
+
+        Self {
+            code: CBCAErrorCode::RateLimited,
+            message: format!("too many requests, retry in {} ms.", retry_after_ms),
+            retry_after_ms: Some(retry_after_ms)
+        }
+    }
+}
+
+impl std::fmt::Display for CBCAErrorPayload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CBCAErrorPayload {}
+
+// Keeps the payload inside, so it comes back out unchanged past any io::Result.
+impl From<CBCAErrorPayload> for std::io::Error {
+    fn from(value: CBCAErrorPayload) -> Self {
+        std::io::Error::other(value)
+    }
+}
+
+impl From<&std::io::Error> for CBCAErrorPayload {
+    fn from(value: &std::io::Error) -> Self {
+        if let Some(v) = value.get_ref().and_then(|v| v.downcast_ref::<CBCAErrorPayload>()) {
+            return v.clone();
+        }
+
+        Self::spawn(
+            match value.kind() {
+                std::io::ErrorKind::InvalidInput => CBCAErrorCode::BadRequest,
+                std::io::ErrorKind::NotFound => CBCAErrorCode::NotFound,
+                std::io::ErrorKind::PermissionDenied => CBCAErrorCode::Unauthorized,
+                std::io::ErrorKind::TimedOut => CBCAErrorCode::Timeout,
+                std::io::ErrorKind::WouldBlock => CBCAErrorCode::Busy,
+                _ => CBCAErrorCode::Internal
+            },
+            value.to_string()
+        )
+    }
+}
+
+impl From<&CBCATcpError> for CBCAErrorPayload {
+    fn from(value: &CBCATcpError) -> Self {
+        match value {
+            CBCATcpError::InvalidHeader(v) => Self::spawn(CBCAErrorCode::BadRequest, v.clone()),
+            CBCATcpError::Rejected(v) => v.clone(),
+            CBCATcpError::TimedOut(v) => Self::spawn(CBCAErrorCode::Timeout, v.clone()),
+            CBCATcpError::TooLarge(v) => Self::spawn(
+                CBCAErrorCode::PayloadTooLarge, 
+                format!("payload of {} bytes is too large.", v)
+            ),
+            CBCATcpError::Io(v) => Self::from(v)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -13,6 +155,8 @@ pub enum CBCATcpPayloadType {
     Data,  // 01
     Debug, // 02
     Reqwest, // 03
+    Hello,   // 04
+    Heartbeat, // 05
     Unknown  // else
 }
 
@@ -23,6 +167,8 @@ impl CBCATcpPayloadType {
             &"01" => CBCATcpPayloadType::Data,
             &"02" => CBCATcpPayloadType::Debug,
             &"03" => CBCATcpPayloadType::Reqwest,
+            &"04" => CBCATcpPayloadType::Hello,
+            &"05" => CBCATcpPayloadType::Heartbeat,
             &_ => CBCATcpPayloadType::Unknown
         }
     }
@@ -61,6 +207,27 @@ impl CBCATcpPayloadType {
             _ => false
         }
     }
+
+    pub fn is_hello(&self) -> bool {
+        matches!(self, CBCATcpPayloadType::Hello)
+    }
+
+    pub fn is_heartbeat(&self) -> bool {
+        matches!(self, CBCATcpPayloadType::Heartbeat)
+    }
+
+    // Second character of the header.
+    pub fn get_code(&self) -> u8 {
+        match self {
+            CBCATcpPayloadType::Error => 0u8,
+            CBCATcpPayloadType::Data => 1u8,
+            CBCATcpPayloadType::Debug => 2u8,
+            CBCATcpPayloadType::Reqwest => 3u8,
+            CBCATcpPayloadType::Hello => 4u8,
+            CBCATcpPayloadType::Heartbeat => 5u8,
+            CBCATcpPayloadType::Unknown => 9u8 
+        }
+    }
 }
 
 impl PartialEq for CBCATcpPayloadType {
@@ -89,130 +256,264 @@ impl CBCATcpPayload {
         }
     }
 
-    // type=[][] length=[][][][][][] length*[]
+    // flag=[] type=[] length=[][][][][][] length*[], a whole frame already read, padded or not.
+    // Compressed bodies may not inflate past `max_size`.
     pub fn decode_response(
-        payload: &Vec<u8>
-    ) -> Result<CBCATcpPayload, Utf8Error> {
-        let action = from_utf8(&payload[0..2])?;
-        let content = from_utf8(&payload[8..payload.len()])?; 
-        
-        Ok(
-            Self::spawn(match &action {
-                &"00" => CBCATcpPayloadType::Error,
-                &"01" => CBCATcpPayloadType::Data,
-                &"02" => CBCATcpPayloadType::Debug,
-                &"03" => CBCATcpPayloadType::Reqwest,
-                &_ => CBCATcpPayloadType::Unknown
-            }, content.to_string())
-        )
+        payload: &[u8],
+        max_size: usize
+    ) -> Result<CBCATcpPayload, CBCATcpError> {
+        // Anything shorter than a header can't be a frame.
+        let header: &[u8; 8] = payload.first_chunk::<8>()
+            .ok_or(CBCATcpError::InvalidHeader("frame shorter than its header.".to_string()))?;
+        let (encoding, action, p_size) = Self::parse_header(header)?;
+
+        match payload.get(8..8 + p_size) {
+            Some(v) => Self::decode_body(encoding, action, v, max_size),
+            None => Err(CBCATcpError::InvalidHeader("frame shorter than its size.".to_string()))
+        }
+    }
+
+    // Inflates the body if the header flagged it so, then reads it as text.
+    fn decode_body(
+        encoding: CBCAFrameEncoding,
+        action: CBCATcpPayloadType,
+        body: &[u8],
+        max_size: usize
+    ) -> Result<CBCATcpPayload, CBCATcpError> {
+        let content: Vec<u8> = encoding.decode(body, max_size)
+            .map_err(|_| CBCATcpError::InvalidHeader("payload unreadable, bad compression.".to_string()))?;
+
+        match from_utf8(content.as_slice()) {
+            Ok(v) => Ok(Self::spawn(action, v.to_string())),
+            Err(_) => Err(CBCATcpError::InvalidHeader("payload unreadable.".to_string()))
+        }
+    }
+
+    pub fn get_type(&self) -> &CBCATcpPayloadType {
+        &self.payload_type
+    }
+
+    pub fn get_content(&self) -> &str {
+        &self.payload_content
     }
 
     pub async fn read(
-        stream: Arc<tokio::sync::Mutex<tokio::net::TcpStream>>,
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>,
         read_type: CBCATcpPayloadType
     ) -> Result<String, CBCATcpError> {
-        let mut header: [u8;8] = [0u8;8];
-        let mut lock = stream.lock().await;
-        let _ = lock.read_exact(&mut header).await;
+        let frame: CBCATcpPayload = Self::read_frame(stream, read_type).await?;
+        Ok(frame.payload_content)
+    }
 
-        let action_raw: Result<&str, Utf8Error> = from_utf8(&header[0..2]);
-        let p_size_raw: Result<&str, Utf8Error> = from_utf8(&header[3..8]);
+    // Encoding, type and body size of a frame, read from its 8 bytes header.
+    pub fn parse_header(
+        header: &[u8; 8]
+    ) -> Result<(CBCAFrameEncoding, CBCATcpPayloadType, usize), CBCATcpError> {
+        // The first character flags the body encoding, the type is the second one.
+        let encoding: Option<CBCAFrameEncoding> = CBCAFrameEncoding::from_flag(header[0]);
+        let action_raw: Result<&str, Utf8Error> = from_utf8(&header[1..2]);
+        let p_size_raw: Result<&str, Utf8Error> = from_utf8(&header[2..8]);
+
+        let encoding: CBCAFrameEncoding = match encoding {
+            Some(v) => v,
+            None => return Err(CBCATcpError::InvalidHeader("invalid header, unknown encoding.".to_string()))
+        };
+
+        let action: CBCATcpPayloadType = match action_raw {
+            Ok(v) => CBCATcpPayloadType::from_str(&["0", v].concat()),
+            Err(_) => return Err(CBCATcpError::InvalidHeader("invalid header, no action.".to_string()))
+        };
+
+        // Digits only, parse would also take a leading '+'.
+        let p_size: usize = match p_size_raw.map(|v| (v.bytes().all(|c| c.is_ascii_digit()), v.parse::<usize>())) {
+            Ok((true, Ok(v))) => v,
+            _ => return Err(CBCATcpError::InvalidHeader("invalid header, payload size isn't a number.".to_string()))
+        };
+
+        Ok((encoding, action, p_size))
+    }
 
-        if let Err(_) = action_raw {
-            return Err(CBCATcpError::InvalidHeader(format!("invalid header, no action.")));
+    // Decodes the first frame of `buf` without a stream, e.g. for traffic captured elsewhere.
+    // Ok(None) until `buf` holds the whole frame, then the frame, its encoding and the bytes it took.
+    // Compressed bodies may not inflate past `max_size`.
+    pub fn decode_frame(
+        buf: &[u8],
+        max_size: usize
+    ) -> Result<Option<(CBCATcpPayload, CBCAFrameEncoding, usize)>, CBCATcpError> {
+        let header: &[u8; 8] = match buf.first_chunk::<8>() {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+
+        let (encoding, action, p_size) = Self::parse_header(header)?;
+        let consumed: usize = 8 + p_size.div_ceil(8) * 8;
+
+        if buf.len() < consumed {
+            return Ok(None);
         }
 
-        if let Err(_) = p_size_raw {
-            return Err(CBCATcpError::InvalidHeader(format!("invalid header, no action.")));
-        }
+        let frame: CBCATcpPayload = Self::decode_body(encoding, action, &buf[8..8 + p_size], max_size)?;
+        Ok(Some((frame, encoding, consumed)))
+    }
 
-        let action: CBCATcpPayloadType = CBCATcpPayloadType::from_str(action_raw.unwrap());
+    // Same as `read`, but keeps the frame type so callers can tell an Error frame apart.
+    // Heartbeat frames only prove the peer is alive, they are skipped.
+    pub async fn read_frame(
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>,
+        read_type: CBCATcpPayloadType
+    ) -> Result<CBCATcpPayload, CBCATcpError> {
+        let mut lock = stream.lock().await;
+        let limits: CBCALimits = lock.get_limits();
 
-        if !action.eq(&read_type) && !action.is_error() {
-            return Err(CBCATcpError::InvalidHeader(format!("invalid header, incorrect action.")))
-        }
+        loop {
+            let mut header: [u8;8] = [0u8;8];
+            timed(limits.idle_timeout, "no frame", lock.read_exact(&mut header[..1])).await?;
+            timed(limits.header_timeout, "header incomplete", lock.read_exact(&mut header[1..])).await?;
 
-        let p_size = usize::from_str_radix(p_size_raw.unwrap(), 10);
+            let (encoding, action, p_size) = Self::parse_header(&header)?;
 
-        if let Err(_) = p_size {
-            return Err(CBCATcpError::InvalidHeader(format!("invalid header, payload size isn't a number.")));
-        }
-        
-        let mut content: Vec<u8> = Vec::new();
-        
-        println!("action={:?} {:?}", action, action_raw);
-        println!("size={:?} {:?}", p_size, p_size_raw);
+            if action.is_heartbeat() {
+                continue;
+            }
 
-        let p_parsed_size_chunks: usize = f64::ceil(p_size.unwrap() as f64/8.0) as usize;
+            if !action.eq(&read_type) && !action.is_error() {
+                return Err(CBCATcpError::InvalidHeader("invalid header, incorrect action.".to_string()))
+            }
 
-        for i in 0..p_parsed_size_chunks {
-            let mut temp_data: [u8; 8] = [0u8;8];
-            let _ = lock.read_exact(&mut temp_data).await;
+            if p_size > limits.max_payload_size {
+                return Err(CBCATcpError::TooLarge(p_size));
+            }
 
-            let _ = temp_data.iter().for_each(|v| {
-                if v != &0u8 {
-                    content.push(*v);
-                }
-            });
+            log::trace!("[FRAME] {:?} {}B.", action, p_size);
 
-            println!("{i} {:?}", temp_data);
-        }
+            // The last chunk is padded with zeros, compressed bodies may hold zeros of their own.
+            let mut content: Vec<u8> = vec![0u8; p_size.div_ceil(8) * 8];
+            timed(limits.body_timeout, "body incomplete", lock.read_exact(&mut content)).await?;
+            content.truncate(p_size);
 
-        let stringify = from_utf8(content.as_slice());
-        
-        match stringify {
-            Ok(v) => Ok(v.to_string()),
-            Err(_) => Err(CBCATcpError::InvalidHeader(format!("payload unreadable."))),
+            return Self::decode_body(encoding, action, &content, limits.max_payload_size);
         }
     }
 
     pub async fn send(
         &self,
-        stream: Arc<tokio::sync::Mutex<tokio::net::TcpStream>>
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>
     ) -> Result<(), std::io::Error> {
-        let data: Vec<u8> = self.build_response();
         let mut lock = stream.lock().await;
+        let limits: CBCALimits = lock.get_limits();
+        let encoding: CBCAFrameEncoding = 
+            if lock.has_compression() && self.payload_size as usize >= CBCA_COMPRESSION_THRESHOLD {
+                CBCAFrameEncoding::Deflate
+            } else {
+                CBCAFrameEncoding::Plain
+            };
+        let data: Vec<u8> = self.build_frame(encoding)?;
+
+        let writing = async {
+            for chunk in data
+                .chunks(8) {
+                    let mut buf = [0u8; 8]; 
+                    buf[..chunk.len()].copy_from_slice(chunk);
+                    lock.write_all(&buf).await?;
+            }
+
+            lock.flush().await
+        };
+
+        timed(limits.write_timeout, "peer not reading", writing).await?;
+        Ok(())
+    }
+
+    // Tells the peer why before closing, it may be gone already so failures are ignored.
+    pub async fn refuse(
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>,
+        error: &CBCATcpError
+    ) {
+        let payload: CBCAErrorPayload = CBCAErrorPayload::from(error);
+        log::warn!("[REFUSE] {:?} {}", payload.code, payload.message);
 
-        for chunk in data
-            .chunks(8) {
-                let mut buf = [0u8; 8]; 
-                buf[..chunk.len()].copy_from_slice(chunk);
-                lock.writable().await?;
-                lock.write_all(&buf).await?;
-                lock.flush().await?;
+        if let Ok(v) = serde_json::to_string(&payload) {
+            let _ = CBCATcpPayload::spawn(CBCATcpPayloadType::Error, v).send(Arc::clone(&stream)).await;
         }
 
-        lock.shutdown().await?;
+        let _ = Self::close(stream).await;
+    }
 
+    pub async fn close(
+        stream: Arc<tokio::sync::Mutex<CBCAStream>>
+    ) -> Result<(), std::io::Error> {
+        let mut lock = stream.lock().await;
+        lock.shutdown().await?;
         Ok(())
     }
 
     pub fn build_response(
         &self
     ) -> Vec<u8> {
-        let action: String = (match self.payload_type {
-            CBCATcpPayloadType::Error => 0u8,
-            CBCATcpPayloadType::Data => 1u8,
-            CBCATcpPayloadType::Debug => 2u8,
-            CBCATcpPayloadType::Reqwest => 3u8,
-            CBCATcpPayloadType::Unknown => 4u8 
-        }).to_string();
+        self.build_frame(CBCAFrameEncoding::Plain).unwrap_or_default()
+    }
+
+    // type=[][] length=[][][][][][] length*[], the first type character is the encoding flag.
+    pub fn build_frame(
+        &self,
+        encoding: CBCAFrameEncoding
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let action: String = self.payload_type.get_code().to_string();
 
         let mut action_byted = action.as_bytes().to_vec();
         if action_byted.len() == 1 {
-            action_byted.insert(0, "0".as_bytes()[0]);
+            action_byted.insert(0, encoding.get_flag());
         }
         
-        let size_stringified = self.payload_size.to_string();
+        let payload = encoding.encode(self.payload_content.as_bytes())?;
+
+        if payload.len() > CBCA_MAX_FRAME_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, 
+                format!("payload of {} bytes doesn't fit in a frame.", payload.len())
+            ));
+        }
+        let size_stringified = payload.len().to_string();
         let mut size_byted = size_stringified.as_bytes().to_vec();
         
         for _ in 0..(6-size_byted.len()) {
             size_byted.insert(0, "0".as_bytes()[0]);
         }
         
-        let payload = self.payload_content.as_bytes().to_vec();
         let final_payload = [&action_byted[..], &size_byted[..], &payload[..]].concat();
         
-        final_payload
+        Ok(final_payload)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::{CBCATcpPayload, CBCATcpPayloadType};
+    use crate::{compression::CBCAFrameEncoding, limits::CBCA_MAX_FRAME_SIZE};
+
+    #[test]
+    fn responses_decode_in_both_encodings() {
+        let sent: CBCATcpPayload = CBCATcpPayload::spawn(CBCATcpPayloadType::Data, "offer ".repeat(100));
+
+        for encoding in [CBCAFrameEncoding::Plain, CBCAFrameEncoding::Deflate] {
+            let mut data: Vec<u8> = sent.build_frame(encoding).unwrap();
+            let got: CBCATcpPayload = CBCATcpPayload::decode_response(&data, CBCA_MAX_FRAME_SIZE).unwrap();
+            assert!(got.get_type().eq(sent.get_type()));
+            assert_eq!(got.get_content(), sent.get_content());
+
+            // Padding off the wire is left out.
+            data.resize(data.len().div_ceil(8) * 8, 0);
+            let got: CBCATcpPayload = CBCATcpPayload::decode_response(&data, CBCA_MAX_FRAME_SIZE).unwrap();
+            assert_eq!(got.get_content(), sent.get_content());
+        }
+    }
+
+    #[test]
+    fn responses_past_their_limit_are_refused() {
+        let sent: CBCATcpPayload = CBCATcpPayload::spawn(CBCATcpPayloadType::Data, "0".repeat(4096));
+        let data: Vec<u8> = sent.build_frame(CBCAFrameEncoding::Deflate).unwrap();
+
+        assert!(data.len() < 1024);
+        assert!(CBCATcpPayload::decode_response(&data, 1024).is_err());
+        assert!(CBCATcpPayload::decode_response(&data[..data.len() - 1], CBCA_MAX_FRAME_SIZE).is_err());
     }
-}
\ No newline at end of file
+}
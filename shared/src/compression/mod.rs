@@ -0,0 +1,64 @@
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+// Smaller frames cost more to compress than they save.
+pub const CBCA_COMPRESSION_THRESHOLD: usize = 512;
+
+// How a frame body is encoded, first character of the header.
+// Peers from before compression always write '0', so their frames read as Plain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CBCAFrameEncoding {
+    Plain,
+    Deflate
+}
+
+impl CBCAFrameEncoding {
+    pub fn from_flag(flag: u8) -> Option<Self> {
+        match flag {
+            b'0' => Some(CBCAFrameEncoding::Plain),
+            b'1' => Some(CBCAFrameEncoding::Deflate),
+            _ => None
+        }
+    }
+
+    pub fn get_flag(&self) -> u8 {
+        match self {
+            CBCAFrameEncoding::Plain => b'0',
+            CBCAFrameEncoding::Deflate => b'1'
+        }
+    }
+
+    pub fn encode(&self, body: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            CBCAFrameEncoding::Plain => Ok(body.to_vec()),
+            CBCAFrameEncoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    // Stops inflating past `max_size`, the same cap a plain body has on the wire.
+    pub fn decode(&self, body: &[u8], max_size: usize) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            CBCAFrameEncoding::Plain => Ok(body.to_vec()),
+            CBCAFrameEncoding::Deflate => {
+                let mut inflated: Vec<u8> = Vec::new();
+                DeflateDecoder::new(body)
+                    .take(max_size as u64 + 1)
+                    .read_to_end(&mut inflated)?;
+
+                if inflated.len() > max_size {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "compressed payload inflates past the limit."
+                    ));
+                }
+
+                Ok(inflated)
+            }
+        }
+    }
+}
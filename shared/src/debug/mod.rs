@@ -1,5 +1,74 @@
+use serde::{Deserialize, Serialize};
+
 use crate::block::CBCABlock;
 
+// Admin commands carried by Debug frames.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum CBCADebugCommand {
+    Stats,
+    Verify {
+        instance_id: String
+    }
+}
+
+// Waits of storage commands for the actor of their instance since the server started, in microseconds.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CBCALockStats {
+    pub waits: u64,
+    pub total_wait_us: u64,
+    pub max_wait_us: u64
+}
+
+// Work queue of the writes, latencies since the server started, in microseconds.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CBCAQueueStats {
+    pub depth: usize,
+    pub capacity: usize,
+    pub workers: usize,
+    pub submitted: u64,
+    pub completed: u64,
+    pub rejected: u64,
+    pub total_wait_us: u64,
+    pub max_wait_us: u64,
+    pub total_run_us: u64,
+    pub max_run_us: u64
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAChainHeads {
+    pub instance_id: String,
+    pub offers: usize,
+    pub offers_head: Option<String>,
+    pub messages: usize,
+    pub messages_head: Option<String>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAServerStats {
+    pub queue_depth: usize,
+    pub open_connections: usize,
+    pub loaded_instances: usize,
+    pub storage_lock: CBCALockStats,
+    #[serde(default)]
+    pub queue: CBCAQueueStats,
+    pub heads: Vec<CBCAChainHeads>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAChainVerdict {
+    pub blocks: usize,
+    pub valid: bool,
+    pub first_invalid: Option<usize>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAVerifyReport {
+    pub instance_id: String,
+    pub offers: CBCAChainVerdict,
+    pub messages: CBCAChainVerdict
+}
+
 pub fn _dbg_generate_masse_message(
     n: usize, 
     instance_id: &String
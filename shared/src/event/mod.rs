@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{block::CBCABlock, fchain::CBCAChainKind, request::CBCAAuctionStatus};
+
+// Something that happened inside an instance, pushed to subscribers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CBCAEvent {
+    InstanceCreated {
+        instance_id: String
+    },
+    BlockAppended {
+        instance_id: String,
+        chain: CBCAChainKind,
+        block: Box<CBCABlock>
+    },
+    StatusChanged {
+        instance_id: String,
+        status: CBCAAuctionStatus
+    },
+    // Seconds left before the auction closes, only sent on live feeds (SSE).
+    Countdown {
+        instance_id: String,
+        remaining: i64
+    }
+}
+
+impl CBCAEvent {
+    pub fn get_instance_id(&self) -> &str {
+        match self {
+            CBCAEvent::InstanceCreated { instance_id } => instance_id,
+            CBCAEvent::BlockAppended { instance_id, .. } => instance_id,
+            CBCAEvent::StatusChanged { instance_id, .. } => instance_id,
+            CBCAEvent::Countdown { instance_id, .. } => instance_id
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            CBCAEvent::InstanceCreated { .. } => "instance_created",
+            CBCAEvent::BlockAppended { .. } => "block_appended",
+            CBCAEvent::StatusChanged { .. } => "status_changed",
+            CBCAEvent::Countdown { .. } => "countdown"
+        }
+    }
+}
@@ -1,7 +1,10 @@
-use crate::{block::CBCABlock, utils::hash_now};
+use crate::{block::CBCABlock, payload::MAX_AUTHOR_LEN, utils::hash_now};
 use serde::{de::Error, Deserialize, Serialize};
 use serde_json::to_string;
 
+pub const CBCA_MAX_INVITED: usize = 256;
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[derive(Serialize, serde::Deserialize, Debug, Clone)]
 pub struct CBCAConfig {
     limit_members: Option<u16>,
@@ -11,7 +14,10 @@ pub struct CBCAConfig {
     description: String,
     name: String,
     pub hash: Option<String>,
-    currency: String
+    currency: String,
+    // Authors who may find a private instance, left out of the hash while empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    invited: Vec<String>
 }
 
 impl CBCAConfig {
@@ -32,7 +38,8 @@ impl CBCAConfig {
             description,
             name,
             hash: None,
-            currency
+            currency,
+            invited: Vec::new()
         };
 
         let serialized: String = serde_json::to_string(&config)?;
@@ -40,9 +47,83 @@ impl CBCAConfig {
 
         Ok(config)
     }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn get_currency(&self) -> &str {
+        &self.currency
+    }
+
+    pub fn get_duration(&self) -> u32 {
+        self.duration
+    }
+
+    pub fn get_start_price(&self) -> Option<f32> {
+        self.start_price
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    pub fn get_invited(&self) -> &[String] {
+        &self.invited
+    }
+
+    // Public instances are open to everyone, private ones to the authors invited.
+    pub fn is_visible_to(&self, author: Option<&str>) -> bool {
+        !self.private || author.is_some_and(|v| self.invited.iter().any(|i| i == v))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() || self.name.len() > 128 {
+            return Err("name must be between 1 and 128 bytes.".to_string());
+        }
+
+        if self.description.len() > 4096 {
+            return Err("description is longer than 4096 bytes.".to_string());
+        }
+
+        if self.currency.len() != 3 || !self.currency.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err("currency must be a 3 letters ISO code.".to_string());
+        }
+
+        if self.duration == 0 {
+            return Err("duration must be positive.".to_string());
+        }
+
+        if let Some(v) = self.start_price && (!v.is_finite() || v < 0.0) {
+            return Err("start price must be a positive number.".to_string());
+        }
+
+        if self.invited.len() > CBCA_MAX_INVITED {
+            return Err(format!("at most {} authors can be invited.", CBCA_MAX_INVITED));
+        }
+
+        if self.invited.iter().any(|v| v.trim().is_empty() || v.len() > MAX_AUTHOR_LEN) {
+            return Err(format!("invited authors must be between 1 and {} bytes.", MAX_AUTHOR_LEN));
+        }
+
+        Ok(())
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// The two chains every instance holds.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CBCAChainKind {
+    Offers,
+    Messages
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CBCAChain {
     instance_id: String,
     chain: Vec<CBCABlock>,
@@ -60,8 +141,29 @@ impl CBCAChain {
         }
     }
 
+    // Rebuilds a chain from blocks stored one by one, the whole chain hash isn't kept with them.
+    pub fn restore(
+        instance_id: String,
+        chain: Vec<CBCABlock>
+    ) -> Self {
+        Self {
+            instance_id,
+            chain,
+            hash: None
+        }
+    }
+
     pub fn verify(&self) -> bool {
-        todo!()
+        self.first_invalid().is_none()
+    }
+
+    // Index of the first block whose hash doesn't match its content or which
+    // doesn't point to the block before it.
+    pub fn first_invalid(&self) -> Option<usize> {
+        (0..self.chain.len()).find(|&i| {
+            let previous: Option<&CBCABlock> = i.checked_sub(1).map(|p| &self.chain[p]);
+            !self.chain[i].verify_hash() || !self.chain[i].follows(previous)
+        })
     }
 
     pub fn len(&self) -> usize {
@@ -77,11 +179,43 @@ impl CBCAChain {
         Ok(hash)
     }
 
+    pub fn blocks(&self) -> &[CBCABlock] {
+        &self.chain
+    }
+
+    // At most `limit` blocks starting at index `from`, empty past the end.
+    pub fn page(&self, from: usize, limit: usize) -> &[CBCABlock] {
+        let start: usize = from.min(self.chain.len());
+        let end: usize = start.saturating_add(limit).min(self.chain.len());
+        &self.chain[start..end]
+    }
+
+    pub fn position(&self, hash: &str) -> Option<usize> {
+        self.chain.iter().position(|v| v.get_hash().as_deref() == Some(hash))
+    }
+
+    pub fn last(&self) -> Option<&CBCABlock> {
+        self.chain.last()
+    }
+
     pub fn get_last_hash(&self) -> Option<String> {
         let last: &CBCABlock = self.chain.iter().last()?;
         last.get_hash()
     }
 
+    // Keeps a block linked and hashed elsewhere, refused unless it follows the last one.
+    pub fn push_linked(
+        &mut self,
+        block: CBCABlock
+    ) -> bool {
+        if !block.follows(self.last()) {
+            return false;
+        }
+
+        self.chain.push(block);
+        true
+    }
+
     pub fn push(
         &mut self, 
         block: CBCABlock
@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    communication::{CBCAErrorCode, CBCAErrorPayload, CBCATcpError, CBCATcpPayload, CBCATcpPayloadType},
+    transport::CBCAStream
+};
+
+// Bump when the wire format changes in a way older peers can't read.
+// 2: messages and offers are answered with a CBCAReceipt instead of "true",
+//    errors with a CBCAErrorPayload instead of "false".
+// Compression needs no bump, it is only used once both peers advertised it.
+pub const CBCA_PROTOCOL_VERSION: u16 = 2;
+pub const CBCA_SUPPORTED_VERSIONS: [u16; 2] = [1, CBCA_PROTOCOL_VERSION];
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CBCAFeature {
+    Compression,
+    Subscriptions,
+    // Heartbeat frames on subscriptions, older clients would take them for garbage.
+    Heartbeats
+}
+
+// First frame of every connection, sent by the client.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAHello {
+    pub versions: Vec<u16>,
+    pub features: Vec<CBCAFeature>,
+    pub agent: String
+}
+
+// Server answer to a compatible `CBCAHello`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAHelloAck {
+    pub version: u16,
+    pub features: Vec<CBCAFeature>
+}
+
+impl CBCAHelloAck {
+    pub fn has_feature(&self, feature: CBCAFeature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+impl CBCAHello {
+    pub fn spawn(
+        features: Vec<CBCAFeature>,
+        agent: String
+    ) -> Self {
+        Self {
+            versions: CBCA_SUPPORTED_VERSIONS.to_vec(),
+            features,
+            agent
+        }
+    }
+
+    // Picks the highest version both sides know and keeps the features both sides advertised.
+    pub fn negotiate(
+        &self,
+        versions: &[u16],
+        features: &[CBCAFeature]
+    ) -> Result<CBCAHelloAck, CBCAErrorPayload> {
+        let version: Option<u16> = self.versions
+            .iter()
+            .filter(|v| versions.contains(v))
+            .max()
+            .copied();
+
+        match version {
+            Some(v) => Ok(
+                CBCAHelloAck {
+                    version: v,
+                    features: self.features
+                        .iter()
+                        .filter(|f| features.contains(f))
+                        .copied()
+                        .collect()
+                }
+            ),
+            None => Err(
+                CBCAErrorPayload::spawn(
+                    CBCAErrorCode::IncompatibleVersion,
+                    format!(
+                        "incompatible protocol version, peer speaks {:?} but server speaks {:?}.",
+                        self.versions, versions
+                    )
+                )
+            )
+        }
+    }
+}
+
+pub async fn client_handshake(
+    stream: Arc<tokio::sync::Mutex<CBCAStream>>,
+    hello: &CBCAHello
+) -> Result<CBCAHelloAck, CBCATcpError> {
+    let serialized: String = serde_json::to_string(hello)
+        .map_err(std::io::Error::from)?;
+
+    CBCATcpPayload::spawn(CBCATcpPayloadType::Hello, serialized)
+        .send(Arc::clone(&stream))
+        .await?;
+
+    let frame: CBCATcpPayload = CBCATcpPayload::read_frame(Arc::clone(&stream), CBCATcpPayloadType::Hello).await?;
+
+    if frame.get_type().is_error() {
+        let error: CBCAErrorPayload = serde_json::from_str(frame.get_content())
+            .unwrap_or(CBCAErrorPayload::spawn(CBCAErrorCode::Internal, frame.get_content().to_string()));
+        return Err(CBCATcpError::Rejected(error));
+    }
+
+    let ack: CBCAHelloAck = serde_json::from_str(frame.get_content())
+        .map_err(|_| CBCATcpError::InvalidHeader("handshake answer unreadable.".to_string()))?;
+
+    stream.lock().await.set_compression(ack.has_feature(CBCAFeature::Compression));
+
+    Ok(ack)
+}
+
+// Reads the client hello and answers it, sending an Error frame to peers we can't talk to.
+pub async fn server_handshake(
+    stream: Arc<tokio::sync::Mutex<CBCAStream>>,
+    features: &[CBCAFeature]
+) -> Result<CBCAHelloAck, CBCATcpError> {
+    let frame: Result<CBCATcpPayload, CBCATcpError> = 
+        CBCATcpPayload::read_frame(Arc::clone(&stream), CBCATcpPayloadType::Hello).await;
+
+    // Peers from before the handshake open with a Reqwest frame, they get told why they're refused.
+    let hello: Option<CBCAHello> = match frame {
+        Ok(v) => serde_json::from_str(v.get_content()).ok(),
+        Err(CBCATcpError::InvalidHeader(_)) => None,
+        Err(e @ (CBCATcpError::TimedOut(_) | CBCATcpError::TooLarge(_))) => {
+            CBCATcpPayload::refuse(stream, &e).await;
+            return Err(e);
+        },
+        Err(e) => return Err(e)
+    };
+
+    let negotiated: Result<CBCAHelloAck, CBCAErrorPayload> = match hello {
+        Some(v) => v.negotiate(&CBCA_SUPPORTED_VERSIONS, features),
+        None => Err(
+            CBCAErrorPayload::spawn(
+                CBCAErrorCode::IncompatibleVersion, 
+                "handshake expected, please upgrade your client.".to_string()
+            )
+        )
+    };
+
+    let (answer, result) = match negotiated {
+        Ok(ack) => (
+            CBCATcpPayload::spawn(
+                CBCATcpPayloadType::Hello,
+                serde_json::to_string(&ack).unwrap_or_default()
+            ),
+            Ok(ack)
+        ),
+        Err(error) => (
+            CBCATcpPayload::spawn(
+                CBCATcpPayloadType::Error,
+                serde_json::to_string(&error).unwrap_or_default()
+            ),
+            Err(CBCATcpError::Rejected(error))
+        )
+    };
+
+    answer.send(Arc::clone(&stream)).await?;
+
+    // The ack itself went out uncompressed, the peer only switches once it has read it.
+    if let Ok(ack) = &result {
+        stream.lock().await.set_compression(ack.has_feature(CBCAFeature::Compression));
+    }
+
+    result
+}
@@ -3,4 +3,11 @@ pub mod payload;
 pub mod fchain;
 pub mod utils;
 pub mod debug;
-pub mod communication;
\ No newline at end of file
+pub mod communication;
+pub mod compression;
+pub mod handshake;
+pub mod limits;
+pub mod tls;
+pub mod transport;
+pub mod event;
+pub mod request;
\ No newline at end of file
@@ -0,0 +1,66 @@
+use std::{env, time::Duration};
+
+// The header gives the body size on 6 digits.
+pub const CBCA_MAX_FRAME_SIZE: usize = 999_999;
+
+// How long a peer may keep a connection busy, applied to every frame read or written.
+#[derive(Clone, Copy, Debug)]
+pub struct CBCALimits {
+    // Waiting for the first byte of a frame.
+    pub idle_timeout: Duration,
+    // Rest of the header once the first byte arrived.
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub write_timeout: Duration,
+    // Heartbeat frames on long-lived connections, must stay under the peer idle timeout.
+    pub heartbeat_interval: Duration,
+    pub keepalive: Duration,
+    pub max_payload_size: usize
+}
+
+impl Default for CBCALimits {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(30),
+            header_timeout: Duration::from_secs(5),
+            body_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(10),
+            heartbeat_interval: Duration::from_secs(10),
+            keepalive: Duration::from_secs(60),
+            max_payload_size: 512 * 1024
+        }
+    }
+}
+
+fn env_millis(name: &str, default: Duration) -> Duration {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(default)
+}
+
+impl CBCALimits {
+    // Defaults overridden by CBCA_IDLE_TIMEOUT_MS, CBCA_HEADER_TIMEOUT_MS, CBCA_BODY_TIMEOUT_MS,
+    // CBCA_WRITE_TIMEOUT_MS, CBCA_HEARTBEAT_MS, CBCA_KEEPALIVE_MS and CBCA_MAX_PAYLOAD_SIZE.
+    pub fn from_env() -> Self {
+        CBCALimits::default().with_env()
+    }
+
+    // Same variables over limits already set, e.g. read from a config file.
+    pub fn with_env(self) -> Self {
+        Self {
+            idle_timeout: env_millis("CBCA_IDLE_TIMEOUT_MS", self.idle_timeout),
+            header_timeout: env_millis("CBCA_HEADER_TIMEOUT_MS", self.header_timeout),
+            body_timeout: env_millis("CBCA_BODY_TIMEOUT_MS", self.body_timeout),
+            write_timeout: env_millis("CBCA_WRITE_TIMEOUT_MS", self.write_timeout),
+            heartbeat_interval: env_millis("CBCA_HEARTBEAT_MS", self.heartbeat_interval),
+            keepalive: env_millis("CBCA_KEEPALIVE_MS", self.keepalive),
+            max_payload_size: env::var("CBCA_MAX_PAYLOAD_SIZE")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(self.max_payload_size)
+                .min(CBCA_MAX_FRAME_SIZE)
+        }
+    }
+}
@@ -1,28 +1,69 @@
 use serde::{Serialize, Deserialize};
 use std::any::{Any, TypeId};
-use crate::fchain::CBCAConfig;
+use crate::{
+    debug::CBCADebugCommand,
+    fchain::{CBCAChainKind, CBCAConfig},
+    request::CBCAAuctionStatus
+};
 
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MPayload {
     pub content: String,
     pub author: String,
-    pub instance_id: String
+    // Optional where the instance is already given by the route (REST).
+    #[serde(default)]
+    pub instance_id: String,
+    // Chosen by the client, a retried request carrying the same key isn't appended twice.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>
 }
 
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OPayload {
     pub amount: f32,
     pub author: String,
+    #[serde(default)]
     pub instance_id: String,
-    pub message: Option<String>
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>
 }
 
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct IPayload {
+    // Assigned by the server, whatever a client puts here is ignored.
+    #[serde(default)]
     pub instance_id: String,
     pub config: CBCAConfig
 }
 
+pub const MAX_AUTHOR_LEN: usize = 64;
+pub const MAX_CONTENT_LEN: usize = 2048;
+
+// Identifiers are issued by the server as hyphenated lowercase UUIDs, and an instance
+// directory is named after one. Nothing else is accepted, so an id can't name another path.
+pub fn validate_instance_id(instance_id: &str) -> Result<(), String> {
+    match uuid::Uuid::try_parse(instance_id) {
+        Ok(v) if v.hyphenated().to_string() == instance_id => Ok(()),
+        _ => Err(format!("{:?} isn't an instance id issued by this server.", instance_id))
+    }
+}
+
+pub fn issue_instance_id() -> String {
+    uuid::Uuid::new_v4().hyphenated().to_string()
+}
+
+fn validate_author(author: &str) -> Result<(), String> {
+    if author.trim().is_empty() || author.len() > MAX_AUTHOR_LEN {
+        return Err(format!("author must be between 1 and {} bytes.", MAX_AUTHOR_LEN));
+    }
+
+    Ok(())
+}
+
 pub trait Payload {
     fn get_payload(&self) -> Self;
     fn get_instance_id(&self) -> &str;
@@ -53,6 +94,34 @@ impl MPayload {
     pub fn extract(payload: &MPayload) -> (String, String, String) {
         (payload.content.to_string(), payload.author.to_string(), payload.instance_id.to_string())
     }
+
+    pub fn validate(&self) -> Result<(), String> {
+        validate_instance_id(&self.instance_id)?;
+        validate_author(&self.author)?;
+
+        if self.content.trim().is_empty() || self.content.len() > MAX_CONTENT_LEN {
+            return Err(format!("content must be between 1 and {} bytes.", MAX_CONTENT_LEN));
+        }
+
+        Ok(())
+    }
+}
+
+impl OPayload {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_instance_id(&self.instance_id)?;
+        validate_author(&self.author)?;
+
+        if !self.amount.is_finite() || self.amount <= 0.0 {
+            return Err("amount must be a positive number.".to_string());
+        }
+
+        if let Some(v) = &self.message && v.len() > MAX_CONTENT_LEN {
+            return Err(format!("message is longer than {} bytes.", MAX_CONTENT_LEN));
+        }
+
+        Ok(())
+    }
 }
 
 impl Payload for OPayload {
@@ -97,4 +166,99 @@ impl IPayload {
     pub fn extract_config(&self) -> &CBCAConfig {
         &self.config
     }
-}
\ No newline at end of file
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.config.validate()
+    }
+}
+
+// Subscription to the events of one instance.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SPayload {
+    pub instance_id: String
+}
+// Which blocks of a chain to read, `limit` is capped to CBCA_MAX_PAGE_SIZE.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "by", rename_all = "snake_case")]
+pub enum CBCAChainRange {
+    Index {
+        from: usize,
+        limit: usize
+    },
+    // Blocks following the one with this hash, e.g. the last one a client already has.
+    After {
+        hash: String,
+        limit: usize
+    }
+}
+
+// Order of the instances listed.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CBCAInstanceSort {
+    // Closing first, instances without an end last.
+    #[default]
+    ClosesAt,
+    // Most bids first.
+    Bids,
+    Name
+}
+
+// Filters of a list or search request, a filter left out matches every instance.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CBCAInstanceFilter {
+    #[serde(default)]
+    pub status: Option<CBCAAuctionStatus>,
+    #[serde(default)]
+    pub currency: Option<String>,
+    // Unix timestamp.
+    #[serde(default)]
+    pub closes_before: Option<i64>,
+    #[serde(default)]
+    pub min_bids: Option<usize>,
+    // Author asking, private instances are only listed to the authors they invite. Never sent,
+    // the server sets it to the name of the client certificate.
+    #[serde(skip)]
+    pub requester: Option<String>,
+    #[serde(default)]
+    pub sort: CBCAInstanceSort,
+    #[serde(default)]
+    pub from: usize,
+    // Capped to CBCA_MAX_PAGE_SIZE.
+    #[serde(default)]
+    pub limit: Option<usize>
+}
+
+// Read-only requests, they never append anything.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum QPayload {
+    Chain {
+        instance_id: String,
+        chain: CBCAChainKind,
+        range: CBCAChainRange
+    },
+    List {
+        #[serde(flatten)]
+        filter: CBCAInstanceFilter
+    },
+    // Instances whose name or description holds every word of `text`, case aside.
+    Search {
+        text: String,
+        #[serde(flatten)]
+        filter: CBCAInstanceFilter
+    }
+}
+
+// Sent in a Debug frame to the admin routine, refused unless `token` matches the server one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DPayload {
+    pub token: String,
+    #[serde(flatten)]
+    pub command: CBCADebugCommand
+}
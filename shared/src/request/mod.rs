@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::CBCABlock,
+    communication::CBCAErrorPayload,
+    event::CBCAEvent,
+    fchain::{CBCAChainKind, CBCAConfig},
+    payload::{IPayload, MPayload, OPayload, QPayload, SPayload}
+};
+
+pub const CBCA_MAX_PAGE_SIZE: usize = 200;
+
+// Every request a peer can make, for transports carrying them all on one connection.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CBCARequest {
+    Instance(IPayload),
+    Message(MPayload),
+    Offer(OPayload),
+    Query(QPayload),
+    Subscribe(SPayload),
+    Unsubscribe(SPayload)
+}
+
+// `id` is chosen by the peer and echoed back in the matching response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCARequestFrame {
+    pub id: Option<u64>,
+    pub request: CBCARequest
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CBCAResponse {
+    Ok {
+        id: Option<u64>,
+        data: serde_json::Value
+    },
+    Error {
+        id: Option<u64>,
+        error: CBCAErrorPayload
+    },
+    Event {
+        event: CBCAEvent
+    }
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CBCAAuctionStatus {
+    Open,
+    Closed
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAInstanceSummary {
+    pub instance_id: String,
+    pub config: CBCAConfig,
+    pub status: CBCAAuctionStatus,
+    // Unix timestamp, unknown for instances created before it was recorded.
+    pub closes_at: Option<i64>
+}
+
+// What the instance index knows of one instance, the invited authors stay on the server.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAInstanceListing {
+    pub instance_id: String,
+    pub name: String,
+    pub description: String,
+    pub currency: String,
+    pub status: CBCAAuctionStatus,
+    pub closes_at: Option<i64>,
+    pub private: bool,
+    pub bids: usize
+}
+
+// One page of the instances matching a list or search request, `total` counts all of them.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAInstanceList {
+    pub total: usize,
+    pub from: usize,
+    pub instances: Vec<CBCAInstanceListing>
+}
+
+// A slice of one chain, `head_hash` is the hash of its last block when the page was read.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAChainPage {
+    pub instance_id: String,
+    pub chain: CBCAChainKind,
+    pub head_hash: Option<String>,
+    pub total: usize,
+    pub from: usize,
+    pub blocks: Vec<CBCABlock>
+}
+
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCASettlement {
+    pub instance_id: String,
+    pub status: CBCAAuctionStatus,
+    pub closes_at: Option<i64>,
+    pub offers: usize,
+    // Highest offer at or above the start price, the earliest one wins a tie.
+    pub winner: Option<CBCABlock>
+}
+
+// Answer to an appended message or offer, `duplicate` when its idempotency key
+// was already used and `block` is the one appended the first time.
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CBCAReceipt {
+    pub block: CBCABlock,
+    pub duplicate: bool
+}
+
+impl CBCAReceipt {
+    pub fn get_block_hash(&self) -> Option<String> {
+        self.block.get_hash()
+    }
+}
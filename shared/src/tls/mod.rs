@@ -0,0 +1,382 @@
+use std::{path::PathBuf, sync::Arc};
+
+use tokio_rustls::{
+    client,
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        server::WebPkiClientVerifier,
+        ClientConfig, RootCertStore, ServerConfig
+    },
+    TlsConnector
+};
+
+pub use tokio_rustls::TlsAcceptor;
+
+fn invalid_data<E: std::fmt::Debug>(context: &str, error: E) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("{} ({:?}).", context, error)
+    )
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn parse_certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, std::io::Error> {
+    let certs: Vec<CertificateDer<'static>> = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid_data("unreadable certificate", e))?;
+
+    if certs.is_empty() {
+        return Err(invalid_data("no certificate found", pem.len()));
+    }
+
+    Ok(certs)
+}
+
+fn parse_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>, std::io::Error> {
+    PrivateKeyDer::from_pem_slice(pem)
+        .map_err(|e| invalid_data("unreadable private key", e))
+}
+
+fn parse_roots(pem: &[u8]) -> Result<RootCertStore, std::io::Error> {
+    let mut roots: RootCertStore = RootCertStore::empty();
+
+    for cert in parse_certs(pem)? {
+        roots.add(cert).map_err(|e| invalid_data("invalid CA certificate", e))?;
+    }
+
+    Ok(roots)
+}
+
+// Paths given to the server to encrypt its listeners.
+// When `client_ca_path` is set, clients must present a certificate signed by it (mutual TLS).
+#[derive(Debug, Clone)]
+pub struct CBCATlsServerConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub client_ca_path: Option<PathBuf>
+}
+
+impl CBCATlsServerConfig {
+    pub fn spawn(
+        cert_path: PathBuf,
+        key_path: PathBuf,
+        client_ca_path: Option<PathBuf>
+    ) -> Self {
+        Self { cert_path, key_path, client_ca_path }
+    }
+
+    pub fn build_acceptor(&self) -> Result<TlsAcceptor, std::io::Error> {
+        let cert_pem: Vec<u8> = std::fs::read(&self.cert_path)?;
+        let key_pem: Vec<u8> = std::fs::read(&self.key_path)?;
+        let client_ca_pem: Option<Vec<u8>> = match &self.client_ca_path {
+            Some(v) => Some(std::fs::read(v)?),
+            None => None
+        };
+
+        acceptor_from_pem(&cert_pem, &key_pem, client_ca_pem.as_deref())
+    }
+}
+
+// Builds the acceptor from PEM already in memory, e.g. certificates generated on the fly.
+pub fn acceptor_from_pem(
+    cert_pem: &[u8],
+    key_pem: &[u8],
+    client_ca_pem: Option<&[u8]>
+) -> Result<TlsAcceptor, std::io::Error> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid_data("tls setup failed", e))?;
+
+    let builder = match client_ca_pem {
+        Some(v) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(parse_roots(v)?),
+                provider()
+            )
+                .build()
+                .map_err(|e| invalid_data("invalid client CA", e))?;
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth()
+    };
+
+    let config: ServerConfig = builder
+        .with_single_cert(parse_certs(cert_pem)?, parse_key(key_pem)?)
+        .map_err(|e| invalid_data("invalid server certificate", e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Common name of the first certificate a peer presented, the one it was verified with.
+// Only mutual TLS gives one, the server then knows who is asking.
+pub fn peer_name(certificates: &[CertificateDer<'_>]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(certificates.first()?.as_ref()).ok()?;
+    let name: &str = cert.subject().iter_common_name().next()?.as_str().ok()?;
+
+    Some(name.to_string())
+}
+
+// Paths given to the client to reach a TLS server.
+// `cert_path` and `key_path` are only needed when the server asks for a client certificate.
+#[derive(Debug, Clone)]
+pub struct CBCATlsClientConfig {
+    pub ca_path: PathBuf,
+    pub server_name: String,
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>
+}
+
+impl CBCATlsClientConfig {
+    pub fn spawn(
+        ca_path: PathBuf,
+        server_name: String,
+        cert_path: Option<PathBuf>,
+        key_path: Option<PathBuf>
+    ) -> Self {
+        Self { ca_path, server_name, cert_path, key_path }
+    }
+
+    pub fn build_connector(&self) -> Result<CBCATlsConnector, std::io::Error> {
+        let ca_pem: Vec<u8> = std::fs::read(&self.ca_path)?;
+        let identity: Option<(Vec<u8>, Vec<u8>)> = match (&self.cert_path, &self.key_path) {
+            (Some(c), Some(k)) => Some((std::fs::read(c)?, std::fs::read(k)?)),
+            (None, None) => None,
+            _ => return Err(invalid_data("client certificate needs both cert and key", &self.server_name))
+        };
+
+        CBCATlsConnector::from_pem(
+            &ca_pem,
+            &self.server_name,
+            identity.as_ref().map(|(c, k)| (c.as_slice(), k.as_slice()))
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct CBCATlsConnector {
+    connector: TlsConnector,
+    server_name: ServerName<'static>
+}
+
+impl CBCATlsConnector {
+    pub fn from_pem(
+        ca_pem: &[u8],
+        server_name: &str,
+        identity: Option<(&[u8], &[u8])>
+    ) -> Result<Self, std::io::Error> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid_data("tls setup failed", e))?
+            .with_root_certificates(parse_roots(ca_pem)?);
+
+        let config: ClientConfig = match identity {
+            Some((cert_pem, key_pem)) => builder
+                .with_client_auth_cert(parse_certs(cert_pem)?, parse_key(key_pem)?)
+                .map_err(|e| invalid_data("invalid client certificate", e))?,
+            None => builder.with_no_client_auth()
+        };
+
+        let server_name: ServerName<'static> = ServerName::try_from(server_name.to_string())
+            .map_err(|e| invalid_data("invalid server name", e))?;
+
+        Ok(
+            Self {
+                connector: TlsConnector::from(Arc::new(config)),
+                server_name
+            }
+        )
+    }
+
+    pub async fn connect(
+        &self,
+        stream: tokio::net::TcpStream
+    ) -> Result<client::TlsStream<tokio::net::TcpStream>, std::io::Error> {
+        self.connector.connect(self.server_name.clone(), stream).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle
+    };
+
+    use super::{acceptor_from_pem, CBCATlsConnector, TlsAcceptor};
+    use crate::transport::{CBCAIncoming, CBCAStream};
+
+    struct CBCATestCa {
+        cert: Certificate,
+        key: KeyPair
+    }
+
+    // PEM of a certificate and its key.
+    struct CBCATestIdentity {
+        cert: String,
+        key: String
+    }
+
+    impl CBCATestCa {
+        fn spawn(name: &str) -> Self {
+            let key: KeyPair = KeyPair::generate().unwrap();
+            let mut params: CertificateParams = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, name);
+
+            Self { cert: params.self_signed(&key).unwrap(), key }
+        }
+
+        fn get_pem(&self) -> String {
+            self.cert.pem()
+        }
+
+        fn issue(
+            &self,
+            name: &str,
+            usage: ExtendedKeyUsagePurpose
+        ) -> CBCATestIdentity {
+            let key: KeyPair = KeyPair::generate().unwrap();
+            let mut params: CertificateParams = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![usage];
+
+            CBCATestIdentity {
+                cert: params.signed_by(&key, &self.cert, &self.key).unwrap().pem(),
+                key: key.serialize_pem()
+            }
+        }
+    }
+
+    // Accepts one peer through `acceptor` and echoes what it reads once, then gives its name.
+    async fn echo_server(acceptor: TlsAcceptor) -> (String, JoinHandle<Result<Option<String>, std::io::Error>>) {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: String = listener.local_addr().unwrap().to_string();
+
+        let handle: JoinHandle<Result<Option<String>, std::io::Error>> = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await?;
+            let mut stream: CBCAStream = CBCAStream::accept(CBCAIncoming::Tcp(socket), Some(&acceptor)).await?;
+
+            let mut buf: [u8; 4] = [0; 4];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.flush().await?;
+            Ok(stream.get_peer_name())
+        });
+
+        (addr, handle)
+    }
+
+    // Sends "ping" and reads the echo back.
+    async fn ping(
+        addr: &str,
+        connector: &CBCATlsConnector
+    ) -> Result<[u8; 4], std::io::Error> {
+        let mut stream: CBCAStream = CBCAStream::connect(addr, Some(connector)).await?;
+        stream.write_all(b"ping").await?;
+        stream.flush().await?;
+
+        let mut buf: [u8; 4] = [0; 4];
+        stream.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn tls_round_trip() {
+        let ca: CBCATestCa = CBCATestCa::spawn("cbca test ca");
+        let server: CBCATestIdentity = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+
+        let acceptor: TlsAcceptor = acceptor_from_pem(server.cert.as_bytes(), server.key.as_bytes(), None).unwrap();
+        let connector: CBCATlsConnector = CBCATlsConnector::from_pem(ca.get_pem().as_bytes(), "localhost", None).unwrap();
+
+        let (addr, handle) = echo_server(acceptor).await;
+        assert_eq!(&ping(&addr, &connector).await.unwrap(), b"ping");
+        assert_eq!(handle.await.unwrap().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn tls_refuses_unknown_server() {
+        let ca: CBCATestCa = CBCATestCa::spawn("cbca test ca");
+        let other: CBCATestCa = CBCATestCa::spawn("other ca");
+        let server: CBCATestIdentity = other.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+
+        let acceptor: TlsAcceptor = acceptor_from_pem(server.cert.as_bytes(), server.key.as_bytes(), None).unwrap();
+        let connector: CBCATlsConnector = CBCATlsConnector::from_pem(ca.get_pem().as_bytes(), "localhost", None).unwrap();
+
+        let (addr, handle) = echo_server(acceptor).await;
+        assert!(ping(&addr, &connector).await.is_err());
+        assert!(handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_round_trip() {
+        let ca: CBCATestCa = CBCATestCa::spawn("cbca test ca");
+        let client_ca: CBCATestCa = CBCATestCa::spawn("cbca client ca");
+        let server: CBCATestIdentity = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let client: CBCATestIdentity = client_ca.issue("bidder", ExtendedKeyUsagePurpose::ClientAuth);
+
+        let acceptor: TlsAcceptor = acceptor_from_pem(
+            server.cert.as_bytes(),
+            server.key.as_bytes(),
+            Some(client_ca.get_pem().as_bytes())
+        ).unwrap();
+        let connector: CBCATlsConnector = CBCATlsConnector::from_pem(
+            ca.get_pem().as_bytes(),
+            "localhost",
+            Some((client.cert.as_bytes(), client.key.as_bytes()))
+        ).unwrap();
+
+        let (addr, handle) = echo_server(acceptor).await;
+        assert_eq!(&ping(&addr, &connector).await.unwrap(), b"ping");
+        assert_eq!(handle.await.unwrap().unwrap().as_deref(), Some("bidder"));
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_rejects_foreign_client() {
+        let ca: CBCATestCa = CBCATestCa::spawn("cbca test ca");
+        let client_ca: CBCATestCa = CBCATestCa::spawn("cbca client ca");
+        let foreign_ca: CBCATestCa = CBCATestCa::spawn("foreign ca");
+        let server: CBCATestIdentity = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let client: CBCATestIdentity = foreign_ca.issue("bidder", ExtendedKeyUsagePurpose::ClientAuth);
+
+        let acceptor: TlsAcceptor = acceptor_from_pem(
+            server.cert.as_bytes(),
+            server.key.as_bytes(),
+            Some(client_ca.get_pem().as_bytes())
+        ).unwrap();
+        let connector: CBCATlsConnector = CBCATlsConnector::from_pem(
+            ca.get_pem().as_bytes(),
+            "localhost",
+            Some((client.cert.as_bytes(), client.key.as_bytes()))
+        ).unwrap();
+
+        let (addr, handle) = echo_server(acceptor).await;
+        assert!(ping(&addr, &connector).await.is_err());
+        assert!(handle.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_rejects_missing_client_certificate() {
+        let ca: CBCATestCa = CBCATestCa::spawn("cbca test ca");
+        let client_ca: CBCATestCa = CBCATestCa::spawn("cbca client ca");
+        let server: CBCATestIdentity = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+
+        let acceptor: TlsAcceptor = acceptor_from_pem(
+            server.cert.as_bytes(),
+            server.key.as_bytes(),
+            Some(client_ca.get_pem().as_bytes())
+        ).unwrap();
+        let connector: CBCATlsConnector = CBCATlsConnector::from_pem(ca.get_pem().as_bytes(), "localhost", None).unwrap();
+
+        let (addr, handle) = echo_server(acceptor).await;
+        assert!(ping(&addr, &connector).await.is_err());
+        assert!(handle.await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    fmt,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll}
+};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{client, server, TlsAcceptor};
+
+use crate::{limits::CBCALimits, tls::{self, CBCATlsConnector}};
+
+// Prefix of addresses naming a Unix domain socket, e.g. "unix:/run/cbca/message.sock".
+pub const CBCA_UNIX_PREFIX: &str = "unix:";
+
+// Where a routine listens or a client connects, parsed from "host:port" or "unix:/path".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CBCAEndpoint {
+    Tcp(String),
+    Unix(PathBuf)
+}
+
+impl CBCAEndpoint {
+    pub fn parse(addr: &str) -> Self {
+        match addr.strip_prefix(CBCA_UNIX_PREFIX) {
+            Some(v) => CBCAEndpoint::Unix(PathBuf::from(v)),
+            None => CBCAEndpoint::Tcp(addr.to_string())
+        }
+    }
+}
+
+impl fmt::Display for CBCAEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CBCAEndpoint::Tcp(v) => write!(f, "{}", v),
+            CBCAEndpoint::Unix(v) => write!(f, "{}{}", CBCA_UNIX_PREFIX, v.display())
+        }
+    }
+}
+
+// Binds the socket in a directory only the process can enter, gives it `mode` and only then
+// moves it to `path`, so no peer ever reaches it with the permissions of the umask.
+#[cfg(unix)]
+fn bind_unix(
+    path: &std::path::Path,
+    mode: u32
+) -> Result<tokio::net::UnixListener, std::io::Error> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    // A socket left by a previous run would make the bind fail, one still answering belongs
+    // to a server running now.
+    if let Ok(meta) = std::fs::symlink_metadata(path) && meta.file_type().is_socket() {
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("{} is in use by another server.", path.display())
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => std::fs::remove_file(path)?,
+            Err(e) => return Err(e)
+        }
+    }
+
+    let name: String = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let private: PathBuf = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    if private.exists() {
+        std::fs::remove_dir_all(&private)?;
+    }
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let bound = (|| {
+        let listener: tokio::net::UnixListener = tokio::net::UnixListener::bind(private.join(&name))?;
+        std::fs::set_permissions(private.join(&name), std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(private.join(&name), path)?;
+        Ok(listener)
+    })();
+
+    let _ = std::fs::remove_dir_all(&private);
+    bound
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, "unix sockets aren't available on this platform.")
+}
+
+// A connection accepted by a CBCAListener, before TLS.
+pub enum CBCAIncoming {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream)
+}
+
+enum CBCAListenerKind {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener)
+}
+
+// Accepts peers on a TCP port or a Unix socket, the routines don't tell them apart.
+pub struct CBCAListener {
+    kind: CBCAListenerKind,
+    endpoint: CBCAEndpoint
+}
+
+impl CBCAListener {
+    // `unix_mode` sets the socket file permissions, they are the access control of local peers.
+    pub async fn bind(
+        endpoint: &CBCAEndpoint,
+        unix_mode: u32
+    ) -> Result<Self, std::io::Error> {
+        let kind: CBCAListenerKind = match endpoint {
+            CBCAEndpoint::Tcp(v) => CBCAListenerKind::Tcp(tokio::net::TcpListener::bind(v).await?),
+            #[cfg(unix)]
+            CBCAEndpoint::Unix(v) => CBCAListenerKind::Unix(bind_unix(v, unix_mode)?),
+            #[cfg(not(unix))]
+            CBCAEndpoint::Unix(_) => {
+                let _ = unix_mode;
+                return Err(unix_unsupported());
+            }
+        };
+
+        Ok(Self { kind, endpoint: endpoint.clone() })
+    }
+
+    pub fn get_endpoint(&self) -> &CBCAEndpoint {
+        &self.endpoint
+    }
+
+    pub async fn accept(&self) -> Result<CBCAIncoming, std::io::Error> {
+        match &self.kind {
+            CBCAListenerKind::Tcp(v) => Ok(CBCAIncoming::Tcp(v.accept().await?.0)),
+            #[cfg(unix)]
+            CBCAListenerKind::Unix(v) => Ok(CBCAIncoming::Unix(v.accept().await?.0))
+        }
+    }
+}
+
+enum CBCAStreamKind {
+    Plain(tokio::net::TcpStream),
+    TlsServer(Box<server::TlsStream<tokio::net::TcpStream>>),
+    TlsClient(Box<client::TlsStream<tokio::net::TcpStream>>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream)
+}
+
+// A connection between a client and a server, encrypted or not, over TCP or a Unix socket.
+// Frames are read and written through it without knowing which one it is.
+pub struct CBCAStream {
+    kind: CBCAStreamKind,
+    // Set once both peers advertised CBCAFeature::Compression.
+    compression: bool,
+    limits: CBCALimits
+}
+
+impl CBCAStream {
+    fn spawn(kind: CBCAStreamKind) -> Self {
+        Self { kind, compression: false, limits: CBCALimits::default() }
+    }
+
+    pub fn plain(stream: tokio::net::TcpStream) -> Self {
+        Self::spawn(CBCAStreamKind::Plain(stream))
+    }
+
+    // Server side, runs the TLS handshake when an acceptor is given.
+    // Unix sockets stay plain, only local peers allowed by the file permissions reach them.
+    pub async fn accept(
+        incoming: CBCAIncoming,
+        acceptor: Option<&TlsAcceptor>
+    ) -> Result<Self, std::io::Error> {
+        match (incoming, acceptor) {
+            (CBCAIncoming::Tcp(stream), Some(v)) => {
+                let tls = v.accept(stream).await?;
+                Ok(Self::spawn(CBCAStreamKind::TlsServer(Box::new(tls))))
+            },
+            (CBCAIncoming::Tcp(stream), None) => Ok(Self::plain(stream)),
+            #[cfg(unix)]
+            (CBCAIncoming::Unix(stream), _) => Ok(Self::spawn(CBCAStreamKind::Unix(stream)))
+        }
+    }
+
+    // Client side, runs the TLS handshake when a connector is given and `addr` is a TCP one.
+    pub async fn connect(
+        addr: &str,
+        connector: Option<&CBCATlsConnector>
+    ) -> Result<Self, std::io::Error> {
+        let addr: String = match CBCAEndpoint::parse(addr) {
+            CBCAEndpoint::Tcp(v) => v,
+            #[cfg(unix)]
+            CBCAEndpoint::Unix(v) => {
+                let stream: tokio::net::UnixStream = tokio::net::UnixStream::connect(v).await?;
+                return Ok(Self::spawn(CBCAStreamKind::Unix(stream)));
+            },
+            #[cfg(not(unix))]
+            CBCAEndpoint::Unix(_) => return Err(unix_unsupported())
+        };
+
+        let stream: tokio::net::TcpStream = tokio::net::TcpStream::connect(addr).await?;
+
+        match connector {
+            Some(v) => {
+                let tls = v.connect(stream).await?;
+                Ok(Self::spawn(CBCAStreamKind::TlsClient(Box::new(tls))))
+            },
+            None => Ok(Self::plain(stream))
+        }
+    }
+
+    pub fn set_compression(&mut self, compression: bool) {
+        self.compression = compression;
+    }
+
+    pub fn has_compression(&self) -> bool {
+        self.compression
+    }
+
+    // Also turns TCP keepalive on, so dead peers are noticed without any traffic.
+    pub fn set_limits(&mut self, limits: CBCALimits) -> Result<(), std::io::Error> {
+        if let Some(v) = self.get_tcp() {
+            let keepalive: TcpKeepalive = TcpKeepalive::new()
+                .with_time(limits.keepalive)
+                .with_interval(limits.keepalive);
+
+            SockRef::from(v).set_tcp_keepalive(&keepalive)?;
+        }
+
+        self.limits = limits;
+        Ok(())
+    }
+
+    pub fn get_limits(&self) -> CBCALimits {
+        self.limits
+    }
+
+    fn get_tcp(&self) -> Option<&tokio::net::TcpStream> {
+        match &self.kind {
+            CBCAStreamKind::Plain(v) => Some(v),
+            CBCAStreamKind::TlsServer(v) => Some(v.get_ref().0),
+            CBCAStreamKind::TlsClient(v) => Some(v.get_ref().0),
+            #[cfg(unix)]
+            CBCAStreamKind::Unix(_) => None
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.kind, CBCAStreamKind::TlsServer(_) | CBCAStreamKind::TlsClient(_))
+    }
+
+    // Name in the certificate of a client that went through mutual TLS, None otherwise.
+    pub fn get_peer_name(&self) -> Option<String> {
+        match &self.kind {
+            CBCAStreamKind::TlsServer(v) => tls::peer_name(v.get_ref().1.peer_certificates()?),
+            _ => None
+        }
+    }
+
+    // None for Unix socket peers, they have no network address.
+    pub fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.get_tcp()?.peer_addr().ok()
+    }
+}
+
+impl AsyncRead for CBCAStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>
+    ) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().kind {
+            CBCAStreamKind::Plain(v) => Pin::new(v).poll_read(cx, buf),
+            CBCAStreamKind::TlsServer(v) => Pin::new(v.as_mut()).poll_read(cx, buf),
+            CBCAStreamKind::TlsClient(v) => Pin::new(v.as_mut()).poll_read(cx, buf),
+            #[cfg(unix)]
+            CBCAStreamKind::Unix(v) => Pin::new(v).poll_read(cx, buf)
+        }
+    }
+}
+
+impl AsyncWrite for CBCAStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8]
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut self.get_mut().kind {
+            CBCAStreamKind::Plain(v) => Pin::new(v).poll_write(cx, buf),
+            CBCAStreamKind::TlsServer(v) => Pin::new(v.as_mut()).poll_write(cx, buf),
+            CBCAStreamKind::TlsClient(v) => Pin::new(v.as_mut()).poll_write(cx, buf),
+            #[cfg(unix)]
+            CBCAStreamKind::Unix(v) => Pin::new(v).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>
+    ) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().kind {
+            CBCAStreamKind::Plain(v) => Pin::new(v).poll_flush(cx),
+            CBCAStreamKind::TlsServer(v) => Pin::new(v.as_mut()).poll_flush(cx),
+            CBCAStreamKind::TlsClient(v) => Pin::new(v.as_mut()).poll_flush(cx),
+            #[cfg(unix)]
+            CBCAStreamKind::Unix(v) => Pin::new(v).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>
+    ) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().kind {
+            CBCAStreamKind::Plain(v) => Pin::new(v).poll_shutdown(cx),
+            CBCAStreamKind::TlsServer(v) => Pin::new(v.as_mut()).poll_shutdown(cx),
+            CBCAStreamKind::TlsClient(v) => Pin::new(v.as_mut()).poll_shutdown(cx),
+            #[cfg(unix)]
+            CBCAStreamKind::Unix(v) => Pin::new(v).poll_shutdown(cx)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, path::PathBuf};
+
+    use super::{CBCAEndpoint, CBCAListener};
+
+    #[tokio::test]
+    async fn unix_socket_is_created_with_its_mode_and_never_stolen() {
+        let dir: PathBuf = std::env::temp_dir().join(format!("cbca-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let endpoint: CBCAEndpoint = CBCAEndpoint::Unix(dir.join("s.sock"));
+        let CBCAEndpoint::Unix(path) = &endpoint else { unreachable!() };
+
+        let listener: CBCAListener = CBCAListener::bind(&endpoint, 0o600).await.unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // A server answering on the socket keeps it.
+        let error: std::io::Error = CBCAListener::bind(&endpoint, 0o600).await.err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::AddrInUse);
+
+        // A socket nobody answers on is left over, the next server replaces it.
+        drop(listener);
+        CBCAListener::bind(&endpoint, 0o660).await.unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o660);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}